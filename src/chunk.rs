@@ -1,13 +1,51 @@
-use std::{iter::Enumerate, slice::Iter};
+use std::{collections::HashSet, iter::Enumerate, slice::Iter};
 
 use crate::{
   scope::Upvalue,
-  value::{Closure, Function, Value},
+  value::{Closure, Value},
+  vm::checked_pow,
 };
 
-#[derive(Debug)]
-pub enum Op {
-  Constant = 0,
+/// What `op` evaluates to on two constants, as the VM would work it out.
+/// Division and remainder by zero are left for run time.
+fn fold(op: &Op, a: &Value, b: &Value) -> Option<Value> {
+  if let (Op::Add, Value::String(a), Value::String(b)) = (op, a, b) {
+    return Some(Value::string(&format!("{}{}", a, b)));
+  }
+  if !a.is_number() || !b.is_number() {
+    return None;
+  }
+  let folded = match op {
+    Op::Add => Value::arithmetic(a, b, i64::checked_add, |a, b| a + b),
+    Op::Subtract => Value::arithmetic(a, b, i64::checked_sub, |a, b| a - b),
+    Op::Multiply => Value::arithmetic(a, b, i64::checked_mul, |a, b| a * b),
+    Op::Power => Value::arithmetic(a, b, checked_pow, f64::powf),
+    Op::Divide | Op::Modulo if b.as_number() == Some(0.0) => return None,
+    Op::Divide => Ok(Value::number(a.as_number()? / b.as_number()?)),
+    Op::Modulo => Value::arithmetic(a, b, i64::checked_rem, |a, b| a % b),
+    _ => return None,
+  };
+  folded.ok()
+}
+
+/// Declares the ops in the order of their bytes, so that the enum and both
+/// conversions can't disagree.
+macro_rules! ops {
+  ($($(#[$meta:meta])* $name:ident,)*) => {
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Op {
+      $($(#[$meta])* $name,)*
+    }
+
+    impl Op {
+      /// Every op, at the index of its byte.
+      pub const ALL: &'static [Op] = &[$(Self::$name,)*];
+    }
+  };
+}
+
+ops! {
+  Constant,
   Nil,
   True,
   False,
@@ -35,6 +73,38 @@ pub enum Op {
   Call,
   Closure,
   Return,
+  Class,
+  Method,
+  GetProperty,
+  SetProperty,
+  CloseUpvalue,
+  Inherit,
+  GetSuper,
+  DeclareGlobal,
+  Assert,
+  Modulo,
+  SetupCatch,
+  PopCatch,
+  Throw,
+  BuildList,
+  Index,
+  SetIndex,
+  BuildMap,
+  SetupFinally,
+  EndFinally,
+  SpreadCall,
+  BitAnd,
+  BitOr,
+  BitXor,
+  ShiftLeft,
+  ShiftRight,
+  Power,
+  ToString,
+  ConstantLong,
+  /// Skips the default value of a parameter when the caller passed it.
+  JumpIfArg,
+  Dup,
+  Swap,
 }
 
 impl From<Op> for u8 {
@@ -43,39 +113,14 @@ impl From<Op> for u8 {
   }
 }
 
-impl From<u8> for Op {
-  fn from(u: u8) -> Self {
-    match u {
-      0 => Self::Constant,
-      1 => Self::Nil,
-      2 => Self::True,
-      3 => Self::False,
-      4 => Self::Pop,
-      5 => Self::GetLocal,
-      6 => Self::SetLocal,
-      7 => Self::GetGlobal,
-      8 => Self::DefineGlobal,
-      9 => Self::SetGlobal,
-      10 => Self::GetUpvalue,
-      11 => Self::SetUpvalue,
-      12 => Self::Equal,
-      13 => Self::Greater,
-      14 => Self::Less,
-      15 => Self::Add,
-      16 => Self::Subtract,
-      17 => Self::Multiply,
-      18 => Self::Divide,
-      19 => Self::Not,
-      20 => Self::Negate,
-      21 => Self::Print,
-      22 => Self::Jump,
-      23 => Self::JumpIfFalse,
-      24 => Self::Loop,
-      25 => Self::Call,
-      26 => Self::Closure,
-      27 => Self::Return,
-      _ => unreachable!("{:?}", u),
-    }
+impl TryFrom<u8> for Op {
+  type Error = String;
+
+  fn try_from(u: u8) -> Result<Self, Self::Error> {
+    Self::ALL
+      .get(u as usize)
+      .copied()
+      .ok_or_else(|| format!("Unknown op {}.", u))
   }
 }
 
@@ -83,6 +128,18 @@ impl From<u8> for Op {
 pub struct Chunk {
   pub codes: Vec<u8>,
   pub constants: Vec<Value>,
+  pub lines: Vec<usize>,
+  /// The slot of the global named by each constant, indexed like
+  /// `constants`, filled in by the VM that loads the chunk so that the global
+  /// ops don't look their names up as they run.
+  pub global_slots: Vec<usize>,
+  line: usize,
+}
+
+impl Default for Chunk {
+  fn default() -> Self {
+    Self::new()
+  }
 }
 
 impl Chunk {
@@ -90,9 +147,17 @@ impl Chunk {
     Self {
       codes: Vec::new(),
       constants: Vec::new(),
+      lines: Vec::new(),
+      global_slots: Vec::new(),
+      line: 0,
     }
   }
 
+  /// Sets the source line attributed to the bytes emitted from now on.
+  pub fn set_line(&mut self, line: usize) {
+    self.line = line;
+  }
+
   pub fn code_len(&self) -> Result<u16, String> {
     let len = self.codes.len();
     if len > u16::MAX.into() {
@@ -105,10 +170,22 @@ impl Chunk {
     self.push(op.into())
   }
 
+  /// Emits a `Constant`, or a `ConstantLong` with a 24-bit big-endian
+  /// operand once the pool has outgrown a byte.
   pub fn emit_constant(&mut self, constant: Value) -> Result<(), String> {
-    let index = self.add_constant(constant)?;
-    self.emit_op(Op::Constant);
-    self.push(index);
+    let index = self.pool_index(constant)?;
+    match u8::try_from(index) {
+      Ok(index) => {
+        self.emit_op(Op::Constant);
+        self.push(index);
+      }
+      Err(_) => {
+        self.emit_op(Op::ConstantLong);
+        self.push((index >> 16) as u8);
+        self.push((index >> 8) as u8);
+        self.push(index as u8);
+      }
+    }
     Ok(())
   }
 
@@ -117,6 +194,11 @@ impl Chunk {
     self.push(index);
   }
 
+  pub fn emit_declare_global(&mut self, index: u8) {
+    self.emit_op(Op::DeclareGlobal);
+    self.push(index);
+  }
+
   pub fn emit_get_global(&mut self, index: u8) {
     self.emit_op(Op::GetGlobal);
     self.push(index);
@@ -154,6 +236,10 @@ impl Chunk {
 
   pub fn emit_jump(&mut self, op: Op) -> Result<u16, String> {
     self.emit_op(op);
+    self.emit_jump_offset()
+  }
+
+  fn emit_jump_offset(&mut self) -> Result<u16, String> {
     self.push(0xff);
     self.push(0xff);
     let len = self.codes.len();
@@ -163,15 +249,22 @@ impl Chunk {
     Ok(len as u16 - 2)
   }
 
+  /// Emits a `JumpIfArg` over the default value of the parameter in `slot`,
+  /// to be patched like `emit_jump`'s.
+  pub fn emit_jump_if_arg(&mut self, slot: u8) -> Result<u16, String> {
+    self.emit_op(Op::JumpIfArg);
+    self.push(slot);
+    self.emit_jump_offset()
+  }
+
   pub fn patch_jump(&mut self, start: u16) -> Result<(), String> {
     let len = self.codes.len();
     if len > u16::MAX.into() {
       return Err("Too much code to jump over.".to_owned());
     }
     let offset = len as u16 - 2 - start;
-    let offset = offset.to_ne_bytes();
-    self.write(offset[0], start)?;
-    self.write(offset[1], start + 1)?;
+    self.write((offset >> 8) as u8, start)?;
+    self.write((offset & 0xff) as u8, start + 1)?;
     Ok(())
   }
 
@@ -182,17 +275,37 @@ impl Chunk {
       return Err("Loop body too large.".to_owned());
     }
     let offset = len as u16 + 2 - start;
-    let offset = offset.to_ne_bytes();
-    self.push(offset[0]);
-    self.push(offset[1]);
+    self.push((offset >> 8) as u8);
+    self.push((offset & 0xff) as u8);
     Ok(())
   }
 
+  /// Reads the big-endian 16-bit operand starting at `index`.
+  pub fn read_short(&self, index: usize) -> u16 {
+    join_short(self.codes[index], self.codes[index + 1])
+  }
+
+  pub fn emit_build_list(&mut self, len: u8) {
+    self.emit_op(Op::BuildList);
+    self.push(len);
+  }
+
+  pub fn emit_build_map(&mut self, len: u8) {
+    self.emit_op(Op::BuildMap);
+    self.push(len);
+  }
+
   pub fn emit_call(&mut self, arg_count: u8) {
     self.emit_op(Op::Call);
     self.push(arg_count);
   }
 
+  /// Like `emit_call`, with the last of the arguments a list to spread.
+  pub fn emit_spread_call(&mut self, arg_count: u8) {
+    self.emit_op(Op::SpreadCall);
+    self.push(arg_count);
+  }
+
   pub fn emit_closure(&mut self, closure: Closure) -> Result<(), String> {
     let index = self.add_constant(Value::closure(closure))?;
     self.emit_op(Op::Closure);
@@ -200,8 +313,284 @@ impl Chunk {
     Ok(())
   }
 
+  pub fn emit_class(&mut self, index: u8) {
+    self.emit_op(Op::Class);
+    self.push(index);
+  }
+
+  pub fn emit_method(&mut self, index: u8) {
+    self.emit_op(Op::Method);
+    self.push(index);
+  }
+
+  pub fn emit_get_property(&mut self, index: u8) {
+    self.emit_op(Op::GetProperty);
+    self.push(index);
+  }
+
+  pub fn emit_set_property(&mut self, index: u8) {
+    self.emit_op(Op::SetProperty);
+    self.push(index);
+  }
+
+  pub fn emit_get_super(&mut self, index: u8) {
+    self.emit_op(Op::GetSuper);
+    self.push(index);
+  }
+
+  /// Replaces `Constant a, Constant b` at `left` and `right` with a single
+  /// constant holding `a op b`, when both are literals `op` can be worked out
+  /// for at compile time and nothing follows them. `constants` is how many
+  /// constants there were before `left`. Returns whether the operands were
+  /// folded.
+  pub fn fold_binary(
+    &mut self,
+    (left, constants): (usize, usize),
+    right: usize,
+    op: &Op,
+  ) -> Result<bool, String> {
+    let constant = Op::Constant as u8;
+    let is_literal_pair = right == left + 2
+      && self.codes.len() == right + 2
+      && self.codes[left] == constant
+      && self.codes[right] == constant;
+    if !is_literal_pair {
+      return Ok(false);
+    }
+    let a = &self.constants[self.codes[left + 1] as usize];
+    let b = &self.constants[self.codes[right + 1] as usize];
+    let folded = match fold(op, a, b) {
+      Some(folded) => folded,
+      None => return Ok(false),
+    };
+    // Any constants added since `left` were added for the operands alone, so
+    // their slots can be reused.
+    self.constants.truncate(constants);
+    self.codes.truncate(left);
+    self.lines.truncate(left);
+    self.emit_constant(folded)?;
+    Ok(true)
+  }
+
+  /// Checks that the code can run without reading past its end or outside
+  /// the constants, and that its jumps land on instructions, as it might not
+  /// when read from bytes that were tampered with. The functions among the
+  /// constants are checked too, with their parameter counts.
+  pub fn validate(&self) -> Result<(), String> {
+    self.validate_function(0, 0)
+  }
+
+  fn validate_function(&self, arity: u8, upvalues: u8) -> Result<(), String> {
+    if self.lines.len() != self.codes.len() {
+      return Err("The line table doesn't match the code.".to_owned());
+    }
+    let mut starts = HashSet::new();
+    let mut jumps = Vec::new();
+    let mut last = None;
+    let mut tries = false;
+    let mut index = 0;
+    while index < self.codes.len() {
+      starts.insert(index);
+      // The locals past the parameters are each pushed by an instruction
+      // before any that uses them.
+      let max_local = arity as usize + starts.len() - 1;
+      let code = self.codes[index];
+      let op = Op::try_from(code)
+        .map_err(|_| format!("Unknown op {} at {}.", code, index))?;
+      let operand = |n: usize| {
+        let byte = self.codes.get(index + n).copied();
+        byte.ok_or_else(|| format!("Truncated {:?} at {}.", op, index))
+      };
+      let constant = |i: usize| {
+        let constant = self.constants.get(i);
+        constant.ok_or_else(|| {
+          format!("No constant {} for {:?} at {}.", i, op, index)
+        })
+      };
+      match op {
+        Op::Constant => {
+          constant(operand(1)? as usize)?;
+        }
+        Op::ConstantLong => {
+          let i = (operand(1)? as usize) << 16
+            | (operand(2)? as usize) << 8
+            | operand(3)? as usize;
+          constant(i)?;
+        }
+        Op::GetGlobal
+        | Op::DefineGlobal
+        | Op::SetGlobal
+        | Op::DeclareGlobal
+        | Op::Class
+        | Op::Method
+        | Op::GetProperty
+        | Op::SetProperty
+        | Op::GetSuper => {
+          let name = constant(operand(1)? as usize)?;
+          if !name.is_string() {
+            return Err(format!("{:?} at {} needs a name.", op, index));
+          }
+        }
+        Op::GetUpvalue | Op::SetUpvalue => {
+          let slot = operand(1)?;
+          if slot >= upvalues {
+            return Err(format!("No upvalue for {:?} at {}.", op, index));
+          }
+        }
+        Op::GetLocal | Op::SetLocal => {
+          let slot = operand(1)? as usize;
+          if slot > max_local {
+            return Err(format!(
+              "No local {} for {:?} at {}.",
+              slot, op, index
+            ));
+          }
+        }
+        Op::PopCatch | Op::EndFinally if !tries => {
+          return Err(format!("{:?} at {} is outside a try.", op, index));
+        }
+        Op::Call | Op::BuildList | Op::BuildMap => {
+          operand(1)?;
+        }
+        // The argument count includes the list being spread.
+        Op::SpreadCall if operand(1)? == 0 => {
+          return Err(format!("SpreadCall at {} spreads no list.", index));
+        }
+        Op::Jump | Op::JumpIfFalse | Op::SetupCatch | Op::SetupFinally => {
+          let offset = join_short(operand(1)?, operand(2)?) as usize;
+          jumps.push((index, Some(index + 3 + offset)));
+          tries |= matches!(op, Op::SetupCatch | Op::SetupFinally);
+        }
+        Op::Loop => {
+          let offset = join_short(operand(1)?, operand(2)?) as usize;
+          jumps.push((index, (index + 3).checked_sub(offset)));
+        }
+        Op::JumpIfArg => {
+          let offset = join_short(operand(2)?, operand(3)?) as usize;
+          jumps.push((index, Some(index + 4 + offset)));
+        }
+        Op::Closure => {
+          let closure = constant(operand(1)? as usize)?
+            .as_closure()
+            .ok_or_else(|| format!("Closure at {} needs a function.", index))?;
+          for i in 0..closure.upvalues_len as usize {
+            let is_local = operand(2 + i * 2)?;
+            let slot = operand(3 + i * 2)?;
+            let in_range = match is_local {
+              0 => slot < upvalues,
+              1 => slot as usize <= max_local,
+              _ => false,
+            };
+            if !in_range {
+              return Err(format!("Bad capture for Closure at {}.", index));
+            }
+          }
+        }
+        // The rest have no operands.
+        _ => {}
+      }
+      last = Some(op);
+      index += self.instruction_len(index);
+    }
+    if !matches!(last, Some(Op::Return)) {
+      return Err("The code doesn't end with a return.".to_owned());
+    }
+    for (from, to) in jumps {
+      if !to.is_some_and(|to| starts.contains(&to)) {
+        return Err(format!("The jump at {} lands off an instruction.", from));
+      }
+    }
+    for constant in &self.constants {
+      if let Value::Closure(closure) = constant {
+        let function = &closure.function;
+        let defaults = function.optional as usize + function.variadic as usize;
+        if defaults > function.arity as usize {
+          return Err(format!("Bad parameter counts for {:?}.", function));
+        }
+        let chunk = &function.chunk;
+        chunk.validate_function(function.arity, closure.upvalues_len)?;
+      }
+    }
+    Ok(())
+  }
+
+  /// The constants naming the globals that the code declares, defines, reads
+  /// or assigns, each once.
+  pub fn global_names(&self) -> Vec<usize> {
+    let mut names = Vec::new();
+    let mut index = 0;
+    while index < self.codes.len() {
+      match self.op_at(index) {
+        Op::GetGlobal
+        | Op::DefineGlobal
+        | Op::SetGlobal
+        | Op::DeclareGlobal => {
+          let name = self.codes[index + 1] as usize;
+          if !names.contains(&name) {
+            names.push(name);
+          }
+        }
+        _ => {}
+      }
+      index += self.instruction_len(index);
+    }
+    names
+  }
+
+  /// Whether an instruction of the validated code starts at `index`.
+  pub fn starts_instruction(&self, index: usize) -> bool {
+    let mut start = 0;
+    while start < index && start < self.codes.len() {
+      start += self.instruction_len(start);
+    }
+    start == index && index < self.codes.len()
+  }
+
+  /// The op at `index` of code that has been validated.
+  fn op_at(&self, index: usize) -> Op {
+    Op::try_from(self.codes[index])
+      .unwrap_or_else(|error| unreachable!("{}", error))
+  }
+
+  /// How many bytes the instruction at `index` takes, with its operands.
+  fn instruction_len(&self, index: usize) -> usize {
+    match self.op_at(index) {
+      Op::Constant
+      | Op::GetGlobal
+      | Op::DefineGlobal
+      | Op::SetGlobal
+      | Op::DeclareGlobal
+      | Op::Class
+      | Op::Method
+      | Op::GetProperty
+      | Op::SetProperty
+      | Op::GetSuper
+      | Op::GetUpvalue
+      | Op::SetUpvalue
+      | Op::GetLocal
+      | Op::SetLocal
+      | Op::Call
+      | Op::SpreadCall
+      | Op::BuildList
+      | Op::BuildMap => 2,
+      Op::Jump
+      | Op::JumpIfFalse
+      | Op::SetupCatch
+      | Op::SetupFinally
+      | Op::Loop => 3,
+      Op::ConstantLong | Op::JumpIfArg => 4,
+      Op::Closure => {
+        let constant = &self.constants[self.codes[index + 1] as usize];
+        let closure = constant.as_closure().unwrap();
+        2 + closure.upvalues_len as usize * 2
+      }
+      _ => 1,
+    }
+  }
+
   fn push(&mut self, byte: u8) {
     self.codes.push(byte);
+    self.lines.push(self.line);
   }
 
   fn write(&mut self, byte: u8, at: u16) -> Result<(), String> {
@@ -213,24 +602,75 @@ impl Chunk {
     Ok(())
   }
 
+  /// Adds `constant` to the pool for an instruction with a one-byte operand.
   pub fn add_constant(&mut self, constant: Value) -> Result<u8, String> {
+    let index = self.pool_index(constant)?;
+    u8::try_from(index)
+      .map_err(|_| "Too many constants in one chunk.".to_owned())
+  }
+
+  /// Adds `constant` to the pool, unless an equal string or number is there
+  /// already. Numbers only match numbers of the same kind and with the same
+  /// bits, so `1` and `1.0` or `0.0` and `-0.0` are kept apart.
+  fn pool_index(&mut self, constant: Value) -> Result<usize, String> {
+    let existing = self.constants.iter().position(|c| match (c, &constant) {
+      (Value::String(a), Value::String(b)) => a == b,
+      (Value::Int(a), Value::Int(b)) => a == b,
+      (Value::Number(a), Value::Number(b)) => a.to_bits() == b.to_bits(),
+      _ => false,
+    });
+    if let Some(index) = existing {
+      return Ok(index);
+    }
     let index = self.constants.len();
-    if index > u8::MAX.into() {
+    if index >= 1 << 24 {
       return Err("Too many constants in one chunk.".to_owned());
     }
     self.constants.push(constant);
-    Ok(index as u8)
+    Ok(index)
   }
 
   pub fn debug_bytecodes(&self, prefix: &str) -> String {
-    let mut buffer = String::from(format!("{}\n", prefix));
+    self.disassemble(prefix, None)
+  }
 
-    let mut codes = self.codes.iter().enumerate();
+  /// Like `debug_bytecodes`, but each run of instructions compiled from the
+  /// same line is preceded by that line of `source`.
+  pub fn debug_bytecodes_with_source(
+    &self,
+    prefix: &str,
+    source: &str,
+  ) -> String {
+    self.disassemble(prefix, Some(source))
+  }
 
-    while let Some((index, &code)) = codes.next() {
+  /// Lists the constants table, each constant after its index.
+  pub fn debug_constants(&self, prefix: &str) -> String {
+    let mut buffer = format!("{}\n", prefix);
+    for (index, constant) in self.constants.iter().enumerate() {
+      buffer.push_str(&format!("{:4} '{:?}'\n", index, constant));
+    }
+    buffer
+  }
+
+  fn disassemble(&self, prefix: &str, source: Option<&str>) -> String {
+    let mut buffer = format!("{}\n", prefix);
+
+    let mut codes = self.codes.iter().enumerate();
+    let mut last_line = None;
+
+    while let Some((index, _)) = codes.next() {
+      if let Some(source) = source {
+        let line = self.lines[index];
+        if last_line != Some(line) {
+          let text = source.lines().nth(line.wrapping_sub(1)).unwrap_or("");
+          buffer.push_str(&format!("[line {}] {}\n", line, text.trim()));
+          last_line = Some(line);
+        }
+      }
       buffer.push_str(&format!("{:04} ", index));
 
-      let op = Op::from(code);
+      let op = self.op_at(index);
       let s = match op {
         Op::Constant => self.debug_double(&op, &mut codes),
         Op::Nil => self.debug_simple(&op),
@@ -267,8 +707,7 @@ impl Chunk {
             constant_index,
             constant
           );
-          let closure = constant.clone().as_closure().unwrap();
-          dbg!(&codes, &closure);
+          let closure = constant.as_closure().unwrap();
           for _ in 0..closure.upvalues_len {
             let (i, &is_local) = codes.next().unwrap();
             let (_, &upvalue_index) = codes.next().unwrap();
@@ -283,6 +722,37 @@ impl Chunk {
           s
         }
         Op::Return => self.debug_simple(&op),
+        Op::Class => self.debug_double(&op, &mut codes),
+        Op::Method => self.debug_double(&op, &mut codes),
+        Op::GetProperty => self.debug_double(&op, &mut codes),
+        Op::SetProperty => self.debug_double(&op, &mut codes),
+        Op::CloseUpvalue => self.debug_simple(&op),
+        Op::Inherit => self.debug_simple(&op),
+        Op::GetSuper => self.debug_double(&op, &mut codes),
+        Op::DeclareGlobal => self.debug_double(&op, &mut codes),
+        Op::Assert => self.debug_simple(&op),
+        Op::Modulo => self.debug_simple(&op),
+        Op::SetupCatch => self.debug_jump(&op, index, true, &mut codes),
+        Op::PopCatch => self.debug_simple(&op),
+        Op::Throw => self.debug_simple(&op),
+        Op::BuildList => self.debug_index(&op, &mut codes),
+        Op::Index => self.debug_simple(&op),
+        Op::SetIndex => self.debug_simple(&op),
+        Op::BuildMap => self.debug_index(&op, &mut codes),
+        Op::SetupFinally => self.debug_jump(&op, index, true, &mut codes),
+        Op::EndFinally => self.debug_simple(&op),
+        Op::SpreadCall => self.debug_index(&op, &mut codes),
+        Op::BitAnd => self.debug_simple(&op),
+        Op::BitOr => self.debug_simple(&op),
+        Op::BitXor => self.debug_simple(&op),
+        Op::ShiftLeft => self.debug_simple(&op),
+        Op::ShiftRight => self.debug_simple(&op),
+        Op::Power => self.debug_simple(&op),
+        Op::ToString => self.debug_simple(&op),
+        Op::ConstantLong => self.debug_long_constant(&op, &mut codes),
+        Op::JumpIfArg => self.debug_jump_if_arg(&op, index, &mut codes),
+        Op::Dup => self.debug_simple(&op),
+        Op::Swap => self.debug_simple(&op),
       };
       buffer.push_str(&s);
     }
@@ -305,6 +775,25 @@ impl Chunk {
     )
   }
 
+  fn debug_long_constant(
+    &self,
+    op: &Op,
+    codes: &mut Enumerate<Iter<u8>>,
+  ) -> String {
+    let mut constant_index = 0;
+    for _ in 0..3 {
+      let (_, &byte) = codes.next().unwrap();
+      constant_index = constant_index << 8 | byte as usize;
+    }
+    let constant = self.constants.get(constant_index).unwrap();
+    format!(
+      "{:16} {:4} '{:?}'\n",
+      format!("{:?}", op),
+      constant_index,
+      constant
+    )
+  }
+
   fn debug_index(&self, op: &Op, codes: &mut Enumerate<Iter<u8>>) -> String {
     let (_, &index) = codes.next().unwrap();
     format!("{:16} {:4}\n", format!("{:?}", op), index)
@@ -319,7 +808,7 @@ impl Chunk {
   ) -> String {
     let (_, &offset_0) = codes.next().unwrap();
     let (_, &offset_1) = codes.next().unwrap();
-    let offset = unsafe { *[offset_0, offset_1].as_ptr().cast::<u16>() };
+    let offset = join_short(offset_0, offset_1);
     let to = if is_forward {
       from + 3 + offset as usize
     } else {
@@ -327,4 +816,41 @@ impl Chunk {
     };
     format!("{:16} {:4} -> {}\n", format!("{:?}", op), from, to)
   }
+
+  fn debug_jump_if_arg(
+    &self,
+    op: &Op,
+    from: usize,
+    codes: &mut Enumerate<Iter<u8>>,
+  ) -> String {
+    let (_, &slot) = codes.next().unwrap();
+    let (_, &offset_0) = codes.next().unwrap();
+    let (_, &offset_1) = codes.next().unwrap();
+    let to = from + 4 + join_short(offset_0, offset_1) as usize;
+    let op = format!("{:?}", op);
+    format!(
+      "{:16} {:4} -> {} if slot {} is passed\n",
+      op, from, to, slot
+    )
+  }
+}
+
+/// Lists the code of `closure`'s function and of every function nested in
+/// it, each function after the ones it defines, as the `Inspector` does.
+pub fn disassemble(closure: &Closure) -> String {
+  let function = &closure.function;
+  let mut buffer = String::new();
+  for constant in &function.chunk.constants {
+    if let Some(closure) = constant.as_closure() {
+      buffer.push_str(&disassemble(&closure));
+    }
+  }
+  let prefix = format!("== {:?} ==", closure);
+  buffer.push_str(&function.chunk.debug_bytecodes(&prefix));
+  buffer
+}
+
+/// Joins the two bytes of a 16-bit operand, which is always stored big-endian.
+pub fn join_short(hi: u8, lo: u8) -> u16 {
+  ((hi as u16) << 8) | lo as u16
 }
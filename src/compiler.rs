@@ -9,6 +9,16 @@ pub struct Compiler {
   pub function: Function,
   pub scopes: Scopes,
   pub upvalues: Vec<Upvalue>,
+  pub loops: Vec<LoopCompiler>,
+  /// The scope depth of each handler set up by a `try` statement around the
+  /// code being compiled, innermost last.
+  pub tries: Vec<usize>,
+  /// Whether the statement just compiled was a `return`, so that nothing
+  /// after it in the same block could run.
+  pub terminated: bool,
+  /// Whether the next statement is the body of an `if`, where a loop leaves
+  /// the `else` after it to the `if`.
+  pub if_body: bool,
 }
 
 impl Compiler {
@@ -18,6 +28,10 @@ impl Compiler {
       function: Function::new_script(),
       scopes: Scopes::new(),
       upvalues: Vec::new(),
+      loops: Vec::new(),
+      tries: Vec::new(),
+      terminated: false,
+      if_body: false,
     }
   }
 
@@ -27,6 +41,23 @@ impl Compiler {
       function: Function::new_function(name),
       scopes: Scopes::new(),
       upvalues: Vec::new(),
+      loops: Vec::new(),
+      tries: Vec::new(),
+      terminated: false,
+      if_body: false,
+    }
+  }
+
+  pub fn method(self, name: &str) -> Self {
+    Self {
+      enclosing: Some(Box::new(self)),
+      function: Function::new_method(name),
+      scopes: Scopes::with_receiver(),
+      upvalues: Vec::new(),
+      loops: Vec::new(),
+      tries: Vec::new(),
+      terminated: false,
+      if_body: false,
     }
   }
 
@@ -36,6 +67,20 @@ impl Compiler {
     (enclosing, function, self.upvalues)
   }
 
+  /// Abandons the functions being compiled inside the script, and the scopes,
+  /// loops and handlers open in it, after an error.
+  pub fn recover(mut self) -> Self {
+    while let Some(enclosing) = self.enclosing {
+      self = *enclosing;
+    }
+    self.scopes = Scopes::new();
+    self.loops.clear();
+    self.tries.clear();
+    self.terminated = false;
+    self.if_body = false;
+    self
+  }
+
   pub fn chunk(&mut self) -> &mut Chunk {
     &mut self.function.chunk
   }
@@ -70,3 +115,42 @@ impl Compiler {
     }
   }
 }
+
+pub struct ClassCompiler {
+  pub has_superclass: bool,
+}
+
+impl ClassCompiler {
+  pub fn new() -> Self {
+    Self {
+      has_superclass: false,
+    }
+  }
+}
+
+pub struct LoopCompiler {
+  /// Where `continue` jumps back to, or `None` when it jumps ahead to the
+  /// condition of a `do` loop.
+  pub start: Option<u16>,
+  /// Scope depth outside the loop body; deeper locals are discarded on exit.
+  pub depth: usize,
+  /// `break` jumps waiting for the end of the loop to be known.
+  pub breaks: Vec<u16>,
+  /// `continue` jumps waiting for the condition of a `do` loop.
+  pub continues: Vec<u16>,
+  /// Handlers of `try` statements outside the loop; leaving the loop must
+  /// drop any deeper ones.
+  pub tries: usize,
+}
+
+impl LoopCompiler {
+  pub fn new(start: Option<u16>, depth: usize, tries: usize) -> Self {
+    Self {
+      start,
+      depth,
+      breaks: Vec::new(),
+      continues: Vec::new(),
+      tries,
+    }
+  }
+}
@@ -0,0 +1,65 @@
+use std::{error::Error, fmt};
+
+/// An error from running Lox code, by the stage that found it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoxError {
+  Scan {
+    line: usize,
+    msg: String,
+  },
+  /// `at` is the text of the token the error was found at, or `None` at the
+  /// end of the source.
+  Compile {
+    line: usize,
+    at: Option<String>,
+    msg: String,
+  },
+  /// `trace` has a line for each call the error was raised under, the
+  /// innermost first.
+  Runtime {
+    line: usize,
+    msg: String,
+    trace: Vec<String>,
+  },
+  /// Code that was rejected before running, as it might be when built by
+  /// hand or read from tampered bytes.
+  Load {
+    msg: String,
+  },
+}
+
+impl fmt::Display for LoxError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Compile {
+        line,
+        at: Some(at),
+        msg,
+      } => write!(f, "[line {}] Error at '{}': {}", line, at, msg),
+      Self::Compile {
+        line,
+        at: None,
+        msg,
+      } => {
+        write!(f, "[line {}] Error at end: {}", line, msg)
+      }
+      Self::Scan { line, msg } => write!(f, "[line {}] Error: {}", line, msg),
+      Self::Load { msg } => write!(f, "Error: {}", msg),
+      Self::Runtime { line, msg, trace } => {
+        write!(f, "[line {}] Error: {}", line, msg)?;
+        for call in trace {
+          write!(f, "\n{}", call)?;
+        }
+        Ok(())
+      }
+    }
+  }
+}
+
+impl Error for LoxError {}
+
+impl From<LoxError> for String {
+  fn from(error: LoxError) -> Self {
+    error.to_string()
+  }
+}
@@ -1,26 +1,33 @@
 use std::fmt;
 
-use crate::value::{Function, FunctionKind, Value};
+use crate::value::{Closure, Value};
 
 pub struct Inspector {
-  bytecode_snapshot: Vec<Function>,
+  source: Option<String>,
+  bytecode_snapshot: Vec<Closure>,
   stack_snapshot: Vec<Vec<Value>>,
 }
 
-pub struct BytecodeSnapshot(Vec<Function>);
+/// The functions caught, the source to interleave with their code, and
+/// whether to list their constants tables.
+pub struct BytecodeSnapshot(Vec<Closure>, Option<String>, bool);
 
 pub struct StackSnapshot(Vec<Vec<Value>>);
 
 impl fmt::Debug for BytecodeSnapshot {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-    for fun in &self.0 {
-      let name = if let FunctionKind::Function { name } = &fun.kind {
-        format!("<fun {}>", name)
-      } else {
-        "<script>".to_owned()
+    for closure in &self.0 {
+      let fun = &closure.function;
+      let prefix = format!("== {:?} ==", closure);
+      let s = match &self.1 {
+        Some(source) => fun.chunk.debug_bytecodes_with_source(&prefix, source),
+        None => fun.chunk.debug_bytecodes(&prefix),
       };
-      let s = fun.chunk.debug_bytecodes(&format!("== {} ==", &name));
       write!(f, "{}", s)?;
+      if self.2 {
+        let prefix = format!("== {:?} constants ==", closure);
+        write!(f, "{}", fun.chunk.debug_constants(&prefix))?;
+      }
     }
     Ok(())
   }
@@ -36,16 +43,32 @@ impl fmt::Debug for StackSnapshot {
   }
 }
 
+impl Default for Inspector {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
 impl Inspector {
   pub fn new() -> Self {
     Self {
+      source: None,
       bytecode_snapshot: Vec::new(),
       stack_snapshot: Vec::new(),
     }
   }
 
-  pub fn catch_bytecode(&mut self, f: Function) {
-    self.bytecode_snapshot.push(f);
+  /// An inspector whose bytecode snapshot shows the source line each group
+  /// of instructions was compiled from.
+  pub fn with_source(source: &str) -> Self {
+    Self {
+      source: Some(source.to_owned()),
+      ..Self::new()
+    }
+  }
+
+  pub fn catch_bytecode(&mut self, closure: Closure) {
+    self.bytecode_snapshot.push(closure);
   }
 
   pub fn catch_stack(&mut self, s: Vec<Value>) {
@@ -53,7 +76,14 @@ impl Inspector {
   }
 
   pub fn debug_bytecode(&self) -> BytecodeSnapshot {
-    BytecodeSnapshot(self.bytecode_snapshot.clone())
+    let functions = self.bytecode_snapshot.clone();
+    BytecodeSnapshot(functions, self.source.clone(), false)
+  }
+
+  /// Like `debug_bytecode`, followed by each function's constants table.
+  pub fn debug_bytecode_with_constants(&self) -> BytecodeSnapshot {
+    let functions = self.bytecode_snapshot.clone();
+    BytecodeSnapshot(functions, self.source.clone(), true)
   }
 
   pub fn debug_stack(&self) -> StackSnapshot {
@@ -0,0 +1,23 @@
+use std::{collections::HashSet, rc::Rc};
+
+/// Hands out a single shared copy of each distinct string, so that equal
+/// names and literals compiled together share storage.
+#[derive(Default)]
+pub struct Interner {
+  strings: HashSet<Rc<str>>,
+}
+
+impl Interner {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn intern(&mut self, string: &str) -> Rc<str> {
+    if let Some(interned) = self.strings.get(string) {
+      return interned.clone();
+    }
+    let interned: Rc<str> = Rc::from(string);
+    self.strings.insert(interned.clone());
+    interned
+  }
+}
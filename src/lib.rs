@@ -1,17 +1,35 @@
 mod chunk;
 mod parser;
 mod scanner;
-mod source;
 mod token;
 mod value;
 mod vm;
 mod scope;
 mod compiler;
 mod inspector;
+mod native;
+mod suggest;
+mod interner;
+mod error;
+mod serialize;
 
 pub use chunk::Chunk;
+pub use chunk::Op;
+pub use chunk::disassemble;
 pub use parser::Parser;
 pub use scanner::Scanner;
+pub use token::Token;
+pub use token::TokenType;
 pub use vm::VM;
+pub use vm::StepResult;
 pub use vm::interpret;
+pub use vm::interpret_to_string;
+pub use vm::OutputBuffer;
+pub use parser::compile;
+pub use parser::compile_eval;
 pub use inspector::Inspector;
+pub use value::Value;
+pub use value::Closure;
+pub use value::Function;
+pub use value::FunctionKind;
+pub use error::LoxError;
@@ -1,25 +1,34 @@
 use std::{
   env, fs,
-  io::{self, BufRead, Write},
+  io::{self, Write},
+  process,
 };
 
-use clox_rs::interpret;
+use clox_rs::{compile, LoxError, VM};
 
 fn run_repl() {
   let stdin = io::stdin();
   let stdout = io::stdout();
-  let mut reader = stdin.lock();
   let mut writer = stdout.lock();
+  let mut vm = VM::new();
 
   loop {
     writer.write_all("> ".as_bytes()).unwrap();
     writer.flush().unwrap();
 
     let mut line = String::new();
-    reader.read_line(&mut line).unwrap();
+    if stdin.read_line(&mut line).unwrap() == 0 {
+      writer.write_all(b"\n").unwrap();
+      break;
+    }
 
-    if let Err(e) = interpret(&line) {
-      eprintln!("{}", e);
+    match compile(&line) {
+      Ok(closure) => {
+        if let Err(e) = vm.interpret_closure(closure) {
+          eprintln!("{}", e);
+        }
+      }
+      Err(errors) => report(&errors),
     }
   }
 }
@@ -27,8 +36,22 @@ fn run_repl() {
 fn run_file(path: &str) {
   let source = fs::read_to_string(path).unwrap();
 
-  if let Err(e) = interpret(&source) {
+  let closure = match compile(&source) {
+    Ok(closure) => closure,
+    Err(errors) => {
+      report(&errors);
+      process::exit(65);
+    }
+  };
+  if let Err(e) = VM::from_closure(closure).and_then(|mut vm| vm.run(None)) {
     eprintln!("{}", e);
+    process::exit(70);
+  }
+}
+
+fn report(errors: &[LoxError]) {
+  for error in errors {
+    eprintln!("{}", error);
   }
 }
 
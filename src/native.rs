@@ -0,0 +1,65 @@
+use std::cell::RefMut;
+
+use crate::{value::Value, VM};
+
+pub fn pretty_print(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+  vm.write_output(&format!("{}\n", args[0].pretty(2)))?;
+  Ok(Value::nil())
+}
+
+pub fn input(vm: &mut VM, _args: &[Value]) -> Result<Value, String> {
+  Ok(match vm.read_input()? {
+    Some(line) => Value::string(&line),
+    None => Value::nil(),
+  })
+}
+
+pub fn number(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+  match &args[0] {
+    Value::Number(n) => Ok(Value::number(*n)),
+    Value::Int(n) => Ok(Value::number(*n as f64)),
+    Value::String(string) => Ok(match string.trim().parse::<f64>() {
+      Ok(n) if n.is_finite() => Value::number(n),
+      _ => Value::nil(),
+    }),
+    _ => Err("Expected a string or number.".to_owned()),
+  }
+}
+
+pub fn string(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+  Ok(Value::string(&args[0].to_string()))
+}
+
+pub fn type_of(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+  Ok(Value::string(args[0].type_name()))
+}
+
+pub fn len(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+  let len = match &args[0] {
+    Value::String(string) => string.chars().count(),
+    Value::List(list) => list.borrow().len(),
+    _ => return Err("Expected a string or list.".to_owned()),
+  };
+  Ok(Value::int(len as i64))
+}
+
+pub fn push(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+  list_mut(&args[0])?.push(args[1].clone());
+  Ok(Value::nil())
+}
+
+pub fn pop(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+  list_mut(&args[0])?
+    .pop()
+    .ok_or_else(|| "Can't pop from an empty list.".to_owned())
+}
+
+fn list_mut(value: &Value) -> Result<RefMut<'_, Vec<Value>>, String> {
+  let list = match value {
+    Value::List(list) => list,
+    _ => return Err("Expected a list.".to_owned()),
+  };
+  list
+    .try_borrow_mut()
+    .map_err(|_| "Can't modify a list while it is being iterated.".to_owned())
+}
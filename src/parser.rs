@@ -1,18 +1,35 @@
+use std::iter;
+
 use crate::{
   chunk::Op,
-  compiler::Compiler,
+  compiler::{ClassCompiler, Compiler, LoopCompiler},
+  error::LoxError,
   inspector::Inspector,
+  interner::Interner,
   scanner::Scanner,
-  scope::Upvalue,
+  scope::{Local, Upvalue},
+  suggest::did_you_mean,
   token::{Precedence, Token, TokenType},
-  value::{Closure, Function, FunctionKind, Value},
+  value::{Closure, FunctionKind, Value},
   Chunk,
 };
 
-pub fn compile(source: &str) -> Result<Closure, String> {
+pub fn compile(source: &str) -> Result<Closure, Vec<LoxError>> {
+  compile_script(source, false)
+}
+
+/// Like `compile`, but a final top-level expression statement, whose `;` may
+/// be left out, returns its value from the script, for `VM::eval`.
+pub fn compile_eval(source: &str) -> Result<Closure, Vec<LoxError>> {
+  compile_script(source, true)
+}
+
+fn compile_script(source: &str, eval: bool) -> Result<Closure, Vec<LoxError>> {
   let scanner = Scanner::new(source);
   let mut parser = Parser::new(scanner, None);
-  parser.advance()?; // TODO
+  parser.eval = eval;
+  // A scan error is kept for `program` to report along with any others.
+  let _ = parser.advance();
   parser.program()?;
   let (closure, _) = parser.end_compiler();
   Ok(closure)
@@ -20,9 +37,25 @@ pub fn compile(source: &str) -> Result<Closure, String> {
 
 pub struct Parser<'source> {
   peek: Option<Token>,
+  /// The last token consumed, for errors about it.
+  previous: Option<Token>,
+  line: usize,
   scanner: Scanner<'source>,
   compiler: Option<Compiler>,
+  classes: Vec<ClassCompiler>,
   inspector: Option<Inspector>,
+  /// Where the code of the left operand of the infix rule being parsed starts,
+  /// and how many constants the chunk had then.
+  operand_start: (usize, usize),
+  /// The first error from scanning, which ends the parse. `match_token` has no
+  /// way to return it, so the next `eat` does.
+  scan_error: Option<LoxError>,
+  /// Whether the error being returned is about `peek`, which `eat` couldn't
+  /// consume, rather than the token before it.
+  error_at_peek: bool,
+  strings: Interner,
+  /// Whether a final top-level expression statement returns its value.
+  eval: bool,
 }
 
 pub type ParseFn<'s> = fn(&mut Parser<'s>, Token, bool) -> Result<(), String>;
@@ -31,9 +64,17 @@ impl<'source> Parser<'source> {
   pub fn new(scanner: Scanner<'source>, inspector: Option<Inspector>) -> Self {
     Self {
       peek: None,
+      previous: None,
+      line: 0,
       scanner,
       compiler: Some(Compiler::script()),
+      classes: Vec::new(),
       inspector,
+      operand_start: (0, 0),
+      scan_error: None,
+      error_at_peek: false,
+      strings: Interner::new(),
+      eval: false,
     }
   }
 
@@ -41,15 +82,20 @@ impl<'source> Parser<'source> {
     self.compiler = Some(self.compiler.take().unwrap().function(name));
   }
 
+  pub fn method_compiler(&mut self, name: &str) {
+    self.compiler = Some(self.compiler.take().unwrap().method(name));
+  }
+
   pub fn end_compiler(&mut self) -> (Closure, Vec<Upvalue>) {
     self.emitter().emit_op(Op::Nil);
     self.emitter().emit_op(Op::Return);
     let (enclosing, function, upvalues) = self.compiler.take().unwrap().end();
     self.compiler = enclosing;
+    let closure = Closure::new(function, upvalues.len() as u8);
     if let Some(ref mut inspector) = self.inspector {
-      inspector.catch_bytecode(function.clone());
+      inspector.catch_bytecode(closure.clone());
     }
-    (Closure::new(function, upvalues.len() as u8), upvalues)
+    (closure, upvalues)
   }
 
   pub fn into_inspector(self) -> Option<Inspector> {
@@ -64,13 +110,48 @@ impl<'source> Parser<'source> {
   }
 
   fn emitter(&mut self) -> &mut Chunk {
-    self.get_compiler_mut().chunk()
+    let line = self.line;
+    let chunk = self.get_compiler_mut().chunk();
+    chunk.set_line(line);
+    chunk
   }
 
-  pub fn advance(&mut self) -> Result<Option<Token>, String> {
+  /// Adds a string constant that shares storage with every equal name or
+  /// literal compiled before it, even in other functions.
+  fn string_constant(&mut self, string: &str) -> Result<u8, String> {
+    let string = Value::shared_string(self.strings.intern(string));
+    self.emitter().add_constant(string)
+  }
+
+  fn emit_string(&mut self, string: &str) -> Result<(), String> {
+    let string = Value::shared_string(self.strings.intern(string));
+    self.emitter().emit_constant(string)
+  }
+
+  pub fn advance(&mut self) -> Result<Option<Token>, LoxError> {
     let current = self.peek.take();
-    self.peek = self.scanner.scan_token()?;
-    Ok(current)
+    if let Some(token) = &current {
+      self.line = token.line;
+      self.previous = Some(token.clone());
+    }
+    let mut error = None;
+    self.peek = loop {
+      match self.scanner.scan_token() {
+        Ok(token) => break token,
+        // Whatever couldn't be scanned is skipped, so that parsing can resume
+        // after it.
+        Err(e) => {
+          error.get_or_insert(e);
+        }
+      }
+    };
+    match error {
+      Some(error) => {
+        self.scan_error.get_or_insert(error.clone());
+        Err(error)
+      }
+      None => Ok(current),
+    }
   }
 
   fn eat(
@@ -78,19 +159,33 @@ impl<'source> Parser<'source> {
     token_type: TokenType,
     message: &str,
   ) -> Result<Token, String> {
+    if let Some(error) = &self.scan_error {
+      return Err(error.to_string());
+    }
     if matches!(&self.peek, Some(p) if p.token_type == token_type) {
       let token = self.advance()?.unwrap();
       return Ok(token);
     }
-    Err(message.to_owned())
+    Err(self.error_at_peek(message))
+  }
+
+  /// An error about `peek` rather than the token before it.
+  fn error_at_peek(&mut self, message: &str) -> String {
+    self.error_at_peek = true;
+    message.to_owned()
   }
 
   fn match_token(&mut self, token_type: TokenType) -> bool {
-    self.eat(token_type, "").is_ok()
+    if !self.check(token_type) {
+      return false;
+    }
+    // An error is kept in `scan_error` for the next `eat`.
+    let _ = self.advance();
+    true
   }
 
   fn is_end(&self) -> bool {
-    matches!(self.peek, None)
+    self.peek.is_none()
   }
 
   fn check(&self, token_type: TokenType) -> bool {
@@ -108,13 +203,59 @@ impl<'source> Parser<'source> {
     Ok(())
   }
 
+  fn assert_statement(&mut self) -> Result<(), String> {
+    self.expression()?;
+    if self.match_token(TokenType::Colon) {
+      self.expression()?;
+    } else {
+      self.emitter().emit_op(Op::Nil);
+    }
+    self.eat(TokenType::Semicolon, "Expect ';' after assertion.")?;
+    self.emitter().emit_op(Op::Assert);
+    Ok(())
+  }
+
   fn expression_statement(&mut self) -> Result<(), String> {
+    let first = match &self.peek {
+      Some(token) if token.token_type == TokenType::Identifier => {
+        Some(token.source.clone())
+      }
+      _ => None,
+    };
+    let start = self.emitter().codes.len();
     self.expression()?;
-    self.eat(TokenType::Semicolon, "Expect ';' after expression.")?;
-    self.emitter().emit_op(Op::Pop);
+    // A lone name followed by more code is most likely a misspelled keyword,
+    // as in `retrun x;`.
+    let is_lone_name = self.emitter().codes.len() == start + 2;
+    if let (Some(name), true) = (first, is_lone_name) {
+      if !self.check(TokenType::Semicolon) && !self.is_end() {
+        if let Some(keyword) = did_you_mean(&name, Scanner::KEYWORDS) {
+          return Err(format!(
+            "Unknown name '{}'; did you mean '{}'?",
+            name, keyword
+          ));
+        }
+      }
+    }
+    let evaluated = self.eval && self.is_top_level();
+    if !(evaluated && self.is_end()) {
+      self.eat(TokenType::Semicolon, "Expect ';' after expression.")?;
+    }
+    if evaluated && self.is_end() {
+      self.emitter().emit_op(Op::Return);
+    } else {
+      self.emitter().emit_op(Op::Pop);
+    }
     Ok(())
   }
 
+  /// Whether the code being compiled is in the script outside any block.
+  fn is_top_level(&mut self) -> bool {
+    let compiler = self.get_compiler_mut();
+    matches!(compiler.function.kind, FunctionKind::Script)
+      && compiler.scopes.is_empty()
+  }
+
   fn if_statement(&mut self) -> Result<(), String> {
     self.eat(TokenType::LeftParen, "Expect '(' after 'if'.")?;
     self.expression()?;
@@ -122,6 +263,7 @@ impl<'source> Parser<'source> {
 
     let then_jump = self.emitter().emit_jump(Op::JumpIfFalse)?;
     self.emitter().emit_op(Op::Pop);
+    self.get_compiler_mut().if_body = true;
     self.statement()?;
 
     let else_jump = self.emitter().emit_jump(Op::Jump)?;
@@ -137,6 +279,64 @@ impl<'source> Parser<'source> {
     Ok(())
   }
 
+  /// Compares the subject with each case in turn and runs the body of the
+  /// first that is equal, or else the `default` body; there is no
+  /// fallthrough.
+  fn switch_statement(&mut self) -> Result<(), String> {
+    self.begin_scope();
+    self.eat(TokenType::LeftParen, "Expect '(' after 'switch'.")?;
+    self.expression()?;
+    self.eat(TokenType::RightParen, "Expect ')' after switch subject.")?;
+    // The subject is evaluated once, into a local no code can name.
+    let scopes = &mut self.get_compiler_mut().scopes;
+    scopes.define_uninit_local("switch subject".to_owned())?;
+    scopes.mark_init_local("switch subject");
+    let subject = scopes.resolve_local("switch subject")?.unwrap().index;
+    self.eat(TokenType::LeftBrace, "Expect '{' before switch cases.")?;
+
+    let mut end_jumps = Vec::new();
+    while self.match_token(TokenType::Case) {
+      self.emitter().emit_get_local(subject);
+      self.expression()?;
+      self.eat(TokenType::Colon, "Expect ':' after case value.")?;
+      self.emitter().emit_op(Op::Equal);
+      let next_case = self.emitter().emit_jump(Op::JumpIfFalse)?;
+      self.emitter().emit_op(Op::Pop);
+      self.case_body()?;
+      end_jumps.push(self.emitter().emit_jump(Op::Jump)?);
+      self.emitter().patch_jump(next_case)?;
+      self.emitter().emit_op(Op::Pop);
+    }
+    if self.match_token(TokenType::Default) {
+      self.eat(TokenType::Colon, "Expect ':' after 'default'.")?;
+      self.case_body()?;
+      if self.check(TokenType::Case) {
+        return Err(self.error_at_peek("The default case must come last."));
+      }
+    }
+    self.eat(TokenType::RightBrace, "Expect '}' after switch cases.")?;
+
+    for jump in end_jumps {
+      self.emitter().patch_jump(jump)?;
+    }
+    self.end_scope()?;
+    Ok(())
+  }
+
+  fn case_body(&mut self) -> Result<(), String> {
+    self.begin_scope();
+    self.get_compiler_mut().terminated = false;
+    while !self.is_end()
+      && !self.check(TokenType::Case)
+      && !self.check(TokenType::Default)
+      && !self.check(TokenType::RightBrace)
+    {
+      self.reachable_declaration()?;
+    }
+    self.end_scope()?;
+    Ok(())
+  }
+
   fn return_statement(&mut self) -> Result<(), String> {
     if let FunctionKind::Script = self.get_compiler_mut().function.kind {
       return Err("Can't return from top-level code.".to_owned());
@@ -150,10 +350,11 @@ impl<'source> Parser<'source> {
       self.eat(TokenType::Semicolon, "Expect ';' after return value.")?;
       self.emitter().emit_op(Op::Return);
     }
+    self.get_compiler_mut().terminated = true;
     Ok(())
   }
 
-  fn while_statement(&mut self) -> Result<(), String> {
+  fn while_statement(&mut self, if_body: bool) -> Result<(), String> {
     let loop_start = self.emitter().code_len()?;
     self.eat(TokenType::LeftParen, "Expect '(' after 'while'.")?;
     self.expression()?;
@@ -161,16 +362,43 @@ impl<'source> Parser<'source> {
 
     let exit_jump = self.emitter().emit_jump(Op::JumpIfFalse)?;
     self.emitter().emit_op(Op::Pop);
+    self.begin_loop(Some(loop_start));
     self.statement()?;
     self.emitter().emit_loop(loop_start)?;
 
     self.emitter().patch_jump(exit_jump)?;
     self.emitter().emit_op(Op::Pop);
+    self.end_loop(if_body)?;
 
     Ok(())
   }
 
-  fn for_statement(&mut self) -> Result<(), String> {
+  /// Checks the condition after the body, so the body runs at least once.
+  fn do_statement(&mut self, if_body: bool) -> Result<(), String> {
+    let loop_start = self.emitter().code_len()?;
+    self.begin_loop(None);
+    self.statement()?;
+    let current = self.get_compiler_mut().loops.last_mut().unwrap();
+    for jump in std::mem::take(&mut current.continues) {
+      self.emitter().patch_jump(jump)?;
+    }
+
+    self.eat(TokenType::While, "Expect 'while' after do body.")?;
+    self.eat(TokenType::LeftParen, "Expect '(' after 'while'.")?;
+    self.expression()?;
+    self.eat(TokenType::RightParen, "Expect ')' after condition.")?;
+    self.eat(TokenType::Semicolon, "Expect ';' after do-while condition.")?;
+
+    let exit_jump = self.emitter().emit_jump(Op::JumpIfFalse)?;
+    self.emitter().emit_op(Op::Pop);
+    self.emitter().emit_loop(loop_start)?;
+
+    self.emitter().patch_jump(exit_jump)?;
+    self.emitter().emit_op(Op::Pop);
+    self.end_loop(if_body)
+  }
+
+  fn for_statement(&mut self, if_body: bool) -> Result<(), String> {
     self.begin_scope();
 
     self.eat(TokenType::LeftParen, "Expect '(' after 'for'.")?;
@@ -205,6 +433,7 @@ impl<'source> Parser<'source> {
       self.emitter().patch_jump(body_jump)?;
     }
 
+    self.begin_loop(Some(loop_start));
     self.statement()?;
     self.emitter().emit_loop(loop_start)?;
 
@@ -212,8 +441,199 @@ impl<'source> Parser<'source> {
       self.emitter().patch_jump(exit_jump)?;
       self.emitter().emit_op(Op::Pop);
     }
+    self.end_loop(if_body)?;
+
+    self.end_scope()?;
+    Ok(())
+  }
+
+  fn break_statement(&mut self) -> Result<(), String> {
+    let (depth, tries) = match self.get_compiler_mut().loops.last() {
+      Some(current) => (current.depth, current.tries),
+      None => return Err("Can't use 'break' outside of a loop.".to_owned()),
+    };
+    self.eat(TokenType::Semicolon, "Expect ';' after 'break'.")?;
+    self.leave_loop_body(depth, tries);
+    let jump = self.emitter().emit_jump(Op::Jump)?;
+    let current = self.get_compiler_mut().loops.last_mut().unwrap();
+    current.breaks.push(jump);
+    Ok(())
+  }
+
+  fn continue_statement(&mut self) -> Result<(), String> {
+    let (start, depth, tries) = match self.get_compiler_mut().loops.last() {
+      Some(current) => (current.start, current.depth, current.tries),
+      None => return Err("Can't use 'continue' outside of a loop.".to_owned()),
+    };
+    self.eat(TokenType::Semicolon, "Expect ';' after 'continue'.")?;
+    self.leave_loop_body(depth, tries);
+    match start {
+      Some(start) => self.emitter().emit_loop(start),
+      None => {
+        let jump = self.emitter().emit_jump(Op::Jump)?;
+        let current = self.get_compiler_mut().loops.last_mut().unwrap();
+        current.continues.push(jump);
+        Ok(())
+      }
+    }
+  }
+
+  /// Drops what the loop body has set up before jumping out of it: handlers
+  /// of `try` statements inside the body and the body's locals. Each handler
+  /// is popped once the locals above it are gone, since popping a `finally`
+  /// handler runs its block right there.
+  fn leave_loop_body(&mut self, depth: usize, tries: usize) {
+    let handlers = self.get_compiler_mut().tries[tries..].to_vec();
+    let mut discarded = 0;
+    for handler in handlers.iter().rev() {
+      let locals = self.get_compiler_mut().scopes.locals_above(*handler);
+      self.discard_locals(locals[discarded..].to_vec());
+      discarded = locals.len();
+      self.emitter().emit_op(Op::PopCatch);
+    }
+    let locals = self.get_compiler_mut().scopes.locals_above(depth);
+    self.discard_locals(locals[discarded..].to_vec());
+  }
+
+  fn try_statement(&mut self) -> Result<(), String> {
+    let (catches, finally) = self.try_clauses();
+    let finally_handler = if finally {
+      Some(self.setup_handler(Op::SetupFinally)?)
+    } else {
+      None
+    };
+    let catch_handler = if catches || !finally {
+      Some(self.setup_handler(Op::SetupCatch)?)
+    } else {
+      None
+    };
+    self.eat(TokenType::LeftBrace, "Expect '{' after 'try'.")?;
+    self.begin_scope();
+    self.block()?;
+    self.end_scope()?;
+
+    if let Some(handler) = catch_handler {
+      self.catch_clause(handler)?;
+    }
+    if let Some(handler) = finally_handler {
+      self.finally_clause(handler)?;
+    }
+    Ok(())
+  }
+
+  /// Looks past the `try` block for the clauses after it without consuming
+  /// anything, as their handlers are set up before the block is compiled.
+  /// Scan errors are left for the real parse to report.
+  fn try_clauses(&mut self) -> (bool, bool) {
+    let position = self.scanner.position();
+    let mut tokens = iter::from_fn(|| self.scanner.scan_token().ok().flatten())
+      .map(|token| token.token_type)
+      .peekable();
+    skip_block(&mut tokens);
+    let catches = tokens.next_if_eq(&TokenType::Catch).is_some();
+    if catches {
+      tokens.by_ref().find(|token| *token == TokenType::LeftBrace);
+      skip_block(&mut tokens);
+    }
+    let finally = tokens.next() == Some(TokenType::Finally);
+    self.scanner.rewind(position);
+    (catches, finally)
+  }
+
+  fn setup_handler(&mut self, op: Op) -> Result<u16, String> {
+    let depth = self.get_compiler_mut().scopes.depth();
+    self.get_compiler_mut().tries.push(depth);
+    self.emitter().emit_jump(op)
+  }
+
+  fn pop_handler(&mut self) {
+    self.get_compiler_mut().tries.pop();
+    self.emitter().emit_op(Op::PopCatch);
+  }
+
+  fn catch_clause(&mut self, handler: u16) -> Result<(), String> {
+    self.pop_handler();
+    let end_jump = self.emitter().emit_jump(Op::Jump)?;
+
+    // The VM enters the handler with the error as the only new stack value,
+    // which becomes the catch variable.
+    self.emitter().patch_jump(handler)?;
+    self.eat(
+      TokenType::Catch,
+      "Expect 'catch' or 'finally' after try block.",
+    )?;
+    self.eat(TokenType::LeftParen, "Expect '(' after 'catch'.")?;
+    let token =
+      self.eat(TokenType::Identifier, "Expect error variable name.")?;
+    self.eat(TokenType::RightParen, "Expect ')' after error variable.")?;
+    self.eat(TokenType::LeftBrace, "Expect '{' after catch clause.")?;
+    self.begin_scope();
+    self.parse_local_variable(&token.source)?;
+    // Like a parameter, the error variable has to be named even if unused.
+    let scopes = &mut self.get_compiler_mut().scopes;
+    scopes.mark_init_local(&token.source);
+    scopes.mark_used_local(&token.source);
+    self.block()?;
+    self.end_scope()?;
+
+    self.emitter().patch_jump(end_jump)?;
+    Ok(())
+  }
+
+  /// Compiles the `finally` block once, after the rest of the statement.
+  /// Popping its handler runs it and comes back, so leaving the `try`
+  /// statement normally just jumps over it.
+  fn finally_clause(&mut self, handler: u16) -> Result<(), String> {
+    self.pop_handler();
+    let end_jump = self.emitter().emit_jump(Op::Jump)?;
+
+    // The VM enters the block with two values saying how to carry on once it
+    // ends, which EndFinally consumes.
+    self.emitter().patch_jump(handler)?;
+    self.eat(TokenType::Finally, "Expect 'finally' after try block.")?;
+    self.eat(TokenType::LeftBrace, "Expect '{' after 'finally'.")?;
+    self.begin_scope();
+    for name in ["finally value", "finally kind"] {
+      let scopes = &mut self.get_compiler_mut().scopes;
+      scopes.define_uninit_local(name.to_owned())?;
+      scopes.mark_init_local(name);
+    }
+    self.begin_scope();
+    self.block()?;
+    self.end_scope()?;
+    self.get_compiler_mut().scopes.pop();
+    self.emitter().emit_op(Op::EndFinally);
 
-    self.end_scope();
+    self.emitter().patch_jump(end_jump)?;
+    Ok(())
+  }
+
+  fn throw_statement(&mut self) -> Result<(), String> {
+    self.expression()?;
+    self.eat(TokenType::Semicolon, "Expect ';' after thrown value.")?;
+    self.emitter().emit_op(Op::Throw);
+    Ok(())
+  }
+
+  fn begin_loop(&mut self, start: Option<u16>) {
+    let compiler = self.get_compiler_mut();
+    let depth = compiler.scopes.depth();
+    let tries = compiler.tries.len();
+    compiler.loops.push(LoopCompiler::new(start, depth, tries));
+  }
+
+  /// Ends the loop with its `else` clause, if any, which runs when the loop
+  /// exits normally: a `break` jumps over it. A loop that is the body of an
+  /// `if` has no `else` clause, as the `else` after it is the `if`'s.
+  fn end_loop(&mut self, if_body: bool) -> Result<(), String> {
+    let current = self.get_compiler_mut().loops.pop().unwrap();
+    // The clause is outside the loop, for a `break` in it.
+    if !if_body && self.match_token(TokenType::Else) {
+      self.statement()?;
+    }
+    for jump in current.breaks {
+      self.emitter().patch_jump(jump)?;
+    }
     Ok(())
   }
 
@@ -221,47 +641,79 @@ impl<'source> Parser<'source> {
     self.get_compiler_mut().scopes.push();
   }
 
-  fn end_scope(&mut self) {
-    let compiler = self.get_compiler_mut();
-    let scope = compiler.scopes.pop().unwrap();
+  fn end_scope(&mut self) -> Result<(), String> {
+    let scope = self.get_compiler_mut().scopes.pop().unwrap();
+    self.discard_locals(scope.locals_rev());
+    scope.check_used()
+  }
 
-    for _ in 0..scope.len() {
-      compiler.chunk().emit_op(Op::Pop);
+  fn discard_locals(&mut self, locals: Vec<Local>) {
+    let chunk = self.emitter();
+    for local in locals {
+      if local.is_captured {
+        chunk.emit_op(Op::CloseUpvalue);
+      } else {
+        chunk.emit_op(Op::Pop);
+      }
     }
   }
 
   fn statement(&mut self) -> Result<(), String> {
+    let if_body = std::mem::take(&mut self.get_compiler_mut().if_body);
     if self.match_token(TokenType::Print) {
       self.print_statement()?;
+    } else if self.match_token(TokenType::Assert) {
+      self.assert_statement()?;
     } else if self.match_token(TokenType::If) {
       self.if_statement()?;
     } else if self.match_token(TokenType::Return) {
-      self.return_statement()?;
+      return self.return_statement();
     } else if self.match_token(TokenType::While) {
-      self.while_statement()?;
+      self.while_statement(if_body)?;
+    } else if self.match_token(TokenType::Do) {
+      self.do_statement(if_body)?;
     } else if self.match_token(TokenType::For) {
-      self.for_statement()?;
+      self.for_statement(if_body)?;
+    } else if self.match_token(TokenType::Break) {
+      self.break_statement()?;
+    } else if self.match_token(TokenType::Continue) {
+      self.continue_statement()?;
+    } else if self.match_token(TokenType::Switch) {
+      self.switch_statement()?;
+    } else if self.match_token(TokenType::Try) {
+      self.try_statement()?;
+    } else if self.match_token(TokenType::Throw) {
+      self.throw_statement()?;
     } else if self.match_token(TokenType::LeftBrace) {
       self.begin_scope();
       self.block()?;
-      self.end_scope();
+      self.end_scope()?;
     } else {
       self.expression_statement()?;
     }
+    // Code after a branch that returns may still be reached.
+    self.get_compiler_mut().terminated = false;
     Ok(())
   }
 
   fn block(&mut self) -> Result<(), String> {
+    // A block can follow a `return` in another branch, as an `else` does.
+    self.get_compiler_mut().terminated = false;
     while !self.is_end() && !self.check(TokenType::RightBrace) {
-      self.declaration()?;
+      self.reachable_declaration()?;
     }
 
     self.eat(TokenType::RightBrace, "Expect '}' after block.")?;
+    self.get_compiler_mut().terminated = false;
     Ok(())
   }
 
   fn function(&mut self, name: &str) -> Result<(), String> {
     self.function_compiler(name);
+    self.function_body()
+  }
+
+  fn function_body(&mut self) -> Result<(), String> {
     self.begin_scope();
 
     self.eat(TokenType::LeftParen, "Expect '(' after function name.")?;
@@ -273,13 +725,21 @@ impl<'source> Parser<'source> {
           .arity
           .checked_add(1)
           .ok_or("Can't have more than 255 parameters.")?;
+        let rest = self.match_token(TokenType::Ellipsis);
         let token =
           self.eat(TokenType::Identifier, "Expect parameter name.")?;
         let name = &token.source;
         self.parse_local_variable(name)?;
-        self.get_compiler_mut().scopes.mark_init_local(name);
+        if rest {
+          self.rest_parameter()?;
+        } else {
+          self.default_value()?;
+        }
+        let scopes = &mut self.get_compiler_mut().scopes;
+        scopes.mark_init_local(name);
+        scopes.mark_used_local(name);
 
-        if !self.match_token(TokenType::Comma) {
+        if rest || !self.match_token(TokenType::Comma) {
           break;
         }
       }
@@ -288,6 +748,8 @@ impl<'source> Parser<'source> {
     self.eat(TokenType::LeftBrace, "Expect '{' before function body.")?;
 
     self.block()?;
+    let scope = self.get_compiler_mut().scopes.pop().unwrap();
+    scope.check_used()?;
 
     let (function, upvalues) = self.end_compiler();
     self.emitter().emit_closure(function)?;
@@ -296,16 +758,50 @@ impl<'source> Parser<'source> {
     Ok(())
   }
 
+  /// Compiles the default value of the parameter just declared, if it has
+  /// one, to be evaluated into its slot when the caller leaves it out.
+  fn default_value(&mut self) -> Result<(), String> {
+    if !self.match_token(TokenType::Equal) {
+      return match self.get_compiler_mut().function.optional {
+        0 => Ok(()),
+        _ => Err(self.error_at_peek(
+          "Expect default value after a parameter that has one.",
+        )),
+      };
+    }
+    let function = &mut self.get_compiler_mut().function;
+    // The parameter's slot is its position, after the closure or receiver.
+    let slot = function.arity;
+    function.optional += 1;
+    let skip = self.emitter().emit_jump_if_arg(slot)?;
+    self.expression()?;
+    self.emitter().patch_jump(skip)
+  }
+
+  /// Makes the parameter just declared, which must be the last, collect the
+  /// arguments after the others in a list, empty when there are none.
+  fn rest_parameter(&mut self) -> Result<(), String> {
+    let function = &mut self.get_compiler_mut().function;
+    function.variadic = true;
+    let slot = function.arity;
+    let skip = self.emitter().emit_jump_if_arg(slot)?;
+    self.emitter().emit_build_list(0);
+    self.emitter().patch_jump(skip)
+  }
+
   fn fun_declaration(&mut self) -> Result<(), String> {
     let token = self.eat(TokenType::Identifier, "Expect function name.")?;
     let name = &token.source;
 
     let global = if self.get_compiler_mut().scopes.is_empty() {
-      let global = self.emitter().add_constant(Value::string(name))?;
+      let global = self.string_constant(name)?;
       Some(global)
     } else {
       self.parse_local_variable(name)?;
-      self.get_compiler_mut().scopes.mark_init_local(name);
+      let scopes = &mut self.get_compiler_mut().scopes;
+      scopes.mark_init_local(name);
+      // Only variables are reported when unused.
+      scopes.mark_used_local(name);
       None
     };
 
@@ -318,16 +814,79 @@ impl<'source> Parser<'source> {
     Ok(())
   }
 
-  fn parse_local_variable(&mut self, name: &str) -> Result<(), String> {
-    if self.get_compiler_mut().scopes.current_has(name).unwrap() {
-      Err("Already a variable with this name in this scope.".to_owned())
+  fn method(&mut self) -> Result<(), String> {
+    let token = self.eat(TokenType::Identifier, "Expect method name.")?;
+    let name = &token.source;
+    let constant = self.string_constant(name)?;
+
+    self.method_compiler(name);
+    self.function_body()?;
+    self.emitter().emit_method(constant);
+    Ok(())
+  }
+
+  fn class_declaration(&mut self) -> Result<(), String> {
+    let token = self.eat(TokenType::Identifier, "Expect class name.")?;
+    let name = &token.source;
+    let name_constant = self.string_constant(name)?;
+
+    let global = if self.get_compiler_mut().scopes.is_empty() {
+      Some(name_constant)
     } else {
-      self
-        .get_compiler_mut()
-        .scopes
-        .define_uninit_local(name.to_owned())?;
-      Ok(())
+      self.parse_local_variable(name)?;
+      let scopes = &mut self.get_compiler_mut().scopes;
+      scopes.mark_init_local(name);
+      // Only variables are reported when unused.
+      scopes.mark_used_local(name);
+      None
+    };
+
+    self.emitter().emit_class(name_constant);
+    if let Some(global) = global {
+      self.emitter().emit_define_global(global);
+    }
+
+    self.classes.push(ClassCompiler::new());
+    let result = self.class_body(name);
+    self.classes.pop();
+    result
+  }
+
+  fn class_body(&mut self, name: &str) -> Result<(), String> {
+    if self.match_token(TokenType::Less) {
+      let superclass =
+        self.eat(TokenType::Identifier, "Expect superclass name.")?;
+      if superclass.source == name {
+        return Err("A class can't inherit from itself.".to_owned());
+      }
+      self.named_variable(&superclass.source, false)?;
+
+      self.begin_scope();
+      self.parse_local_variable("super")?;
+      self.get_compiler_mut().scopes.mark_init_local("super");
+
+      self.named_variable(name, false)?;
+      self.emitter().emit_op(Op::Inherit);
+      self.classes.last_mut().unwrap().has_superclass = true;
+    }
+
+    self.named_variable(name, false)?;
+    self.eat(TokenType::LeftBrace, "Expect '{' before class body.")?;
+    while !self.is_end() && !self.check(TokenType::RightBrace) {
+      self.method()?;
+    }
+    self.eat(TokenType::RightBrace, "Expect '}' after class body.")?;
+    self.emitter().emit_op(Op::Pop);
+
+    if self.classes.last().unwrap().has_superclass {
+      self.end_scope()?;
     }
+    Ok(())
+  }
+
+  fn parse_local_variable(&mut self, name: &str) -> Result<(), String> {
+    let scopes = &mut self.get_compiler_mut().scopes;
+    scopes.define_uninit_local(name.to_owned())
   }
 
   fn var_declaration(&mut self) -> Result<(), String> {
@@ -335,16 +894,17 @@ impl<'source> Parser<'source> {
     let name = &token.source;
 
     let global = if self.get_compiler_mut().scopes.is_empty() {
-      let global = self.emitter().add_constant(Value::string(name))?;
+      let global = self.string_constant(name)?;
       Some(global)
     } else {
       self.parse_local_variable(name)?;
       None
     };
 
-    if self.match_token(TokenType::Equal) {
+    let has_initializer = self.match_token(TokenType::Equal);
+    if has_initializer {
       self.expression()?;
-    } else {
+    } else if global.is_none() {
       self.emitter().emit_op(Op::Nil);
     }
     self.eat(
@@ -353,14 +913,19 @@ impl<'source> Parser<'source> {
     )?;
 
     match global {
-      Some(global) => self.emitter().emit_define_global(global),
+      Some(global) if has_initializer => {
+        self.emitter().emit_define_global(global)
+      }
+      Some(global) => self.emitter().emit_declare_global(global),
       None => self.get_compiler_mut().scopes.mark_init_local(name),
     }
     Ok(())
   }
 
   fn declaration(&mut self) -> Result<(), String> {
-    if self.match_token(TokenType::Fun) {
+    if self.match_token(TokenType::Class) {
+      self.class_declaration()
+    } else if self.match_token(TokenType::Fun) {
       self.fun_declaration()
     } else if self.match_token(TokenType::Var) {
       self.var_declaration()
@@ -369,15 +934,104 @@ impl<'source> Parser<'source> {
     }
   }
 
-  pub fn program(&mut self) -> Result<(), String> {
+  /// Compiles a declaration in a block, which must not follow a `return`.
+  fn reachable_declaration(&mut self) -> Result<(), String> {
+    if self.get_compiler_mut().terminated {
+      return Err(self.error_at_peek("Unreachable code."));
+    }
+    self.declaration()
+  }
+
+  /// Compiles the rest of the source, carrying on after each error from the
+  /// next statement so that they can all be reported.
+  pub fn program(&mut self) -> Result<(), Vec<LoxError>> {
+    let mut errors = Vec::new();
     while !self.is_end() {
-      self.declaration()?;
+      if !errors.is_empty() && self.is_leftover() {
+        let _ = self.advance();
+        continue;
+      }
+      let result = self.declaration();
+      let error = match (self.scan_error.take(), result) {
+        // Any compile error is most likely caused by the scan error.
+        (Some(error), _) => error,
+        (None, Err(msg)) => self.compile_error(msg),
+        (None, Ok(())) => continue,
+      };
+      errors.push(error);
+      self.synchronize();
+    }
+    errors.extend(self.scan_error.take());
+    match errors.is_empty() {
+      true => Ok(()),
+      false => Err(errors),
+    }
+  }
+
+  /// Skips to the start of the next statement and drops what was being
+  /// compiled when the error cut it short.
+  fn synchronize(&mut self) {
+    self.error_at_peek = false;
+    self.compiler = Some(self.compiler.take().unwrap().recover());
+    self.classes.clear();
+    while let Some(token) = &self.peek {
+      let after_statement = matches!(
+        &self.previous,
+        Some(previous) if previous.token_type == TokenType::Semicolon
+      );
+      let starts_statement = matches!(
+        token.token_type,
+        TokenType::Assert
+          | TokenType::Break
+          | TokenType::Class
+          | TokenType::Continue
+          | TokenType::Do
+          | TokenType::For
+          | TokenType::Fun
+          | TokenType::If
+          | TokenType::Print
+          | TokenType::Return
+          | TokenType::Switch
+          | TokenType::Throw
+          | TokenType::Try
+          | TokenType::Var
+          | TokenType::While
+      );
+      if (after_statement || starts_statement) && !self.is_leftover() {
+        break;
+      }
+      let _ = self.advance();
+    }
+    // Errors in what was skipped are most likely caused by the first one.
+    self.scan_error = None;
+  }
+
+  /// Whether the next token is what's left of a block or switch abandoned
+  /// after an error, which can't start a statement at the top level.
+  fn is_leftover(&self) -> bool {
+    matches!(
+      self.peek.as_ref().map(|token| token.token_type),
+      Some(TokenType::RightBrace | TokenType::Case | TokenType::Default)
+    )
+  }
+
+  fn compile_error(&self, msg: String) -> LoxError {
+    let (line, at) = match &self.peek {
+      Some(token) if self.error_at_peek => (token.line, Some(&token.source)),
+      _ if self.error_at_peek => (self.line, None),
+      _ => (self.line, self.previous.as_ref().map(|token| &token.source)),
+    };
+    LoxError::Compile {
+      line,
+      at: at.cloned(),
+      msg,
     }
-    Ok(())
   }
 
   fn parse_precedence(&mut self, precedence: Precedence) -> Result<(), String> {
     if let Some(token) = self.advance()? {
+      let chunk = self.emitter();
+      let start = (chunk.codes.len(), chunk.constants.len());
       let prefix =
         token.token_type.rule().prefix.ok_or("Expect expression.")?;
       let can_assign = precedence <= Precedence::Assignment;
@@ -388,10 +1042,14 @@ impl<'source> Parser<'source> {
         if let Some(token) = self.advance()? {
           let infix =
             token.token_type.rule().infix.ok_or("Expect expression.")?;
+          self.operand_start = start;
           infix(self, token, can_assign)?;
         }
       }
-      if can_assign && self.match_token(TokenType::Equal) {
+      if can_assign
+        && (self.match_token(TokenType::Equal)
+          || self.match_compound_assignment().is_some())
+      {
         return Err("Invalid assignment target.".to_owned());
       }
     }
@@ -403,41 +1061,129 @@ impl<'source> Parser<'source> {
     token: Token,
     can_assign: bool,
   ) -> Result<(), String> {
+    self.named_variable(&token.source, can_assign)
+  }
+
+  fn named_variable(
+    &mut self,
+    name: &str,
+    can_assign: bool,
+  ) -> Result<(), String> {
+    let compound = if can_assign {
+      self.match_compound_assignment()
+    } else {
+      None
+    };
     let is_set = can_assign && self.match_token(TokenType::Equal);
-    let name = &token.source;
-    let local = self.get_compiler_mut().scopes.resolve_local(name)?;
-    match (is_set, local) {
-      (true, None) => {
-        if let Some(upvalue) = self.get_compiler_mut().resolve_upvalue(name)? {
-          self.expression()?;
-          self.emitter().emit_set_upvalue(upvalue);
-        } else {
-          let global = self.emitter().add_constant(Value::string(name))?;
-          self.expression()?;
-          self.emitter().emit_set_global(global);
-        }
-      }
-      (false, None) => {
-        if let Some(upvalue) = self.get_compiler_mut().resolve_upvalue(name)? {
-          self.emitter().emit_get_upvalue(upvalue);
-        } else {
-          let global = self.emitter().add_constant(Value::string(name))?;
-          self.emitter().emit_get_global(global);
-        }
-      }
-      (true, Some(local)) => {
-        let index = local.index;
-        self.expression()?;
-        self.emitter().emit_set_local(index);
-      }
-      (false, Some(local)) => {
-        let index = local.index;
-        self.emitter().emit_get_local(index);
-      }
+    let variable = self.resolve_variable(name)?;
+    if let Some(op) = compound {
+      // `a += b` is compiled as `a = a + b`.
+      self.emit_get_variable(&variable);
+      self.expression()?;
+      self.emitter().emit_op(op);
+      self.emit_set_variable(&variable);
+    } else if is_set {
+      self.expression()?;
+      self.emit_set_variable(&variable);
+    } else if let Some(op) = self.match_increment() {
+      // `x++` leaves the value from before the step.
+      self.emit_get_variable(&variable);
+      self.emit_step(&variable, op)?;
+      self.emitter().emit_op(Op::Pop);
+    } else {
+      self.emit_get_variable(&variable);
+    }
+    Ok(())
+  }
+
+  /// `++x` and `--x`, which leave the updated value.
+  pub fn increment(
+    &mut self,
+    token: Token,
+    _can_assign: bool,
+  ) -> Result<(), String> {
+    let message = format!("Operand of '{}' must be a variable.", token.source);
+    let name = self.eat(TokenType::Identifier, &message)?;
+    let variable = self.resolve_variable(&name.source)?;
+    let op = match token.token_type {
+      TokenType::PlusPlus => Op::Add,
+      _ => Op::Subtract,
+    };
+    self.emit_step(&variable, op)
+  }
+
+  /// `x++` and `x--` after anything but a variable, which `named_variable`
+  /// takes care of.
+  pub fn postfix_increment(
+    &mut self,
+    token: Token,
+    _can_assign: bool,
+  ) -> Result<(), String> {
+    Err(format!("Operand of '{}' must be a variable.", token.source))
+  }
+
+  fn match_increment(&mut self) -> Option<Op> {
+    if self.match_token(TokenType::PlusPlus) {
+      Some(Op::Add)
+    } else if self.match_token(TokenType::MinusMinus) {
+      Some(Op::Subtract)
+    } else {
+      None
     }
+  }
+
+  /// Adds or subtracts one, leaving the new value.
+  fn emit_step(&mut self, variable: &Variable, op: Op) -> Result<(), String> {
+    self.emit_get_variable(variable);
+    self.emitter().emit_constant(Value::int(1))?;
+    self.emitter().emit_op(op);
+    self.emit_set_variable(variable);
     Ok(())
   }
 
+  /// Consumes a compound assignment operator, returning the arithmetic it
+  /// stands for.
+  fn match_compound_assignment(&mut self) -> Option<Op> {
+    if self.match_token(TokenType::PlusEqual) {
+      Some(Op::Add)
+    } else if self.match_token(TokenType::MinusEqual) {
+      Some(Op::Subtract)
+    } else if self.match_token(TokenType::StarEqual) {
+      Some(Op::Multiply)
+    } else if self.match_token(TokenType::SlashEqual) {
+      Some(Op::Divide)
+    } else {
+      None
+    }
+  }
+
+  fn resolve_variable(&mut self, name: &str) -> Result<Variable, String> {
+    if let Some(local) = self.get_compiler_mut().scopes.resolve_local(name)? {
+      return Ok(Variable::Local(local.index));
+    }
+    if let Some(upvalue) = self.get_compiler_mut().resolve_upvalue(name)? {
+      return Ok(Variable::Upvalue(upvalue));
+    }
+    let global = self.string_constant(name)?;
+    Ok(Variable::Global(global))
+  }
+
+  fn emit_get_variable(&mut self, variable: &Variable) {
+    match *variable {
+      Variable::Local(index) => self.emitter().emit_get_local(index),
+      Variable::Upvalue(index) => self.emitter().emit_get_upvalue(index),
+      Variable::Global(constant) => self.emitter().emit_get_global(constant),
+    }
+  }
+
+  fn emit_set_variable(&mut self, variable: &Variable) {
+    match *variable {
+      Variable::Local(index) => self.emitter().emit_set_local(index),
+      Variable::Upvalue(index) => self.emitter().emit_set_upvalue(index),
+      Variable::Global(constant) => self.emitter().emit_set_global(constant),
+    }
+  }
+
   pub fn grouping(
     &mut self,
     _token: Token,
@@ -453,11 +1199,27 @@ impl<'source> Parser<'source> {
     token: Token,
     _can_assign: bool,
   ) -> Result<(), String> {
-    let constant = token
-      .source
-      .parse::<f64>()
-      .map_err(|_e| "ParseFloatError".to_owned())?;
-    self.emitter().emit_constant(Value::number(constant))?;
+    let source = token.source.replace('_', "");
+    let radix = match source.get(..2) {
+      Some("0x" | "0X") => 16,
+      Some("0b" | "0B") => 2,
+      _ => 10,
+    };
+    if radix != 10 {
+      let int = i64::from_str_radix(&source[2..], radix)
+        .map_err(|_| "Invalid number literal.")?;
+      self.emitter().emit_constant(Value::int(int))?;
+      return Ok(());
+    }
+    // Literals without a fraction are integers, unless too big for one.
+    let constant = match source.parse::<i64>() {
+      Ok(int) => Value::int(int),
+      Err(_) => source
+        .parse::<f64>()
+        .map(Value::number)
+        .map_err(|_e| "ParseFloatError".to_owned())?,
+    };
+    self.emitter().emit_constant(constant)?;
     Ok(())
   }
 
@@ -466,11 +1228,52 @@ impl<'source> Parser<'source> {
     token: Token,
     _can_assign: bool,
   ) -> Result<(), String> {
-    let string = &token.source[1..(token.length - 1)];
-    self.emitter().emit_constant(Value::string(string))?;
+    let mut string = string_contents(&token)?;
+    // Adjacent literals are joined at compile time, as in C.
+    while self.check(TokenType::String) {
+      let next = self.advance()?.unwrap();
+      string.push_str(&string_contents(&next)?);
+    }
+    self.emit_string(&string)?;
     Ok(())
   }
 
+  /// Compiles `"a${b}c"` as `"a" + str(b) + "c"`, with a token for each
+  /// string fragment around the interpolated expressions.
+  pub fn interpolation(
+    &mut self,
+    token: Token,
+    _can_assign: bool,
+  ) -> Result<(), String> {
+    let contents = string_contents(&token)?;
+    self.emit_string(&contents)?;
+    loop {
+      self.expression()?;
+      self.emitter().emit_op(Op::ToString);
+      self.emitter().emit_op(Op::Add);
+
+      let fragment = match self.advance()? {
+        Some(token)
+          if matches!(
+            token.token_type,
+            TokenType::Interpolation | TokenType::String
+          ) =>
+        {
+          token
+        }
+        _ => {
+          return Err("Expect '}' after interpolated expression.".to_owned())
+        }
+      };
+      let contents = string_contents(&fragment)?;
+      self.emit_string(&contents)?;
+      self.emitter().emit_op(Op::Add);
+      if fragment.token_type == TokenType::String {
+        return Ok(());
+      }
+    }
+  }
+
   pub fn literal(
     &mut self,
     token: Token,
@@ -505,8 +1308,15 @@ impl<'source> Parser<'source> {
     token: Token,
     _can_assign: bool,
   ) -> Result<(), String> {
+    let left = self.operand_start;
+    let right = self.emitter().codes.len();
     let precedence = token.token_type.rule().precedence;
-    self.parse_precedence(precedence.up())?;
+    // `**` is right-associative, so its right operand may contain another.
+    if token.token_type == TokenType::StarStar {
+      self.parse_precedence(precedence)?;
+    } else {
+      self.parse_precedence(precedence.up())?;
+    }
 
     match token.token_type {
       TokenType::BangEqual => {
@@ -524,15 +1334,35 @@ impl<'source> Parser<'source> {
         self.emitter().emit_op(Op::Greater);
         self.emitter().emit_op(Op::Not);
       }
-      TokenType::Plus => self.emitter().emit_op(Op::Add),
-      TokenType::Minus => self.emitter().emit_op(Op::Subtract),
-      TokenType::Star => self.emitter().emit_op(Op::Multiply),
-      TokenType::Slash => self.emitter().emit_op(Op::Divide),
+      TokenType::Plus => self.fold_or_emit(left, right, Op::Add)?,
+      TokenType::Minus => self.fold_or_emit(left, right, Op::Subtract)?,
+      TokenType::Star => self.fold_or_emit(left, right, Op::Multiply)?,
+      TokenType::Slash => self.fold_or_emit(left, right, Op::Divide)?,
+      TokenType::Percent => self.fold_or_emit(left, right, Op::Modulo)?,
+      TokenType::Ampersand => self.emitter().emit_op(Op::BitAnd),
+      TokenType::Pipe => self.emitter().emit_op(Op::BitOr),
+      TokenType::Caret => self.emitter().emit_op(Op::BitXor),
+      TokenType::LessLess => self.emitter().emit_op(Op::ShiftLeft),
+      TokenType::GreaterGreater => self.emitter().emit_op(Op::ShiftRight),
+      TokenType::StarStar => self.fold_or_emit(left, right, Op::Power)?,
       _ => unreachable!(),
     }
     Ok(())
   }
 
+  /// Emits `op`, unless its operands are literals it can be applied to now.
+  fn fold_or_emit(
+    &mut self,
+    left: (usize, usize),
+    right: usize,
+    op: Op,
+  ) -> Result<(), String> {
+    if !self.emitter().fold_binary(left, right, &op)? {
+      self.emitter().emit_op(op);
+    }
+    Ok(())
+  }
+
   pub fn and(
     &mut self,
     _token: Token,
@@ -555,14 +1385,35 @@ impl<'source> Parser<'source> {
     Ok(())
   }
 
+  pub fn conditional(
+    &mut self,
+    _token: Token,
+    _can_assign: bool,
+  ) -> Result<(), String> {
+    let else_jump = self.emitter().emit_jump(Op::JumpIfFalse)?;
+    self.emitter().emit_op(Op::Pop);
+    self.parse_precedence(Precedence::Conditional)?;
+    self.eat(TokenType::Colon, "Expect ':' after then branch.")?;
+
+    let end_jump = self.emitter().emit_jump(Op::Jump)?;
+    self.emitter().patch_jump(else_jump)?;
+    self.emitter().emit_op(Op::Pop);
+    // Same precedence on the right so `a ? b : c ? d : e` nests to the right.
+    self.parse_precedence(Precedence::Conditional)?;
+    self.emitter().patch_jump(end_jump)?;
+    Ok(())
+  }
+
   pub fn call(
     &mut self,
     _token: Token,
     _can_assign: bool,
   ) -> Result<(), String> {
     let mut arg_count: u8 = 0;
+    let mut spread = false;
     if !self.check(TokenType::RightParen) {
       loop {
+        spread = self.match_token(TokenType::Ellipsis);
         self.expression()?;
         arg_count = arg_count
           .checked_add(1)
@@ -571,10 +1422,211 @@ impl<'source> Parser<'source> {
         if !self.match_token(TokenType::Comma) {
           break;
         }
+        if spread {
+          return Err("Spread argument must be last.".to_owned());
+        }
       }
     }
     self.eat(TokenType::RightParen, "Expect ')' after arguments.")?;
-    self.emitter().emit_call(arg_count);
+    if spread {
+      self.emitter().emit_spread_call(arg_count);
+    } else {
+      self.emitter().emit_call(arg_count);
+    }
+    Ok(())
+  }
+
+  pub fn list(
+    &mut self,
+    _token: Token,
+    _can_assign: bool,
+  ) -> Result<(), String> {
+    let mut len: u8 = 0;
+    if !self.check(TokenType::RightBracket) {
+      loop {
+        self.expression()?;
+        len = len
+          .checked_add(1)
+          .ok_or("Can't have more than 255 elements in a list literal.")?;
+
+        if !self.match_token(TokenType::Comma) {
+          break;
+        }
+      }
+    }
+    self.eat(TokenType::RightBracket, "Expect ']' after list elements.")?;
+    self.emitter().emit_build_list(len);
     Ok(())
   }
+
+  /// Only reached in expression position; a `{` starting a statement is
+  /// parsed as a block.
+  pub fn map(
+    &mut self,
+    _token: Token,
+    _can_assign: bool,
+  ) -> Result<(), String> {
+    let mut len: u8 = 0;
+    if !self.check(TokenType::RightBrace) {
+      loop {
+        self.expression()?;
+        self.eat(TokenType::Colon, "Expect ':' after map key.")?;
+        self.expression()?;
+        len = len
+          .checked_add(1)
+          .ok_or("Can't have more than 255 entries in a map literal.")?;
+
+        if !self.match_token(TokenType::Comma) {
+          break;
+        }
+      }
+    }
+    self.eat(TokenType::RightBrace, "Expect '}' after map entries.")?;
+    self.emitter().emit_build_map(len);
+    Ok(())
+  }
+
+  /// Only reached in expression position; a `fun` starting a statement is
+  /// parsed as a declaration.
+  pub fn lambda(
+    &mut self,
+    _token: Token,
+    _can_assign: bool,
+  ) -> Result<(), String> {
+    self.function("<anonymous>")
+  }
+
+  pub fn index(
+    &mut self,
+    _token: Token,
+    can_assign: bool,
+  ) -> Result<(), String> {
+    self.expression()?;
+    self.eat(TokenType::RightBracket, "Expect ']' after index.")?;
+
+    if can_assign && self.match_token(TokenType::Equal) {
+      self.expression()?;
+      self.emitter().emit_op(Op::SetIndex);
+    } else {
+      self.emitter().emit_op(Op::Index);
+    }
+    Ok(())
+  }
+
+  pub fn this(
+    &mut self,
+    token: Token,
+    _can_assign: bool,
+  ) -> Result<(), String> {
+    if self.classes.is_empty() {
+      return Err("Can't use 'this' outside of a class.".to_owned());
+    }
+    self.variable(token, false)
+  }
+
+  pub fn super_(
+    &mut self,
+    _token: Token,
+    _can_assign: bool,
+  ) -> Result<(), String> {
+    match self.classes.last() {
+      None => {
+        return Err("Can't use 'super' outside of a class.".to_owned());
+      }
+      Some(class) if !class.has_superclass => {
+        return Err(
+          "Can't use 'super' in a class with no superclass.".to_owned(),
+        );
+      }
+      _ => {}
+    }
+
+    self.eat(TokenType::Dot, "Expect '.' after 'super'.")?;
+    let token =
+      self.eat(TokenType::Identifier, "Expect superclass method name.")?;
+    let name = self.string_constant(&token.source)?;
+
+    self.named_variable("this", false)?;
+    self.named_variable("super", false)?;
+    self.emitter().emit_get_super(name);
+    Ok(())
+  }
+
+  pub fn dot(&mut self, _token: Token, can_assign: bool) -> Result<(), String> {
+    let token =
+      self.eat(TokenType::Identifier, "Expect property name after '.'.")?;
+    let name = self.string_constant(&token.source)?;
+
+    if can_assign && self.match_token(TokenType::Equal) {
+      self.expression()?;
+      self.emitter().emit_set_property(name);
+    } else {
+      self.emitter().emit_get_property(name);
+    }
+    Ok(())
+  }
+}
+
+/// Where a named variable lives: its slot, upvalue or name constant.
+enum Variable {
+  Local(u8),
+  Upvalue(u8),
+  Global(u8),
+}
+
+/// Consumes tokens up to the `}` closing a block whose `{` is already
+/// consumed.
+fn skip_block(tokens: &mut impl Iterator<Item = TokenType>) {
+  let mut depth = 1;
+  for token in tokens {
+    match token {
+      TokenType::LeftBrace => depth += 1,
+      TokenType::RightBrace => depth -= 1,
+      _ => {}
+    }
+    if depth == 0 {
+      break;
+    }
+  }
+}
+
+/// The text of a string literal: a raw string's as written, any other's with
+/// its escape sequences replaced.
+fn string_contents(token: &Token) -> Result<String, String> {
+  let (raw, source) = match token.source.strip_prefix('r') {
+    Some(source) => (true, source),
+    None => (false, token.source.as_str()),
+  };
+  // A fragment after an interpolated expression starts at its closing `}`.
+  let start = if source.starts_with("\"\"\"") { 3 } else { 1 };
+  let end = if token.token_type == TokenType::Interpolation {
+    "${".len()
+  } else if source.len() >= start + 3 && source.ends_with("\"\"\"") {
+    3
+  } else {
+    1
+  };
+  let text = &source[start..(source.len() - end)];
+  if raw {
+    return Ok(text.to_owned());
+  }
+
+  let mut contents = String::new();
+  let mut chars = text.chars();
+  while let Some(c) = chars.next() {
+    if c != '\\' {
+      contents.push(c);
+      continue;
+    }
+    contents.push(match chars.next() {
+      Some('n') => '\n',
+      Some('t') => '\t',
+      Some('r') => '\r',
+      Some('"') => '"',
+      Some('\\') => '\\',
+      Some('$') => '$',
+      _ => return Err("Invalid escape sequence.".to_owned()),
+    });
+  }
+  Ok(contents)
 }
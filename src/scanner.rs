@@ -1,34 +1,79 @@
-use std::str::Chars;
-
-use crate::token::{Token, TokenType};
+use crate::{
+  error::LoxError,
+  token::{Token, TokenType},
+};
 
+/// Whether `c` can start an identifier: a letter in any script, or `_`.
 fn is_alpha(c: char) -> bool {
-  c.is_ascii_alphabetic()
+  c.is_alphabetic() || c == '_'
 }
 
 fn is_digit(c: char) -> bool {
   c.is_ascii_digit()
 }
 
+/// A `${` whose closing `}` hasn't been scanned yet.
+#[derive(Clone)]
+struct Interpolation {
+  /// `{`s opened inside the interpolated expression and not yet closed.
+  braces: usize,
+  triple: bool,
+}
+
+/// Where the scanner is, for `rewind` to come back to after looking ahead.
+#[derive(Clone)]
+pub struct Position {
+  index: usize,
+  line: usize,
+  interpolations: Vec<Interpolation>,
+}
+
 pub struct Scanner<'source> {
-  source: Chars<'source>,
+  source: &'source str,
+  /// The source's characters with their byte offsets, so that looking up a
+  /// character by position is O(1).
+  chars: Vec<(usize, char)>,
   start: usize,
   index: usize,
   line: usize,
+  interpolations: Vec<Interpolation>,
 }
 
 impl<'source> Scanner<'source> {
   pub fn new(source: &'source str) -> Self {
     Self {
-      source: source.chars(),
+      source,
+      chars: source.char_indices().collect(),
       start: 0,
       index: 0,
       line: 1,
+      interpolations: Vec::new(),
     }
   }
 
-  pub fn scan_token(&mut self) -> Result<Option<Token>, String> {
-    self.skip_whitespace();
+  pub fn position(&self) -> Position {
+    Position {
+      index: self.index,
+      line: self.line,
+      interpolations: self.interpolations.clone(),
+    }
+  }
+
+  pub fn rewind(&mut self, position: Position) {
+    self.index = position.index;
+    self.line = position.line;
+    self.interpolations = position.interpolations;
+  }
+
+  pub fn scan_token(&mut self) -> Result<Option<Token>, LoxError> {
+    self.scan().map_err(|msg| LoxError::Scan {
+      line: self.line,
+      msg,
+    })
+  }
+
+  fn scan(&mut self) -> Result<Option<Token>, String> {
+    self.skip_whitespace()?;
     self.start = self.index;
 
     let t = match self.advance() {
@@ -36,15 +81,76 @@ impl<'source> Scanner<'source> {
       Some(c) => match c {
         '(' => self.make_token(TokenType::LeftParen),
         ')' => self.make_token(TokenType::RightParen),
-        '{' => self.make_token(TokenType::LeftBrace),
-        '}' => self.make_token(TokenType::RightBrace),
+        '{' => {
+          if let Some(interpolation) = self.interpolations.last_mut() {
+            interpolation.braces += 1;
+          }
+          self.make_token(TokenType::LeftBrace)
+        }
+        '}' => match self.interpolations.last_mut() {
+          Some(Interpolation { braces: 0, triple }) => {
+            let triple = *triple;
+            self.interpolations.pop();
+            self.scan_string_body(false, triple)?
+          }
+          Some(interpolation) => {
+            interpolation.braces -= 1;
+            self.make_token(TokenType::RightBrace)
+          }
+          None => self.make_token(TokenType::RightBrace),
+        },
+        '[' => self.make_token(TokenType::LeftBracket),
+        ']' => self.make_token(TokenType::RightBracket),
         ';' => self.make_token(TokenType::Semicolon),
+        ':' => self.make_token(TokenType::Colon),
         ',' => self.make_token(TokenType::Comma),
-        '.' => self.make_token(TokenType::Dot),
-        '-' => self.make_token(TokenType::Minus),
-        '+' => self.make_token(TokenType::Plus),
-        '/' => self.make_token(TokenType::Slash),
-        '*' => self.make_token(TokenType::Star),
+        '.' => {
+          if self.peek() == Some('.') && self.peek_next() == Some('.') {
+            self.index += 2;
+            self.make_token(TokenType::Ellipsis)
+          } else {
+            self.make_token(TokenType::Dot)
+          }
+        }
+        '-' => {
+          if self.test('=') {
+            self.make_token(TokenType::MinusEqual)
+          } else if self.test('-') {
+            self.make_token(TokenType::MinusMinus)
+          } else {
+            self.make_token(TokenType::Minus)
+          }
+        }
+        '+' => {
+          if self.test('=') {
+            self.make_token(TokenType::PlusEqual)
+          } else if self.test('+') {
+            self.make_token(TokenType::PlusPlus)
+          } else {
+            self.make_token(TokenType::Plus)
+          }
+        }
+        '/' => {
+          if self.test('=') {
+            self.make_token(TokenType::SlashEqual)
+          } else {
+            self.make_token(TokenType::Slash)
+          }
+        }
+        '*' => {
+          if self.test('*') {
+            self.make_token(TokenType::StarStar)
+          } else if self.test('=') {
+            self.make_token(TokenType::StarEqual)
+          } else {
+            self.make_token(TokenType::Star)
+          }
+        }
+        '%' => self.make_token(TokenType::Percent),
+        '&' => self.make_token(TokenType::Ampersand),
+        '|' => self.make_token(TokenType::Pipe),
+        '^' => self.make_token(TokenType::Caret),
+        '?' => self.make_token(TokenType::Question),
         '!' => {
           if self.test('=') {
             self.make_token(TokenType::BangEqual)
@@ -62,6 +168,8 @@ impl<'source> Scanner<'source> {
         '<' => {
           if self.test('=') {
             self.make_token(TokenType::LessEqual)
+          } else if self.test('<') {
+            self.make_token(TokenType::LessLess)
           } else {
             self.make_token(TokenType::Less)
           }
@@ -69,20 +177,26 @@ impl<'source> Scanner<'source> {
         '>' => {
           if self.test('=') {
             self.make_token(TokenType::GreaterEqual)
+          } else if self.test('>') {
+            self.make_token(TokenType::GreaterGreater)
           } else {
             self.make_token(TokenType::Greater)
           }
         }
-        '"' => self.scan_string()?,
+        '"' => self.scan_string(false)?,
+        'r' if self.peek() == Some('"') => {
+          self.advance();
+          self.scan_string(true)?
+        }
         _ if is_alpha(c) => self.scan_keyword_or_identifier(),
-        _ if is_digit(c) => self.scan_number(),
-        _ => return Err("Unexpected character.".to_string()),
+        _ if is_digit(c) => self.scan_number(c)?,
+        _ => return Err(format!("Unexpected character '{}'.", c)),
       },
     };
     Ok(Some(t))
   }
 
-  fn skip_whitespace(&mut self) {
+  fn skip_whitespace(&mut self) -> Result<(), String> {
     while let Some(c) = self.peek() {
       match c {
         ' ' | '\r' | '\t' => {
@@ -92,18 +206,46 @@ impl<'source> Scanner<'source> {
           self.line += 1;
           self.advance();
         }
-        '/' => {
-          if let Some('/') = self.peek_next() {
+        '/' => match self.peek_next() {
+          Some('/') => {
             while matches!(self.peek(), Some(c) if c != '\n') {
               self.advance();
             }
-          } else {
-            return;
           }
-        }
-        _ => return,
+          Some('*') => self.skip_block_comment()?,
+          _ => return Ok(()),
+        },
+        _ => return Ok(()),
       };
     }
+    Ok(())
+  }
+
+  /// Skips a `/* */` comment, along with any comments nested in it.
+  fn skip_block_comment(&mut self) -> Result<(), String> {
+    self.index += 2;
+    let mut depth = 1;
+    while depth > 0 {
+      match (self.peek(), self.peek_next()) {
+        (None, _) => return Err("Unterminated block comment.".to_owned()),
+        (Some('/'), Some('*')) => {
+          self.index += 2;
+          depth += 1;
+        }
+        (Some('*'), Some('/')) => {
+          self.index += 2;
+          depth -= 1;
+        }
+        (Some('\n'), _) => {
+          self.line += 1;
+          self.advance();
+        }
+        _ => {
+          self.advance();
+        }
+      }
+    }
+    Ok(())
   }
 
   fn advance(&mut self) -> Option<char> {
@@ -125,12 +267,18 @@ impl<'source> Scanner<'source> {
   }
 
   fn get(&self, index: usize) -> Option<char> {
-    self.source.clone().nth(index)
+    self.chars.get(index).map(|&(_, c)| c)
+  }
+
+  fn byte_offset(&self, index: usize) -> usize {
+    match self.chars.get(index) {
+      Some(&(offset, _)) => offset,
+      None => self.source.len(),
+    }
   }
 
   fn slice(&self, start: usize, end: usize) -> &str {
-    let str = self.source.as_str();
-    &str[start..end]
+    &self.source[self.byte_offset(start)..self.byte_offset(end)]
   }
 
   fn test(&mut self, expected: char) -> bool {
@@ -154,14 +302,55 @@ impl<'source> Scanner<'source> {
     )
   }
 
-  fn scan_string(&mut self) -> Result<Token, String> {
+  /// Scans up to the closing quote. A backslash keeps the character after it
+  /// from closing the string, except in a raw string, which therefore can't
+  /// contain a quote. Triple-quoted strings close at `"""` instead, so quotes
+  /// need no escaping inside.
+  fn scan_string(&mut self, raw: bool) -> Result<Token, String> {
+    let triple = self.peek() == Some('"') && self.peek_next() == Some('"');
+    if triple {
+      self.index += 2;
+    }
+    self.scan_string_body(raw, triple)
+  }
+
+  /// Scans the rest of a string, or up to a `${` in one that isn't raw. The
+  /// string then resumes at the `}` closing the interpolated expression.
+  fn scan_string_body(
+    &mut self,
+    raw: bool,
+    triple: bool,
+  ) -> Result<Token, String> {
     loop {
       match self.peek() {
+        None if triple => {
+          return Err("Unterminated triple-quoted string.".to_string())
+        }
         None => return Err("Unterminated string.".to_string()),
-        Some('"') => {
+        Some('"') if !triple => {
           self.advance();
           break;
         }
+        Some('"')
+          if self.peek_next() == Some('"')
+            && self.get(self.index + 2) == Some('"') =>
+        {
+          self.index += 3;
+          break;
+        }
+        Some('$') if !raw && self.peek_next() == Some('{') => {
+          self.index += 2;
+          self
+            .interpolations
+            .push(Interpolation { braces: 0, triple });
+          return Ok(self.make_token(TokenType::Interpolation));
+        }
+        Some('\\') if !raw => {
+          self.advance();
+          if self.advance() == Some('\n') {
+            self.line += 1;
+          }
+        }
         Some('\n') => {
           self.line += 1;
           self.advance();
@@ -174,20 +363,63 @@ impl<'source> Scanner<'source> {
     Ok(self.make_token(TokenType::String))
   }
 
-  fn scan_number(&mut self) -> Token {
-    while matches!(self.peek(), Some(c) if is_digit(c)) {
+  /// Scans the rest of a number literal starting with `first`: a decimal
+  /// with an optional fraction and exponent, or an integer in hex after `0x`
+  /// or binary after `0b`.
+  fn scan_number(&mut self, first: char) -> Result<Token, String> {
+    let radix = match (first, self.peek()) {
+      ('0', Some('x' | 'X')) => 16,
+      ('0', Some('b' | 'B')) => 2,
+      _ => 10,
+    };
+    let valid = if radix == 10 {
+      self.scan_digits(10, true);
+      if matches!(self.peek(), Some('.'))
+        && matches!(self.peek_next(), Some(c) if is_digit(c))
+      {
+        self.advance();
+        self.scan_digits(10, false);
+      }
+      let exponent = matches!(self.peek(), Some('e' | 'E'));
+      if exponent {
+        self.advance();
+        if matches!(self.peek(), Some('+' | '-')) {
+          self.advance();
+        }
+      }
+      let valid = !exponent || self.scan_digits(10, false);
+      valid && self.peek() != Some('_')
+    } else {
       self.advance();
+      let valid = self.scan_digits(radix, false);
+      valid && !matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric())
+    };
+    if !valid {
+      // Skip the rest of it, so that scanning resumes after the literal.
+      while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || c == '_')
+      {
+        self.advance();
+      }
+      return Err("Invalid number literal.".to_owned());
     }
-    if matches!(self.peek(), Some('.'))
-      && matches!(self.peek_next(), Some(c) if is_digit(c))
-    {
-      self.advance();
+    Ok(self.make_token(TokenType::Number))
+  }
 
-      while matches!(self.peek(), Some(c) if is_digit(c)) {
-        self.advance();
+  /// Scans digits in `radix`, with single `_`s allowed between them. Returns
+  /// whether there were any, counting one already scanned if `started`.
+  fn scan_digits(&mut self, radix: u32, started: bool) -> bool {
+    let mut any = started;
+    loop {
+      let separator = any
+        && self.peek() == Some('_')
+        && matches!(self.peek_next(), Some(c) if c.is_digit(radix));
+      match self.peek() {
+        Some(c) if c.is_digit(radix) => any = true,
+        _ if separator => {}
+        _ => return any,
       }
+      self.advance();
     }
-    self.make_token(TokenType::Number)
   }
 
   fn scan_keyword_or_identifier(&mut self) -> Token {
@@ -197,28 +429,68 @@ impl<'source> Scanner<'source> {
     self.make_token(self.keyword_or_identifier_type())
   }
 
+  /// Every reserved word, for diagnostics about misspelled ones.
+  pub const KEYWORDS: [&'static str; 27] = [
+    "and", "assert", "break", "case", "catch", "class", "continue", "default",
+    "do", "else", "false", "finally", "for", "fun", "if", "nil", "or", "print",
+    "return", "super", "switch", "this", "throw", "true", "try", "var",
+    "while",
+  ];
+
   fn keyword_or_identifier_type(&self) -> TokenType {
     match self.get(self.start).unwrap() {
-      'a' => self.check_keyword(1, "nd", TokenType::And),
-      'c' => self.check_keyword(1, "lass", TokenType::Class),
+      'a' => match self.get(self.start + 1) {
+        Some('n') => self.check_keyword(2, "d", TokenType::And),
+        Some('s') => self.check_keyword(2, "sert", TokenType::Assert),
+        _ => TokenType::Identifier,
+      },
+      'b' => self.check_keyword(1, "reak", TokenType::Break),
+      'c' => match self.get(self.start + 1) {
+        Some('a') => match self.get(self.start + 2) {
+          Some('s') => self.check_keyword(3, "e", TokenType::Case),
+          Some('t') => self.check_keyword(3, "ch", TokenType::Catch),
+          _ => TokenType::Identifier,
+        },
+        Some('l') => self.check_keyword(2, "ass", TokenType::Class),
+        Some('o') => self.check_keyword(2, "ntinue", TokenType::Continue),
+        _ => TokenType::Identifier,
+      },
+      'd' => match self.get(self.start + 1) {
+        Some('e') => self.check_keyword(2, "fault", TokenType::Default),
+        Some('o') => self.check_keyword(2, "", TokenType::Do),
+        _ => TokenType::Identifier,
+      },
       'e' => self.check_keyword(1, "lse", TokenType::Else),
       'i' => self.check_keyword(1, "f", TokenType::If),
       'n' => self.check_keyword(1, "il", TokenType::Nil),
       'o' => self.check_keyword(1, "r", TokenType::Or),
       'p' => self.check_keyword(1, "rint", TokenType::Print),
       'r' => self.check_keyword(1, "eturn", TokenType::Return),
-      's' => self.check_keyword(1, "uper", TokenType::Super),
+      's' => match self.get(self.start + 1) {
+        Some('u') => self.check_keyword(2, "per", TokenType::Super),
+        Some('w') => self.check_keyword(2, "itch", TokenType::Switch),
+        _ => TokenType::Identifier,
+      },
       'v' => self.check_keyword(1, "ar", TokenType::Var),
       'w' => self.check_keyword(1, "hile", TokenType::While),
       'f' => match self.get(self.start + 1) {
         Some('a') => self.check_keyword(2, "lse", TokenType::False),
+        Some('i') => self.check_keyword(2, "nally", TokenType::Finally),
         Some('o') => self.check_keyword(2, "r", TokenType::For),
         Some('u') => self.check_keyword(2, "n", TokenType::Fun),
         _ => TokenType::Identifier,
       },
       't' => match self.get(self.start + 1) {
-        Some('h') => self.check_keyword(2, "is", TokenType::This),
-        Some('r') => self.check_keyword(2, "ue", TokenType::True),
+        Some('h') => match self.get(self.start + 2) {
+          Some('i') => self.check_keyword(3, "s", TokenType::This),
+          Some('r') => self.check_keyword(3, "ow", TokenType::Throw),
+          _ => TokenType::Identifier,
+        },
+        Some('r') => match self.get(self.start + 2) {
+          Some('u') => self.check_keyword(3, "e", TokenType::True),
+          Some('y') => self.check_keyword(3, "", TokenType::Try),
+          _ => TokenType::Identifier,
+        },
         _ => TokenType::Identifier,
       },
       _ => TokenType::Identifier,
@@ -231,7 +503,8 @@ impl<'source> Scanner<'source> {
     rest: &str,
     token_type: TokenType,
   ) -> TokenType {
-    let len = rest.len();
+    // The indices count chars, not bytes.
+    let len = rest.chars().count();
     if self.index - self.start == start + len
       && self.slice(self.start + start, self.start + start + len) == rest
     {
@@ -241,8 +514,18 @@ impl<'source> Scanner<'source> {
   }
 
   pub fn print(&mut self) {
-    while let Ok(Some(t)) = self.scan_token() {
-      println!("{:?}", t);
+    for token in self.map_while(Result::ok) {
+      println!("{:?}", token);
     }
   }
 }
+
+/// Yields the tokens up to the end of the source. An error is yielded in the
+/// place of the text it's about, and scanning goes on after it.
+impl Iterator for Scanner<'_> {
+  type Item = Result<Token, LoxError>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    self.scan_token().transpose()
+  }
+}
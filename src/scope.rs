@@ -1,5 +1,3 @@
-use std::collections::HashMap;
-
 pub struct Scopes {
   scopes: Vec<Scope>,
   count: u8,
@@ -13,31 +11,54 @@ impl Scopes {
     }
   }
 
+  pub fn with_receiver() -> Self {
+    let mut scope = Scope::new();
+    scope.define("this".to_owned(), 0); // CallFrame slot zero
+    scope.get_mut("this").unwrap().mark_init();
+    Self {
+      scopes: vec![scope],
+      count: 1,
+    }
+  }
+
   pub fn push(&mut self) {
     self.scopes.push(Scope::new());
   }
 
   pub fn pop(&mut self) -> Option<Scope> {
-    self.scopes.pop().map(|scope| {
-      self.count -= scope.len() as u8;
-      scope
-    })
+    self
+      .scopes
+      .pop()
+      .inspect(|scope| self.count -= scope.len() as u8)
   }
 
-  pub fn is_empty(&self) -> bool {
-    self.scopes.is_empty()
+  pub fn depth(&self) -> usize {
+    self.scopes.len()
   }
 
-  pub fn current_has(&mut self, name: &str) -> Option<bool> {
-    self.scopes.last().map(|scope| scope.has(name))
+  /// Locals of the scopes deeper than `depth`, in the order they are popped.
+  pub fn locals_above(&self, depth: usize) -> Vec<Local> {
+    let scopes = self.scopes.iter().skip(depth).rev();
+    scopes.flat_map(|scope| scope.locals_rev()).collect()
   }
 
+  pub fn is_empty(&self) -> bool {
+    self.scopes.is_empty()
+  }
+
+  /// Declares a local in the innermost scope. It may shadow one of an outer
+  /// scope, but not one of the same scope.
   pub fn define_uninit_local(&mut self, name: String) -> Result<(), String> {
     let index = self.count;
     let scope = self
       .scopes
       .last_mut()
       .ok_or("Can't define a local variable without scope.")?;
+    if scope.has(&name) {
+      return Err(
+        "Already a variable with this name in this scope.".to_owned(),
+      );
+    }
     scope.define(name, index);
     self.count = self
       .count
@@ -47,15 +68,27 @@ impl Scopes {
   }
 
   pub fn mark_init_local(&mut self, name: &str) {
-    for scope in self.scopes.iter_mut().rev() {
-      if let Some(local) = scope.get_mut(name) {
-        local.mark_init();
-        return;
-      }
+    if let Some(local) = self.find_local(name) {
+      local.mark_init();
+    }
+  }
+
+  /// Exempts a local from being reported as unused, as parameters are.
+  pub fn mark_used_local(&mut self, name: &str) {
+    if let Some(local) = self.find_local(name) {
+      local.is_used = true;
     }
   }
 
-  pub fn resolve_local(&mut self, name: &str) -> Result<Option<&mut Local>, String> {
+  fn find_local(&mut self, name: &str) -> Option<&mut Local> {
+    let mut scopes = self.scopes.iter_mut().rev();
+    scopes.find_map(|scope| scope.get_mut(name))
+  }
+
+  pub fn resolve_local(
+    &mut self,
+    name: &str,
+  ) -> Result<Option<&mut Local>, String> {
     for scope in self.scopes.iter_mut().rev() {
       if let Some(local) = scope.get_mut(name) {
         if !local.is_init {
@@ -63,6 +96,7 @@ impl Scopes {
             "Can't read local variable in its own initializer.".to_owned(),
           );
         }
+        local.is_used = true;
         return Ok(Some(local));
       }
     }
@@ -74,6 +108,7 @@ impl Scopes {
 pub struct Local {
   pub is_init: bool,
   pub is_captured: bool,
+  pub is_used: bool,
   pub index: u8,
 }
 
@@ -82,6 +117,7 @@ impl Local {
     Self {
       is_init: false,
       is_captured: false,
+      is_used: false,
       index,
     }
   }
@@ -97,35 +133,55 @@ pub struct Upvalue {
   pub index: u8,
 }
 
+/// The locals of a block in the order they were declared, which is the
+/// order of their stack slots.
 pub struct Scope {
-  locals: HashMap<String, Local>,
+  locals: Vec<(String, Local)>,
 }
 
 impl Scope {
   pub fn new() -> Self {
-    Self {
-      locals: HashMap::new(),
-    }
+    Self { locals: Vec::new() }
   }
 
   pub fn has(&self, name: &str) -> bool {
-    self.locals.contains_key(name)
+    self.locals.iter().any(|(local, _)| local == name)
   }
 
   pub fn define(&mut self, name: String, index: u8) {
     let local = Local::new_uninit(index);
-    self.locals.insert(name, local);
-  }
-
-  pub fn get(&self, name: &str) -> Option<&Local> {
-    self.locals.get(name)
+    self.locals.push((name, local));
   }
 
+  /// The most recently declared local called `name`.
   pub fn get_mut(&mut self, name: &str) -> Option<&mut Local> {
-    self.locals.get_mut(name)
+    let mut locals = self.locals.iter_mut().rev();
+    locals
+      .find(|(local, _)| local == name)
+      .map(|(_, local)| local)
   }
 
   pub fn len(&self) -> usize {
     self.locals.len()
   }
+
+  /// Fails on the first local that was never used. Names starting with `_`
+  /// are exempt, as are `super` and the hidden locals the compiler declares.
+  pub fn check_used(&self) -> Result<(), String> {
+    let unused = self.locals.iter().find(|(name, local)| {
+      !local.is_used
+        && !name.starts_with('_')
+        && !name.contains(' ')
+        && name != "super"
+    });
+    match unused {
+      Some((name, _)) => Err(format!("Unused local variable '{}'.", name)),
+      None => Ok(()),
+    }
+  }
+
+  /// Locals from the most recently declared one, the order they are popped.
+  pub fn locals_rev(&self) -> Vec<Local> {
+    self.locals.iter().rev().map(|(_, local)| *local).collect()
+  }
 }
@@ -0,0 +1,205 @@
+use crate::{
+  interner::Interner,
+  value::{Closure, Function, FunctionKind},
+  Chunk, Value,
+};
+
+const MAGIC: &[u8; 4] = b"LOXC";
+const VERSION: u8 = 3;
+
+const NIL: u8 = 0;
+const FALSE: u8 = 1;
+const TRUE: u8 = 2;
+const NUMBER: u8 = 3;
+const INT: u8 = 4;
+const STRING: u8 = 5;
+const CLOSURE: u8 = 6;
+
+const FUNCTION: u8 = 0;
+const METHOD: u8 = 1;
+const SCRIPT: u8 = 2;
+
+impl Chunk {
+  /// Encodes the chunk, with the functions in its constants, so that it can be
+  /// stored and run later. Numbers are written little-endian whatever the
+  /// machine, so the bytes can be read back anywhere. Fails on the constants
+  /// the compiler never makes, such as lists built by hand.
+  pub fn serialize(&self) -> Result<Vec<u8>, String> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.push(VERSION);
+    write_chunk(&mut bytes, self)?;
+    Ok(bytes)
+  }
+
+  /// Decodes a chunk written by `serialize`, checking that it is safe to run.
+  pub fn deserialize(bytes: &[u8]) -> Result<Chunk, String> {
+    let mut reader = Reader {
+      bytes,
+      strings: Interner::new(),
+    };
+    if reader.take(MAGIC.len())? != MAGIC {
+      return Err("Not a compiled Lox chunk.".to_owned());
+    }
+    let version = reader.byte()?;
+    if version != VERSION {
+      return Err(format!("Unsupported chunk version {}.", version));
+    }
+    let chunk = reader.chunk()?;
+    if !reader.bytes.is_empty() {
+      return Err("Unexpected bytes after the chunk.".to_owned());
+    }
+    chunk.validate()?;
+    Ok(chunk)
+  }
+}
+
+fn write_len(bytes: &mut Vec<u8>, len: usize) {
+  bytes.extend((len as u32).to_le_bytes());
+}
+
+fn write_str(bytes: &mut Vec<u8>, s: &str) {
+  write_len(bytes, s.len());
+  bytes.extend(s.as_bytes());
+}
+
+fn write_chunk(bytes: &mut Vec<u8>, chunk: &Chunk) -> Result<(), String> {
+  write_len(bytes, chunk.codes.len());
+  bytes.extend(&chunk.codes);
+  for &line in &chunk.lines {
+    write_len(bytes, line);
+  }
+  write_len(bytes, chunk.constants.len());
+  for constant in &chunk.constants {
+    write_constant(bytes, constant)?;
+  }
+  Ok(())
+}
+
+fn write_constant(bytes: &mut Vec<u8>, constant: &Value) -> Result<(), String> {
+  match constant {
+    Value::Nil => bytes.push(NIL),
+    Value::Bool(false) => bytes.push(FALSE),
+    Value::Bool(true) => bytes.push(TRUE),
+    Value::Number(n) => {
+      bytes.push(NUMBER);
+      bytes.extend(n.to_le_bytes());
+    }
+    Value::Int(n) => {
+      bytes.push(INT);
+      bytes.extend(n.to_le_bytes());
+    }
+    Value::String(s) => {
+      bytes.push(STRING);
+      write_str(bytes, s);
+    }
+    Value::Closure(closure) => {
+      bytes.push(CLOSURE);
+      bytes.push(closure.upvalues_len);
+      let function = &closure.function;
+      match &function.kind {
+        FunctionKind::Function { name } => {
+          bytes.push(FUNCTION);
+          write_str(bytes, name);
+        }
+        FunctionKind::Method { name } => {
+          bytes.push(METHOD);
+          write_str(bytes, name);
+        }
+        FunctionKind::Script => bytes.push(SCRIPT),
+      }
+      bytes.push(function.arity);
+      bytes.push(function.optional);
+      bytes.push(function.variadic as u8);
+      write_chunk(bytes, &function.chunk)?;
+    }
+    _ => return Err(format!("Can't serialize the constant {}.", constant)),
+  }
+  Ok(())
+}
+
+struct Reader<'a> {
+  bytes: &'a [u8],
+  strings: Interner,
+}
+
+impl<'a> Reader<'a> {
+  fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+    if self.bytes.len() < len {
+      return Err("Unexpected end of the chunk.".to_owned());
+    }
+    let (taken, rest) = self.bytes.split_at(len);
+    self.bytes = rest;
+    Ok(taken)
+  }
+
+  fn byte(&mut self) -> Result<u8, String> {
+    Ok(self.take(1)?[0])
+  }
+
+  fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+    Ok(self.take(N)?.try_into().unwrap())
+  }
+
+  fn len(&mut self) -> Result<usize, String> {
+    Ok(u32::from_le_bytes(self.array()?) as usize)
+  }
+
+  fn str(&mut self) -> Result<&'a str, String> {
+    let len = self.len()?;
+    std::str::from_utf8(self.take(len)?)
+      .map_err(|_| "Invalid string in the chunk.".to_owned())
+  }
+
+  fn chunk(&mut self) -> Result<Chunk, String> {
+    let mut chunk = Chunk::new();
+    let len = self.len()?;
+    chunk.codes = self.take(len)?.to_vec();
+    chunk.lines = (0..len).map(|_| self.len()).collect::<Result<_, _>>()?;
+    let len = self.len()?;
+    chunk.constants = (0..len)
+      .map(|_| self.constant())
+      .collect::<Result<_, _>>()?;
+    Ok(chunk)
+  }
+
+  fn constant(&mut self) -> Result<Value, String> {
+    let value = match self.byte()? {
+      NIL => Value::nil(),
+      FALSE => Value::bool(false),
+      TRUE => Value::bool(true),
+      NUMBER => Value::number(f64::from_le_bytes(self.array()?)),
+      INT => Value::int(i64::from_le_bytes(self.array()?)),
+      STRING => {
+        let s = self.str()?;
+        Value::shared_string(self.strings.intern(s))
+      }
+      CLOSURE => {
+        let upvalues_len = self.byte()?;
+        let kind = match self.byte()? {
+          FUNCTION => FunctionKind::Function {
+            name: self.str()?.to_owned(),
+          },
+          METHOD => FunctionKind::Method {
+            name: self.str()?.to_owned(),
+          },
+          SCRIPT => FunctionKind::Script,
+          tag => return Err(format!("Unknown function kind {}.", tag)),
+        };
+        let arity = self.byte()?;
+        let optional = self.byte()?;
+        let variadic = self.byte()? != 0;
+        let chunk = self.chunk()?;
+        let function = Function {
+          kind,
+          arity,
+          optional,
+          variadic,
+          chunk,
+        };
+        Value::closure(Closure::new(function, upvalues_len))
+      }
+      tag => return Err(format!("Unknown constant tag {}.", tag)),
+    };
+    Ok(value)
+  }
+}
@@ -0,0 +1,43 @@
+/// Picks the candidate closest to `name`, if any is close enough to be a
+/// likely typo of it.
+pub fn did_you_mean<'a>(
+  name: &str,
+  candidates: impl IntoIterator<Item = &'a str>,
+) -> Option<&'a str> {
+  let len = name.chars().count();
+  // Anything is a single edit away from a one-letter name.
+  let max_distance = (len / 3).max(1).min(len.saturating_sub(1));
+  candidates
+    .into_iter()
+    .filter(|candidate| *candidate != name)
+    .map(|candidate| (distance(name, candidate), candidate))
+    .filter(|(distance, _)| *distance <= max_distance)
+    .min()
+    .map(|(_, candidate)| candidate)
+}
+
+/// Edit distance counting insertions, deletions, substitutions and swaps of
+/// adjacent characters, so `retrun` is one edit away from `return`.
+fn distance(a: &str, b: &str) -> usize {
+  let a: Vec<char> = a.chars().collect();
+  let b: Vec<char> = b.chars().collect();
+  let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+  for (i, row) in d.iter_mut().enumerate() {
+    row[0] = i;
+  }
+  for (j, cell) in d[0].iter_mut().enumerate() {
+    *cell = j;
+  }
+  for i in 1..=a.len() {
+    for j in 1..=b.len() {
+      let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+      d[i][j] = (d[i - 1][j] + 1)
+        .min(d[i][j - 1] + 1)
+        .min(d[i - 1][j - 1] + cost);
+      if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+        d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+      }
+    }
+  }
+  d[a.len()][b.len()]
+}
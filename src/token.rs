@@ -1,12 +1,16 @@
 use crate::parser::{ParseFn, Parser};
 
-#[derive(Debug, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum TokenType {
   // Single-character tokens.
   LeftParen,
   RightParen,
   LeftBrace,
   RightBrace,
+  LeftBracket,
+  RightBracket,
+  Colon,
+  Question,
   Comma,
   Dot,
   Minus,
@@ -14,7 +18,11 @@ pub enum TokenType {
   Semicolon,
   Slash,
   Star,
-  // One or two character tokens.
+  Percent,
+  Ampersand,
+  Pipe,
+  Caret,
+  // One, two or three character tokens.
   Bang,
   BangEqual,
   Equal,
@@ -23,15 +31,36 @@ pub enum TokenType {
   GreaterEqual,
   Less,
   LessEqual,
+  LessLess,
+  GreaterGreater,
+  StarStar,
+  PlusEqual,
+  MinusEqual,
+  StarEqual,
+  SlashEqual,
+  PlusPlus,
+  MinusMinus,
+  Ellipsis,
   // Literals.
   Identifier,
   String,
+  /// A string fragment ending in `${`, followed by the tokens of the
+  /// interpolated expression.
+  Interpolation,
   Number,
   // Keywords.
   And,
+  Assert,
+  Break,
+  Case,
+  Catch,
   Class,
+  Continue,
+  Default,
+  Do,
   Else,
   False,
+  Finally,
   For,
   Fun,
   If,
@@ -40,8 +69,11 @@ pub enum TokenType {
   Print,
   Return,
   Super,
+  Switch,
   This,
+  Throw,
   True,
+  Try,
   Var,
   While,
 }
@@ -49,15 +81,21 @@ pub enum TokenType {
 #[derive(PartialEq, PartialOrd)]
 pub enum Precedence {
   None,
-  Assignment, // =
-  Or,         // or
-  And,        // and
-  Equality,   // == !=
-  Comparison, // < > <= >=
-  Term,       // + -
-  Factor,     // * /
-  Unary,      // ! -
-  Call,       // . ()
+  Assignment,  // =
+  Conditional, // ?:
+  Or,          // or
+  And,         // and
+  Equality,    // == !=
+  Comparison,  // < > <= >=
+  BitOr,       // |
+  BitXor,      // ^
+  BitAnd,      // &
+  Shift,       // << >>
+  Term,        // + -
+  Factor,      // * / %
+  Unary,       // ! -
+  Power,       // **
+  Call,        // . ()
   Primary,
 }
 
@@ -65,14 +103,20 @@ impl Precedence {
   pub fn up(self) -> Self {
     match self {
       Self::None => Self::Assignment,
-      Self::Assignment => Self::Or,
+      Self::Assignment => Self::Conditional,
+      Self::Conditional => Self::Or,
       Self::Or => Self::And,
       Self::And => Self::Equality,
       Self::Equality => Self::Comparison,
-      Self::Comparison => Self::Term,
+      Self::Comparison => Self::BitOr,
+      Self::BitOr => Self::BitXor,
+      Self::BitXor => Self::BitAnd,
+      Self::BitAnd => Self::Shift,
+      Self::Shift => Self::Term,
       Self::Term => Self::Factor,
       Self::Factor => Self::Unary,
-      Self::Unary => Self::Call,
+      Self::Unary => Self::Power,
+      Self::Power => Self::Call,
       Self::Call => Self::Primary,
       Self::Primary => Self::Primary,
     }
@@ -106,10 +150,18 @@ impl TokenType {
         Rule::new(Precedence::Call, Some(Parser::grouping), Some(Parser::call))
       }
       Self::RightParen => Rule::new(Precedence::None, None, None),
-      Self::LeftBrace => Rule::new(Precedence::None, None, None),
+      Self::LeftBrace => Rule::new(Precedence::None, Some(Parser::map), None),
       Self::RightBrace => Rule::new(Precedence::None, None, None),
+      Self::LeftBracket => {
+        Rule::new(Precedence::Call, Some(Parser::list), Some(Parser::index))
+      }
+      Self::RightBracket => Rule::new(Precedence::None, None, None),
+      Self::Colon => Rule::new(Precedence::None, None, None),
+      Self::Question => {
+        Rule::new(Precedence::Conditional, None, Some(Parser::conditional))
+      }
       Self::Comma => Rule::new(Precedence::None, None, None),
-      Self::Dot => Rule::new(Precedence::None, None, None),
+      Self::Dot => Rule::new(Precedence::Call, None, Some(Parser::dot)),
       Self::Minus => {
         Rule::new(Precedence::Term, Some(Parser::unary), Some(Parser::binary))
       }
@@ -117,6 +169,14 @@ impl TokenType {
       Self::Semicolon => Rule::new(Precedence::None, None, None),
       Self::Slash => Rule::new(Precedence::Factor, None, Some(Parser::binary)),
       Self::Star => Rule::new(Precedence::Factor, None, Some(Parser::binary)),
+      Self::Percent => {
+        Rule::new(Precedence::Factor, None, Some(Parser::binary))
+      }
+      Self::Ampersand => {
+        Rule::new(Precedence::BitAnd, None, Some(Parser::binary))
+      }
+      Self::Pipe => Rule::new(Precedence::BitOr, None, Some(Parser::binary)),
+      Self::Caret => Rule::new(Precedence::BitXor, None, Some(Parser::binary)),
       Self::Bang => Rule::new(Precedence::None, Some(Parser::unary), None),
       Self::BangEqual => {
         Rule::new(Precedence::Equality, None, Some(Parser::binary))
@@ -137,34 +197,74 @@ impl TokenType {
       Self::LessEqual => {
         Rule::new(Precedence::Comparison, None, Some(Parser::binary))
       }
+      Self::LessLess => {
+        Rule::new(Precedence::Shift, None, Some(Parser::binary))
+      }
+      Self::GreaterGreater => {
+        Rule::new(Precedence::Shift, None, Some(Parser::binary))
+      }
+      Self::StarStar => {
+        Rule::new(Precedence::Power, None, Some(Parser::binary))
+      }
+      Self::PlusEqual => Rule::new(Precedence::None, None, None),
+      Self::MinusEqual => Rule::new(Precedence::None, None, None),
+      Self::StarEqual => Rule::new(Precedence::None, None, None),
+      Self::SlashEqual => Rule::new(Precedence::None, None, None),
+      Self::PlusPlus => Rule::new(
+        Precedence::Call,
+        Some(Parser::increment),
+        Some(Parser::postfix_increment),
+      ),
+      Self::MinusMinus => Rule::new(
+        Precedence::Call,
+        Some(Parser::increment),
+        Some(Parser::postfix_increment),
+      ),
+      Self::Ellipsis => Rule::new(Precedence::None, None, None),
       Self::Identifier => {
         Rule::new(Precedence::None, Some(Parser::variable), None)
       }
       Self::String => Rule::new(Precedence::None, Some(Parser::string), None),
+      Self::Interpolation => {
+        Rule::new(Precedence::None, Some(Parser::interpolation), None)
+      }
       Self::Number => Rule::new(Precedence::None, Some(Parser::number), None),
       Self::And => Rule::new(Precedence::And, None, Some(Parser::and)),
+      Self::Assert => Rule::new(Precedence::None, None, None),
+      Self::Break => Rule::new(Precedence::None, None, None),
+      Self::Case => Rule::new(Precedence::None, None, None),
+      Self::Catch => Rule::new(Precedence::None, None, None),
       Self::Class => Rule::new(Precedence::None, None, None),
+      Self::Continue => Rule::new(Precedence::None, None, None),
+      Self::Default => Rule::new(Precedence::None, None, None),
+      Self::Do => Rule::new(Precedence::None, None, None),
       Self::Else => Rule::new(Precedence::None, None, None),
       Self::False => Rule::new(Precedence::None, Some(Parser::literal), None),
+      Self::Finally => Rule::new(Precedence::None, None, None),
       Self::For => Rule::new(Precedence::None, None, None),
-      Self::Fun => Rule::new(Precedence::None, None, None),
+      Self::Fun => Rule::new(Precedence::None, Some(Parser::lambda), None),
       Self::If => Rule::new(Precedence::None, None, None),
       Self::Nil => Rule::new(Precedence::None, Some(Parser::literal), None),
       Self::Or => Rule::new(Precedence::Or, None, Some(Parser::or)),
       Self::Print => Rule::new(Precedence::None, None, None),
       Self::Return => Rule::new(Precedence::None, None, None),
-      Self::Super => Rule::new(Precedence::None, None, None),
-      Self::This => Rule::new(Precedence::None, None, None),
+      Self::Super => Rule::new(Precedence::None, Some(Parser::super_), None),
+      Self::Switch => Rule::new(Precedence::None, None, None),
+      Self::This => Rule::new(Precedence::None, Some(Parser::this), None),
+      Self::Throw => Rule::new(Precedence::None, None, None),
       Self::True => Rule::new(Precedence::None, Some(Parser::literal), None),
+      Self::Try => Rule::new(Precedence::None, None, None),
       Self::Var => Rule::new(Precedence::None, None, None),
       Self::While => Rule::new(Precedence::None, None, None),
     }
   }
 }
 
-#[derive(Debug, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Token {
   pub token_type: TokenType,
+  /// Where the token starts in the source and how long it is, both counted in
+  /// chars. Slice `source` for its text, which is indexed by bytes.
   pub start: usize,
   pub length: usize,
   pub line: usize,
@@ -1,10 +1,17 @@
-use std::{cell::RefCell, fmt, rc::Rc};
+use std::{
+  cell::{Ref, RefCell},
+  cmp::Ordering,
+  collections::HashMap,
+  fmt,
+  rc::Rc,
+};
 
 use crate::{vm::CallFrame, Chunk, VM};
 
 #[derive(Clone)]
 pub enum FunctionKind {
   Function { name: String },
+  Method { name: String },
   Script,
 }
 
@@ -12,6 +19,12 @@ pub enum FunctionKind {
 pub struct Function {
   pub kind: FunctionKind,
   pub arity: u8,
+  /// How many of the last parameters have default values, so that callers
+  /// may leave them out.
+  pub optional: u8,
+  /// Whether the last parameter is a `...rest` one, collecting any further
+  /// arguments in a list.
+  pub variadic: bool,
   pub chunk: Chunk,
 }
 
@@ -22,42 +35,70 @@ impl Function {
         name: name.to_owned(),
       },
       arity: 0,
+      optional: 0,
+      variadic: false,
       chunk: Chunk::new(),
     }
   }
 
+  pub fn new_method(name: &str) -> Self {
+    Self {
+      kind: FunctionKind::Method {
+        name: name.to_owned(),
+      },
+      arity: 0,
+      optional: 0,
+      variadic: false,
+      chunk: Chunk::new(),
+    }
+  }
+
+  pub fn name(&self) -> &str {
+    match &self.kind {
+      FunctionKind::Function { name } | FunctionKind::Method { name } => name,
+      FunctionKind::Script => "script",
+    }
+  }
+
   pub fn new_script() -> Self {
     Self {
       kind: FunctionKind::Script,
       arity: 0,
+      optional: 0,
+      variadic: false,
       chunk: Chunk::new(),
     }
   }
 
   pub fn call(
-    self,
+    self: Rc<Self>,
     vm: &mut VM,
     arg_count: u8,
-    frame: CallFrame,
-  ) -> Result<CallFrame, String> {
-    let closure = Closure::new(self, 0);
+    frame: &mut CallFrame,
+  ) -> Result<(), String> {
+    let closure = Closure {
+      function: self,
+      upvalues_len: 0,
+      upvalues: Vec::new(),
+    };
     closure.call(vm, arg_count, frame)
   }
 }
 
 impl fmt::Debug for Function {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-    if let FunctionKind::Function { name } = &self.kind {
-      write!(f, "<fun {}>", name)
-    } else {
-      write!(f, "<script>")
+    match &self.kind {
+      FunctionKind::Function { name } | FunctionKind::Method { name } => {
+        write!(f, "<fun {}>", name)
+      }
+      FunctionKind::Script => write!(f, "<script>"),
     }
   }
 }
 
 #[derive(Clone)]
 pub struct Closure {
-  pub function: Function,
+  pub function: Rc<Function>,
   pub upvalues_len: u8,
   pub upvalues: Vec<Upvalue>,
 }
@@ -65,56 +106,200 @@ pub struct Closure {
 impl Closure {
   pub fn new(function: Function, upvalues_len: u8) -> Self {
     Self {
-      function,
+      function: Rc::new(function),
       upvalues_len,
       upvalues: Vec::new(),
     }
   }
 
+  /// Whether both are the same closure: one function declaration capturing
+  /// the same variables.
+  pub fn same(a: &Self, b: &Self) -> bool {
+    Rc::ptr_eq(&a.function, &b.function)
+      && a.upvalues.len() == b.upvalues.len()
+      && a
+        .upvalues
+        .iter()
+        .zip(&b.upvalues)
+        .all(|(a, b)| Rc::ptr_eq(a, b))
+  }
+
   pub fn call(
     self,
     vm: &mut VM,
-    arg_count: u8,
-    frame: CallFrame,
-  ) -> Result<CallFrame, String> {
-    if arg_count != self.function.arity {
-      return Err(format!(
-        "Expected {} arguments but got {}.",
-        self.function.arity, arg_count
-      ));
+    mut arg_count: u8,
+    frame: &mut CallFrame,
+  ) -> Result<(), String> {
+    let function = &self.function;
+    let fixed = function.arity - function.variadic as u8;
+    let required = fixed - function.optional;
+    if function.variadic {
+      if arg_count < required {
+        return Err(format!(
+          "Expected at least {} arguments but got {}.",
+          required, arg_count
+        ));
+      }
+      if arg_count > fixed {
+        let rest = vm.stack.len() - (arg_count - fixed) as usize;
+        let rest = vm.stack.split_off(rest);
+        vm.stack.push(Value::list(rest));
+        arg_count = fixed + 1;
+      }
+    } else if arg_count < required || arg_count > function.arity {
+      return Err(match function.optional {
+        0 => format!(
+          "Expected {} arguments but got {}.",
+          function.arity, arg_count
+        ),
+        _ => format!(
+          "Expected {} to {} arguments but got {}.",
+          required, function.arity, arg_count
+        ),
+      });
     }
-    if vm.frames.len() >= u8::MAX.into() {
-      return Err("stack overflow.".to_owned());
+    if vm.frames.len() + 1 >= vm.max_frames {
+      return Err(vm.stack_overflow(&self, frame));
     }
 
-    vm.frames.push(frame);
-    let f_frame = CallFrame::new(self, vm.stack.len() as u8 - arg_count - 1);
-    Ok(f_frame)
+    let start = vm.stack.len() - arg_count as usize - 1;
+    let caller = std::mem::replace(frame, CallFrame::new(self, start));
+    vm.frames.push(caller);
+    Ok(())
   }
 }
 
+/// Shows the arity and how many variables are captured, as in
+/// `<fun sum/3 up0>`.
 impl fmt::Debug for Closure {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-    write!(f, "{:?}", self.function)
+    let function = &self.function;
+    match &function.kind {
+      FunctionKind::Function { name } | FunctionKind::Method { name } => {
+        let (arity, upvalues) = (function.arity, self.upvalues_len);
+        write!(f, "<fun {}/{} up{}>", name, arity, upvalues)
+      }
+      FunctionKind::Script => write!(f, "<script>"),
+    }
   }
 }
 
 #[derive(Debug, Clone)]
-pub struct Upvalue {
-  pub location: *mut Value,
+pub enum UpvalueState {
+  Open(usize),
+  Closed(Value),
+}
+
+pub type Upvalue = Rc<RefCell<UpvalueState>>;
+
+pub struct Class {
+  pub name: String,
+  pub methods: HashMap<String, Closure>,
+}
+
+impl Class {
+  pub fn new(name: &str) -> Self {
+    Self {
+      name: name.to_owned(),
+      methods: HashMap::new(),
+    }
+  }
+}
+
+impl fmt::Debug for Class {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "<class {}>", self.name)
+  }
+}
+
+#[derive(Clone)]
+pub struct Instance {
+  pub class: Rc<RefCell<Class>>,
+  pub fields: Rc<RefCell<HashMap<String, Value>>>,
+}
+
+impl Instance {
+  pub fn new(class: Rc<RefCell<Class>>) -> Self {
+    Self {
+      class,
+      fields: Rc::new(RefCell::new(HashMap::new())),
+    }
+  }
+}
+
+impl fmt::Debug for Instance {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "<{} instance>", self.class.borrow().name)
+  }
+}
+
+#[derive(Clone)]
+pub struct BoundMethod {
+  pub receiver: Value,
+  pub method: Closure,
 }
 
-impl Upvalue {
-  pub fn new(location: &mut Value) -> Self {
-    Self { location }
+impl BoundMethod {
+  pub fn new(receiver: Value, method: Closure) -> Self {
+    Self { receiver, method }
   }
 
-  pub fn get(&self) -> Value {
-    unsafe { self.location.as_ref() }.unwrap().clone()
+  pub fn call(
+    self,
+    vm: &mut VM,
+    arg_count: u8,
+    frame: &mut CallFrame,
+  ) -> Result<(), String> {
+    let len = vm.stack.len();
+    vm.stack[len - 1 - arg_count as usize] = self.receiver;
+    self.method.call(vm, arg_count, frame)
   }
+}
 
-  pub fn set(&mut self, location: &mut Value) {
-    self.location = location;
+impl fmt::Debug for BoundMethod {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{:?}", self.method)
+  }
+}
+
+pub type NativeFn = fn(&mut VM, &[Value]) -> Result<Value, String>;
+
+#[derive(Clone)]
+pub struct Native {
+  pub name: String,
+  pub arity: u8,
+  pub function: NativeFn,
+}
+
+impl Native {
+  pub fn new(name: &str, arity: u8, function: NativeFn) -> Self {
+    Self {
+      name: name.to_owned(),
+      arity,
+      function,
+    }
+  }
+
+  pub fn call(&self, vm: &mut VM, arg_count: u8) -> Result<(), String> {
+    if arg_count != self.arity {
+      return Err(format!(
+        "Expected {} arguments but got {}.",
+        self.arity, arg_count
+      ));
+    }
+
+    let start = vm.stack.len() - arg_count as usize;
+    let args = vm.stack.split_off(start);
+    let result = (self.function)(vm, &args)?;
+    vm.stack.pop();
+    vm.stack.push(result);
+    Ok(())
+  }
+}
+
+impl fmt::Debug for Native {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "<native {}>", self.name)
   }
 }
 
@@ -123,9 +308,95 @@ pub enum Value {
   Bool(bool),
   Nil,
   Number(f64),
-  String(String),
-  Function(Function),
+  Int(i64),
+  String(Rc<str>),
+  Function(Rc<Function>),
   Closure(Closure),
+  Class(Rc<RefCell<Class>>),
+  Instance(Instance),
+  BoundMethod(Box<BoundMethod>),
+  Native(Rc<Native>),
+  List(Rc<RefCell<Vec<Value>>>),
+  Map(Rc<RefCell<HashMap<ValueKey, Value>>>),
+}
+
+/// A value usable as a map key: a string, number, bool or nil. Numbers equal
+/// to an integer are stored as that integer, so `m[1]` and `m[1.0]` agree;
+/// other numbers are compared by their bit pattern.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum ValueKey {
+  Nil,
+  Bool(bool),
+  Int(i64),
+  Number(u64),
+  String(Rc<str>),
+}
+
+impl TryFrom<&Value> for ValueKey {
+  type Error = String;
+
+  fn try_from(value: &Value) -> Result<Self, Self::Error> {
+    match *value {
+      Value::Nil => Ok(Self::Nil),
+      Value::Bool(v) => Ok(Self::Bool(v)),
+      Value::Int(v) => Ok(Self::Int(v)),
+      Value::Number(v) if v.fract() == 0.0 && v.abs() < i64::MAX as f64 => {
+        Ok(Self::Int(v as i64))
+      }
+      Value::Number(v) => Ok(Self::Number(v.to_bits())),
+      Value::String(ref v) => Ok(Self::String(Rc::clone(v))),
+      _ => Err("Unhashable key.".to_owned()),
+    }
+  }
+}
+
+impl ValueKey {
+  pub fn value(&self) -> Value {
+    match self {
+      Self::Nil => Value::nil(),
+      Self::Bool(v) => Value::bool(*v),
+      Self::Int(v) => Value::int(*v),
+      Self::Number(v) => Value::number(f64::from_bits(*v)),
+      Self::String(v) => Value::String(v.clone()),
+    }
+  }
+}
+
+impl PartialOrd for ValueKey {
+  fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+/// Orders keys of different kinds as nil, bools, numbers, then strings.
+impl Ord for ValueKey {
+  fn cmp(&self, other: &Self) -> Ordering {
+    let rank = |key: &Self| match key {
+      Self::Nil => 0,
+      Self::Bool(_) => 1,
+      Self::Int(_) | Self::Number(_) => 2,
+      Self::String(_) => 3,
+    };
+    let number = |key: &Self| match key {
+      Self::Int(v) => *v as f64,
+      Self::Number(v) => f64::from_bits(*v),
+      _ => 0.0,
+    };
+    rank(self)
+      .cmp(&rank(other))
+      .then_with(|| match (self, other) {
+        (Self::Bool(a), Self::Bool(b)) => a.cmp(b),
+        (Self::Int(a), Self::Int(b)) => a.cmp(b),
+        (Self::String(a), Self::String(b)) => a.cmp(b),
+        (a, b) => number(a).total_cmp(&number(b)),
+      })
+  }
+}
+
+impl fmt::Debug for ValueKey {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{:?}", self.value())
+  }
 }
 
 impl Value {
@@ -141,53 +412,177 @@ impl Value {
     Self::Number(v)
   }
 
+  pub fn int(v: i64) -> Self {
+    Self::Int(v)
+  }
+
   pub fn string(v: &str) -> Self {
-    Self::String(v.to_owned())
+    Self::String(Rc::from(v))
+  }
+
+  /// A string sharing storage with `v`, as handed out by an `Interner`.
+  pub fn shared_string(v: Rc<str>) -> Self {
+    Self::String(v)
   }
 
   pub fn function(v: Function) -> Self {
-    Self::Function(v)
+    Self::Function(Rc::new(v))
   }
 
   pub fn closure(v: Closure) -> Self {
     Self::Closure(v)
   }
 
-  pub fn as_bool(self) -> Option<bool> {
+  pub fn class(v: Class) -> Self {
+    Self::Class(Rc::new(RefCell::new(v)))
+  }
+
+  pub fn instance(v: Instance) -> Self {
+    Self::Instance(v)
+  }
+
+  pub fn bound_method(v: BoundMethod) -> Self {
+    Self::BoundMethod(Box::new(v))
+  }
+
+  pub fn native(v: Native) -> Self {
+    Self::Native(Rc::new(v))
+  }
+
+  pub fn list(v: Vec<Value>) -> Self {
+    Self::List(Rc::new(RefCell::new(v)))
+  }
+
+  pub fn map(v: HashMap<ValueKey, Value>) -> Self {
+    Self::Map(Rc::new(RefCell::new(v)))
+  }
+
+  pub fn as_bool(&self) -> Option<bool> {
+    match self {
+      Self::Bool(v) => Some(*v),
+      _ => None,
+    }
+  }
+
+  /// The value as a float, converting integers.
+  pub fn as_number(&self) -> Option<f64> {
+    match self {
+      Self::Number(v) => Some(*v),
+      Self::Int(v) => Some(*v as f64),
+      _ => None,
+    }
+  }
+
+  /// Applies an arithmetic operator, staying integral when both operands are
+  /// integers and the result fits, and working on floats otherwise.
+  pub fn arithmetic(
+    a: &Self,
+    b: &Self,
+    int: fn(i64, i64) -> Option<i64>,
+    float: fn(f64, f64) -> f64,
+  ) -> Result<Self, String> {
+    if let (Self::Int(a), Self::Int(b)) = (a, b) {
+      if let Some(v) = int(*a, *b) {
+        return Ok(Self::Int(v));
+      }
+    }
+    let b = b.expect_number()?;
+    let a = a.expect_number()?;
+    Ok(Self::Number(float(a, b)))
+  }
+
+  pub fn as_string(&self) -> Option<String> {
+    match self {
+      Self::String(v) => Some(v.to_string()),
+      _ => None,
+    }
+  }
+
+  /// Like `as_string`, sharing the string's storage instead of copying it.
+  pub fn as_shared_string(&self) -> Option<Rc<str>> {
+    match self {
+      Self::String(v) => Some(v.clone()),
+      _ => None,
+    }
+  }
+
+  /// Like `as_number`, with the runtime error for any other operand.
+  pub fn expect_number(&self) -> Result<f64, String> {
+    self
+      .as_number()
+      .ok_or_else(|| "Operand must be a number.".to_owned())
+  }
+
+  /// The operands of a bitwise operator, which must both be integers.
+  pub fn expect_ints(a: &Self, b: &Self) -> Result<(i64, i64), String> {
+    match (a, b) {
+      (Self::Int(a), Self::Int(b)) => Ok((*a, *b)),
+      _ => Err("Operands must be integers.".to_owned()),
+    }
+  }
+
+  /// Like `as_string`, with the runtime error for any other operand.
+  pub fn expect_string(&self) -> Result<String, String> {
+    self
+      .as_string()
+      .ok_or_else(|| "Operand must be a string.".to_owned())
+  }
+
+  pub fn as_function(&self) -> Option<Rc<Function>> {
     match self {
-      Self::Bool(v) => Some(v),
+      Self::Function(v) => Some(Rc::clone(v)),
       _ => None,
     }
   }
 
-  pub fn as_number(self) -> Option<f64> {
+  pub fn as_closure(&self) -> Option<Closure> {
     match self {
-      Self::Number(v) => Some(v),
+      Self::Closure(v) => Some(v.clone()),
       _ => None,
     }
   }
 
-  pub fn as_string(self) -> Option<String> {
+  pub fn as_class(&self) -> Option<Rc<RefCell<Class>>> {
     match self {
-      Self::String(v) => Some(v),
+      Self::Class(v) => Some(Rc::clone(v)),
       _ => None,
     }
   }
 
-  pub fn as_function(self) -> Option<Function> {
+  /// Borrows the elements of a list, so host code can iterate them without
+  /// copying. While the borrow is alive, anything trying to modify the list
+  /// gets an error instead.
+  pub fn as_list(&self) -> Option<Ref<'_, Vec<Value>>> {
     match self {
-      Self::Function(v) => Some(v),
+      Self::List(v) => Some(v.borrow()),
       _ => None,
     }
   }
 
-  pub fn as_closure(self) -> Option<Closure> {
+  pub fn as_instance(&self) -> Option<Instance> {
     match self {
-      Self::Closure(v) => Some(v),
+      Self::Instance(v) => Some(v.clone()),
       _ => None,
     }
   }
 
+  pub fn type_name(&self) -> &'static str {
+    match self {
+      Self::Bool(_) => "bool",
+      Self::Nil => "nil",
+      Self::Number(_) | Self::Int(_) => "number",
+      Self::String(_) => "string",
+      Self::Function(_)
+      | Self::Closure(_)
+      | Self::BoundMethod(_)
+      | Self::Native(_) => "function",
+      Self::Class(_) => "class",
+      Self::Instance(_) => "instance",
+      Self::List(_) => "list",
+      Self::Map(_) => "map",
+    }
+  }
+
   pub fn is_bool(&self) -> bool {
     matches!(self, Self::Bool(_))
   }
@@ -197,11 +592,11 @@ impl Value {
   }
 
   pub fn is_number(&self) -> bool {
-    matches!(self, Self::Number(_))
+    matches!(self, Self::Number(_) | Self::Int(_))
   }
 
   pub fn is_falsey(&self) -> bool {
-    self.is_nil() || self.is_bool() && !self.clone().as_bool().unwrap()
+    self.is_nil() || self.is_bool() && !self.as_bool().unwrap()
   }
 
   pub fn is_string(&self) -> bool {
@@ -212,26 +607,302 @@ impl Value {
     matches!(self, Self::Function(_))
   }
 
+  /// Orders two numbers, or two strings lexicographically. `None` when a
+  /// number is NaN.
+  pub fn compare(a: &Self, b: &Self) -> Result<Option<Ordering>, String> {
+    match (a, b) {
+      (Self::Int(a), Self::Int(b)) => Ok(Some(a.cmp(b))),
+      (Self::String(a), Self::String(b)) => Ok(Some(a.cmp(b))),
+      _ if a.is_number() && b.is_number() => {
+        let a = a.as_number().unwrap();
+        Ok(a.partial_cmp(&b.as_number().unwrap()))
+      }
+      _ => Err("Operands must be comparable.".to_owned()),
+    }
+  }
+
   pub fn equal(a: &Self, b: &Self) -> bool {
     match (a, b) {
       (Self::Number(a), Self::Number(b)) => a == b,
+      (Self::Int(a), Self::Int(b)) => a == b,
+      (Self::Int(a), Self::Number(b)) | (Self::Number(b), Self::Int(a)) => {
+        *a as f64 == *b
+      }
       (Self::Bool(a), Self::Bool(b)) => a == b,
       (Self::Nil, Self::Nil) => true,
-      (Self::String(a), Self::String(b)) => a == b,
+      (Self::String(a), Self::String(b)) => Rc::ptr_eq(a, b) || a == b,
+      (Self::Closure(a), Self::Closure(b)) => Closure::same(a, b),
+      (Self::Class(a), Self::Class(b)) => Rc::ptr_eq(a, b),
+      (Self::Instance(a), Self::Instance(b)) => {
+        Rc::ptr_eq(&a.fields, &b.fields)
+      }
+      (Self::BoundMethod(a), Self::BoundMethod(b)) => {
+        Self::equal(&a.receiver, &b.receiver)
+          && Closure::same(&a.method, &b.method)
+      }
+      (Self::Function(a), Self::Function(b)) => Rc::ptr_eq(a, b),
+      (Self::Native(a), Self::Native(b)) => Rc::ptr_eq(a, b),
+      (Self::List(a), Self::List(b)) => Rc::ptr_eq(a, b),
+      (Self::Map(a), Self::Map(b)) => Rc::ptr_eq(a, b),
       _ => false,
     }
   }
 }
 
+const PRETTY_MAX_DEPTH: usize = 16;
+
+impl Value {
+  /// Renders the value over multiple lines, nesting the elements of lists and
+  /// maps and the fields of instances `indent` spaces deeper than their owner.
+  pub fn pretty(&self, indent: usize) -> String {
+    let mut buffer = String::new();
+    self.write_pretty(&mut buffer, indent, 0, &mut Vec::new());
+    buffer
+  }
+
+  fn write_pretty(
+    &self,
+    buffer: &mut String,
+    indent: usize,
+    depth: usize,
+    seen: &mut Vec<*const ()>,
+  ) {
+    match self {
+      Self::Instance(instance) => {
+        let id = Rc::as_ptr(&instance.fields) as *const ();
+        if seen.contains(&id) {
+          buffer.push_str("<cycle>");
+          return;
+        }
+        if depth >= PRETTY_MAX_DEPTH {
+          buffer.push_str("...");
+          return;
+        }
+
+        let fields = instance.fields.borrow();
+        if fields.is_empty() {
+          buffer.push_str(&format!("{:?} {{}}", instance));
+          return;
+        }
+
+        buffer.push_str(&format!("{:?} {{\n", instance));
+        seen.push(id);
+        let mut names: Vec<&String> = fields.keys().collect();
+        names.sort();
+        for (i, name) in names.iter().enumerate() {
+          buffer.push_str(&" ".repeat(indent * (depth + 1)));
+          buffer.push_str(&format!("{}: ", name));
+          fields[*name].write_pretty(buffer, indent, depth + 1, seen);
+          if i + 1 < names.len() {
+            buffer.push(',');
+          }
+          buffer.push('\n');
+        }
+        seen.pop();
+        buffer.push_str(&" ".repeat(indent * depth));
+        buffer.push('}');
+      }
+      Self::List(list) => {
+        let id = Rc::as_ptr(list) as *const ();
+        if seen.contains(&id) {
+          buffer.push_str("<cycle>");
+          return;
+        }
+        if depth >= PRETTY_MAX_DEPTH {
+          buffer.push_str("...");
+          return;
+        }
+
+        let elements = list.borrow();
+        if elements.is_empty() {
+          buffer.push_str("[]");
+          return;
+        }
+
+        buffer.push_str("[\n");
+        seen.push(id);
+        for (i, element) in elements.iter().enumerate() {
+          buffer.push_str(&" ".repeat(indent * (depth + 1)));
+          element.write_pretty(buffer, indent, depth + 1, seen);
+          if i + 1 < elements.len() {
+            buffer.push(',');
+          }
+          buffer.push('\n');
+        }
+        seen.pop();
+        buffer.push_str(&" ".repeat(indent * depth));
+        buffer.push(']');
+      }
+      Self::Map(map) => {
+        let id = Rc::as_ptr(map) as *const ();
+        if seen.contains(&id) {
+          buffer.push_str("<cycle>");
+          return;
+        }
+        if depth >= PRETTY_MAX_DEPTH {
+          buffer.push_str("...");
+          return;
+        }
+
+        let entries = map.borrow();
+        if entries.is_empty() {
+          buffer.push_str("{}");
+          return;
+        }
+
+        buffer.push_str("{\n");
+        seen.push(id);
+        let mut keys: Vec<&ValueKey> = entries.keys().collect();
+        keys.sort();
+        for (i, key) in keys.iter().enumerate() {
+          buffer.push_str(&" ".repeat(indent * (depth + 1)));
+          buffer.push_str(&format!("{:?}: ", key));
+          entries[*key].write_pretty(buffer, indent, depth + 1, seen);
+          if i + 1 < keys.len() {
+            buffer.push(',');
+          }
+          buffer.push('\n');
+        }
+        seen.pop();
+        buffer.push_str(&" ".repeat(indent * depth));
+        buffer.push('}');
+      }
+      _ => buffer.push_str(&format!("{:?}", self)),
+    }
+  }
+}
+
+/// Formats a number the way C's `printf("%g", n)` does, which is what clox
+/// uses to print numbers.
+fn format_number(n: f64) -> String {
+  const PRECISION: i32 = 6;
+
+  if n.is_nan() {
+    return "nan".to_owned();
+  }
+  if n.is_infinite() {
+    return if n > 0.0 { "inf" } else { "-inf" }.to_owned();
+  }
+  if n == 0.0 {
+    return if n.is_sign_negative() { "-0" } else { "0" }.to_owned();
+  }
+
+  // Round to the significant digits first, the exponent may change with it.
+  let scientific = format!("{:.*e}", (PRECISION - 1) as usize, n);
+  let (mantissa, exponent) = scientific.split_once('e').unwrap();
+  let exponent: i32 = exponent.parse().unwrap();
+
+  if !(-4..PRECISION).contains(&exponent) {
+    format!(
+      "{}e{}{:02}",
+      trim_fraction(mantissa),
+      if exponent < 0 { '-' } else { '+' },
+      exponent.abs()
+    )
+  } else {
+    let decimals = (PRECISION - 1 - exponent) as usize;
+    trim_fraction(&format!("{:.*}", decimals, n)).to_owned()
+  }
+}
+
+fn trim_fraction(s: &str) -> &str {
+  if s.contains('.') {
+    s.trim_end_matches('0').trim_end_matches('.')
+  } else {
+    s
+  }
+}
+
 impl fmt::Debug for Value {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     match self {
-      Self::Number(v) => write!(f, "{}", v),
+      Self::Number(v) => write!(f, "{}", format_number(*v)),
+      Self::Int(v) => write!(f, "{}", v),
       Self::Bool(v) => write!(f, "{}", v),
       Self::Nil => write!(f, "nil"),
       Self::String(v) => write!(f, "\"{}\"", v),
       Self::Function(v) => write!(f, "{:?}", v),
       Self::Closure(v) => write!(f, "{:?}", v),
+      Self::Class(v) => write!(f, "{:?}", v.borrow()),
+      Self::Instance(v) => write!(f, "{:?}", v),
+      Self::BoundMethod(v) => write!(f, "{:?}", v),
+      Self::Native(v) => write!(f, "{:?}", v),
+      Self::List(_) | Self::Map(_) => write_nested(f, self, &mut Vec::new()),
     }
   }
 }
+
+/// Writes a value and the lists and maps nested in it, showing a collection
+/// that contains itself as `[...]` or `{...}`.
+fn write_nested(
+  f: &mut fmt::Formatter<'_>,
+  value: &Value,
+  seen: &mut Vec<*const ()>,
+) -> fmt::Result {
+  match value {
+    Value::List(list) => {
+      let id = Rc::as_ptr(list) as *const ();
+      if seen.contains(&id) {
+        return write!(f, "[...]");
+      }
+      seen.push(id);
+      write!(f, "[")?;
+      for (i, element) in list.borrow().iter().enumerate() {
+        if i > 0 {
+          write!(f, ", ")?;
+        }
+        write_nested(f, element, seen)?;
+      }
+      seen.pop();
+      write!(f, "]")
+    }
+    Value::Map(map) => {
+      let id = Rc::as_ptr(map) as *const ();
+      if seen.contains(&id) {
+        return write!(f, "{{...}}");
+      }
+      seen.push(id);
+      write!(f, "{{")?;
+      let map = map.borrow();
+      let mut keys: Vec<&ValueKey> = map.keys().collect();
+      keys.sort();
+      for (i, key) in keys.iter().enumerate() {
+        if i > 0 {
+          write!(f, ", ")?;
+        }
+        write!(f, "{:?}: ", key)?;
+        write_nested(f, &map[*key], seen)?;
+      }
+      seen.pop();
+      write!(f, "}}")
+    }
+    _ => write!(f, "{:?}", value),
+  }
+}
+
+/// The form `print` shows to users: strings without quotes and callables in
+/// the book's `<fn name>` style.
+impl fmt::Display for Value {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::String(v) => write!(f, "{}", v),
+      Self::Function(v) => write_function(f, v),
+      Self::Closure(v) => write_function(f, &v.function),
+      Self::Class(v) => write!(f, "{}", v.borrow().name),
+      Self::Instance(v) => write!(f, "{} instance", v.class.borrow().name),
+      Self::BoundMethod(v) => write_function(f, &v.method.function),
+      Self::Native(_) => write!(f, "<native fn>"),
+      _ => write!(f, "{:?}", self),
+    }
+  }
+}
+
+fn write_function(
+  f: &mut fmt::Formatter<'_>,
+  function: &Function,
+) -> fmt::Result {
+  match function.kind {
+    FunctionKind::Script => write!(f, "<script>"),
+    _ => write!(f, "<fn {}>", function.name()),
+  }
+}
@@ -1,27 +1,126 @@
-use std::collections::HashMap;
+use std::{
+  cell::RefCell,
+  cmp::Ordering,
+  collections::HashMap,
+  io::{self, BufRead, Write},
+  rc::Rc,
+};
 
 use crate::{
-  chunk::Op,
+  chunk::{join_short, Op},
+  error::LoxError,
+  native,
   parser::compile,
-  value::{Closure, Function, Upvalue, Value},
-  Inspector,
+  suggest::did_you_mean,
+  value::{
+    BoundMethod, Class, Closure, Function, Instance, Native, NativeFn, Upvalue,
+    UpvalueState, Value, ValueKey,
+  },
+  Chunk, Inspector,
 };
 
-pub fn interpret(source: &str) -> Result<(), String> {
+/// The default for `VM::max_frames`.
+pub const FRAMES_MAX: usize = 256;
+const OVERFLOW_TRACE_LEN: usize = 5;
+/// What a malformed chunk popping more than it pushed runs into.
+const STACK_UNDERFLOW: &str = "VM stack underflow.";
+/// What `EndFinally` finds when the values below it aren't the ones a
+/// `finally` block is entered with.
+const BAD_FINALLY: &str = "No finally block to end.";
+/// What an open upvalue finds when a malformed chunk popped the local it
+/// captured without closing it.
+const LOST_LOCAL: &str = "Captured local is no longer on the stack.";
+/// How many frames a stack trace lists before summing up the rest.
+const STACK_TRACE_LEN: usize = 10;
+/// Room for the values of 64 calls, more being allocated as needed.
+const STACK_CAPACITY: usize = 64 * (u8::MAX as usize + 1);
+
+pub fn interpret(source: &str) -> Result<(), Vec<LoxError>> {
   let closure = compile(source)?;
-  let mut vm = VM::from_closure(closure);
-  vm.run(None)?;
+  let mut vm = VM::from_closure(closure).map_err(|error| vec![error])?;
+  vm.run(None).map_err(|error| vec![error])?;
   Ok(())
 }
 
+/// Converts a Lox value into a position in a list of `len` elements.
+fn list_index(index: &Value, len: usize) -> Result<usize, String> {
+  match index.as_number() {
+    Some(i) if i.fract() == 0.0 => {
+      if i < 0.0 || i >= len as f64 {
+        return Err("List index out of range.".to_owned());
+      }
+      Ok(i as usize)
+    }
+    _ => Err("List index must be a number.".to_owned()),
+  }
+}
+
+/// Raises an integer to an integer power, for exponents that keep it one.
+pub fn checked_pow(base: i64, exponent: i64) -> Option<i64> {
+  base.checked_pow(u32::try_from(exponent).ok()?)
+}
+
+fn no_local(slot: u8) -> String {
+  format!("No local in slot {}.", slot)
+}
+
+fn no_upvalue(slot: u8) -> String {
+  format!("No upvalue in slot {}.", slot)
+}
+
+/// Checks that an integer is shifted by fewer places than it has bits.
+fn shift_amount(amount: i64) -> Result<u32, String> {
+  match u32::try_from(amount) {
+    Ok(amount) if amount < i64::BITS => Ok(amount),
+    _ => Err("Shift amount must be between 0 and 63.".to_owned()),
+  }
+}
+
+/// Runs `source` and returns everything it printed.
+pub fn interpret_to_string(source: &str) -> Result<String, Vec<LoxError>> {
+  let closure = compile(source)?;
+  let output = OutputBuffer::new();
+  let mut vm = VM::from_closure(closure)
+    .map_err(|error| vec![error])?
+    .with_output(output.clone());
+  vm.run(None).map_err(|error| vec![error])?;
+  Ok(output.contents())
+}
+
+/// An in-memory output sink whose clones share one buffer, so the output can
+/// be read after handing a clone to `VM::with_output`.
+#[derive(Clone, Default)]
+pub struct OutputBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl OutputBuffer {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn contents(&self) -> String {
+    String::from_utf8_lossy(&self.0.borrow()).into_owned()
+  }
+}
+
+impl Write for OutputBuffer {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    self.0.borrow_mut().write(buf)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+#[derive(Clone)]
 pub struct CallFrame {
   closure: Closure,
   index: u16,
-  start: u8,
+  start: usize,
 }
 
 impl CallFrame {
-  pub fn new(closure: Closure, start: u8) -> Self {
+  pub fn new(closure: Closure, start: usize) -> Self {
     Self {
       closure,
       index: 0,
@@ -29,10 +128,15 @@ impl CallFrame {
     }
   }
 
-  pub fn start(&self) -> u8 {
+  pub fn start(&self) -> usize {
     self.start
   }
 
+  /// The name of the function running in this frame.
+  pub fn function_name(&self) -> &str {
+    self.closure.function.name()
+  }
+
   pub fn step_ahead(&mut self, n: u16) {
     self.index += n;
   }
@@ -41,6 +145,14 @@ impl CallFrame {
     self.index -= n;
   }
 
+  /// The source line of the instruction being run, or of the call a caller
+  /// is waiting on.
+  pub fn current_line(&self) -> usize {
+    let lines = &self.closure.function.chunk.lines;
+    let index = (self.index as usize).wrapping_sub(1);
+    lines.get(index).copied().unwrap_or(0)
+  }
+
   pub fn read_byte(&mut self) -> u8 {
     let byte = self
       .closure
@@ -54,13 +166,30 @@ impl CallFrame {
   }
 
   pub fn read_short(&mut self) -> u16 {
-    let offset_0 = self.read_byte();
-    let offset_1 = self.read_byte();
-    unsafe { *[offset_0, offset_1].as_ptr().cast::<u16>() }
+    let hi = self.read_byte();
+    let lo = self.read_byte();
+    join_short(hi, lo)
   }
 
   pub fn read_constant(&mut self) -> Value {
     let i = self.read_byte() as usize;
+    self.constant(i)
+  }
+
+  /// Reads a constant naming a global and returns the global's slot.
+  pub fn read_global_slot(&mut self) -> usize {
+    let i = self.read_byte() as usize;
+    self.closure.function.chunk.global_slots[i]
+  }
+
+  /// Reads the 24-bit big-endian index of a `ConstantLong`.
+  pub fn read_long_constant(&mut self) -> Value {
+    let hi = self.read_byte() as usize;
+    let i = hi << 16 | self.read_short() as usize;
+    self.constant(i)
+  }
+
+  fn constant(&self, i: usize) -> Value {
     self
       .closure
       .function
@@ -71,56 +200,434 @@ impl CallFrame {
       .clone()
   }
 
-  pub fn get_local(&mut self, stack: &Vec<Value>) -> Value {
-    let index = self.start() + self.read_byte();
-    stack.get(index as usize).unwrap().clone()
+  pub fn get_local(&mut self, stack: &[Value]) -> Result<Value, String> {
+    let slot = self.read_byte();
+    let local = stack.get(self.start() + slot as usize);
+    local.cloned().ok_or_else(|| no_local(slot))
   }
 
-  pub fn set_local(&mut self, stack: &mut Vec<Value>, value: Value) {
-    let index = self.start() + self.read_byte();
-    let old = stack.get_mut(index as usize).unwrap();
-    *old = value;
+  pub fn set_local(
+    &mut self,
+    stack: &mut [Value],
+    value: Value,
+  ) -> Result<(), String> {
+    let slot = self.read_byte();
+    let local = stack.get_mut(self.start() + slot as usize);
+    *local.ok_or_else(|| no_local(slot))? = value;
+    Ok(())
   }
 }
 
+/// What a single `VM::step` came to.
+#[derive(Debug)]
+pub enum StepResult {
+  /// The instruction ran and the next one is ready.
+  Continue,
+  /// The script returned this value and there is nothing left to run.
+  Halted(Value),
+  /// The instruction raised an error and a `try` statement caught it, so the
+  /// next step runs its handler.
+  Error,
+}
+
 pub struct VM {
+  /// The frames suspended by calls, below the `current` one.
   pub frames: Vec<CallFrame>,
+  /// The frame of the function being run, if any.
+  pub current: Option<CallFrame>,
+  /// How deep calls may nest before a stack overflow is reported.
+  pub max_frames: usize,
   pub stack: Vec<Value>,
-  pub globals: HashMap<String, Value>,
+  /// Indexed by the slots that loaded chunks find in `global_slots`.
+  globals: Vec<Global>,
+  global_names: Vec<Rc<str>>,
+  /// The slot of each global name, looked up once for each chunk that uses
+  /// the name when the chunk is loaded.
+  global_slots: HashMap<Rc<str>, usize>,
+  /// How many times `global_slots` was searched while loading code.
+  pub name_lookups: usize,
+  open_upvalues: Vec<Upvalue>,
+  on_global_set: Option<GlobalSetHook>,
+  tracer: Option<Tracer>,
+  print_terminator: String,
+  /// Whether dividing by zero is an error rather than an infinity or NaN.
+  strict_math: bool,
+  output: Box<dyn Write>,
+  /// Where `input()` reads from, stdin when `None`. Stdin isn't locked for
+  /// the whole run so that the REPL can read from it between lines.
+  input: Option<Box<dyn BufRead>>,
+  handlers: Vec<CatchHandler>,
+  /// The value of a `throw` on its way to a catch handler.
+  thrown: Option<Value>,
+}
+
+enum Global {
+  Undefined,
+  /// Introduced by `var a;` with no initializer (`Op::DeclareGlobal`), so
+  /// reading it before it's assigned is an error.
+  Declared,
+  Defined(Value),
+}
+
+/// Where execution resumes when an error reaches a `try` block's `catch`.
+struct CatchHandler {
+  /// The frame of the `try` block, pointing at its catch clause.
+  frame: CallFrame,
+  /// How many frames were suspended below it.
+  frames: usize,
+  stack: usize,
+  /// Whether it points at a `finally` block, which also runs when the `try`
+  /// statement is left by popping the handler or by a `return`.
+  finally: bool,
+}
+
+/// How a `finally` block carries on when it ends, kept on the stack below
+/// its locals next to the value it applies to.
+const FINALLY_RESUME: f64 = 0.0;
+const FINALLY_THROW: f64 = 1.0;
+const FINALLY_RETURN: f64 = 2.0;
+
+/// Observes every value a script stores into a global.
+pub type GlobalSetHook = Box<dyn FnMut(&str, &Value)>;
+
+/// Called before each instruction with its op, its index in the chunk and the
+/// stack.
+pub type Tracer = Box<dyn FnMut(&str, usize, &[Value])>;
+
+impl Default for VM {
+  fn default() -> Self {
+    Self::new()
+  }
 }
 
 impl VM {
   pub fn new() -> Self {
-    Self {
+    let mut vm = Self {
       frames: Vec::new(),
-      stack: Vec::new(),
-      globals: HashMap::new(),
+      current: None,
+      max_frames: FRAMES_MAX,
+      stack: Vec::with_capacity(STACK_CAPACITY),
+      globals: Vec::new(),
+      global_names: Vec::new(),
+      global_slots: HashMap::new(),
+      name_lookups: 0,
+      open_upvalues: Vec::new(),
+      on_global_set: None,
+      tracer: None,
+      print_terminator: "\n".to_owned(),
+      strict_math: false,
+      output: Box::new(io::stdout()),
+      input: None,
+      handlers: Vec::new(),
+      thrown: None,
+    };
+    vm.define_native("prettyPrint", 1, native::pretty_print);
+    vm.define_native("len", 1, native::len);
+    vm.define_native("push", 2, native::push);
+    vm.define_native("pop", 1, native::pop);
+    vm.define_native("input", 0, native::input);
+    vm.define_native("number", 1, native::number);
+    vm.define_native("string", 1, native::string);
+    vm.define_native("type", 1, native::type_of);
+    vm
+  }
+
+  pub fn define_native(&mut self, name: &str, arity: u8, function: NativeFn) {
+    let native = Native::new(name, arity, function);
+    let slot = self.global_slot(&Rc::from(name));
+    self.globals[slot] = Global::Defined(Value::native(native));
+  }
+
+  /// The value of the global `name`, if it has been defined.
+  pub fn global(&self, name: &str) -> Option<&Value> {
+    match &self.globals[*self.global_slots.get(name)?] {
+      Global::Defined(value) => Some(value),
+      _ => None,
+    }
+  }
+
+  /// The zero-division policy of `/` and `%`, checked once the operands are
+  /// known to be numbers.
+  fn check_divisor(&self, divisor: &Value) -> Result<(), String> {
+    if self.strict_math && divisor.as_number() == Some(0.0) {
+      return Err("Division by zero.".to_owned());
+    }
+    Ok(())
+  }
+
+  fn global_slot(&mut self, name: &Rc<str>) -> usize {
+    if let Some(&slot) = self.global_slots.get(name) {
+      return slot;
+    }
+    let slot = self.globals.len();
+    self.globals.push(Global::Undefined);
+    self.global_names.push(name.clone());
+    self.global_slots.insert(name.clone(), slot);
+    slot
+  }
+
+  /// Copies `function`, and the functions in its constants, with the slots
+  /// of the globals their code names.
+  fn link(&mut self, function: &Function) -> Function {
+    let mut function = function.clone();
+    let chunk = &mut function.chunk;
+    chunk.global_slots = vec![0; chunk.constants.len()];
+    for index in chunk.global_names() {
+      let name = chunk.constants[index].as_shared_string().unwrap();
+      self.name_lookups += 1;
+      chunk.global_slots[index] = self.global_slot(&name);
     }
+    for constant in &mut chunk.constants {
+      if let Value::Closure(closure) = constant {
+        closure.function = Rc::new(self.link(&closure.function));
+      }
+    }
+    function
+  }
+
+  /// Sets what `print` writes after each value, a newline by default.
+  pub fn with_print_terminator(mut self, terminator: String) -> Self {
+    self.print_terminator = terminator;
+    self
+  }
+
+  /// Makes `/` and `%` by zero runtime errors instead of following f64
+  /// arithmetic.
+  pub fn with_strict_math(mut self, strict: bool) -> Self {
+    self.strict_math = strict;
+    self
+  }
+
+  /// Sends what the script prints to `output` instead of stdout.
+  pub fn with_output(mut self, output: impl Write + 'static) -> Self {
+    self.output = Box::new(output);
+    self
+  }
+
+  pub fn write_output(&mut self, text: &str) -> Result<(), String> {
+    self
+      .output
+      .write_all(text.as_bytes())
+      .map_err(|error| format!("Failed to write output: {}.", error))
+  }
+
+  /// Reads what `input()` returns from `input` instead of stdin.
+  pub fn with_input(mut self, input: impl BufRead + 'static) -> Self {
+    self.input = Some(Box::new(input));
+    self
+  }
+
+  /// Reads a line of input without its line ending, or `None` at the end.
+  pub fn read_input(&mut self) -> Result<Option<String>, String> {
+    let mut line = String::new();
+    let read = match &mut self.input {
+      Some(input) => input.read_line(&mut line),
+      None => io::stdin().read_line(&mut line),
+    };
+    let read =
+      read.map_err(|error| format!("Failed to read input: {}.", error))?;
+    if read == 0 {
+      return Ok(None);
+    }
+    if line.ends_with('\n') {
+      line.pop();
+      if line.ends_with('\r') {
+        line.pop();
+      }
+    }
+    Ok(Some(line))
+  }
+
+  /// Registers `hook` to be called with the name and new value whenever the
+  /// script defines or assigns a global.
+  pub fn on_global_set(&mut self, hook: impl FnMut(&str, &Value) + 'static) {
+    self.on_global_set = Some(Box::new(hook));
+  }
+
+  pub fn set_tracer(&mut self, tracer: Tracer) {
+    self.tracer = Some(tracer);
   }
 
-  pub fn from_closure(closure: Closure) -> Self {
+  /// A VM set to run `closure` as a script, failing if its code couldn't run.
+  pub fn from_closure(closure: Closure) -> Result<Self, LoxError> {
     let mut vm = Self::new();
-    let frame = CallFrame::new(closure.clone(), 0);
-    vm.frames.push(frame);
-    vm.stack.push(Value::closure(closure));
-    vm
+    vm.load(closure)?;
+    Ok(vm)
+  }
+
+  /// A VM set to run `chunk` as a script, such as one read back by
+  /// `Chunk::deserialize` or built by hand.
+  pub fn from_chunk(chunk: Chunk) -> Result<Self, LoxError> {
+    let mut script = Function::new_script();
+    script.chunk = chunk;
+    Self::from_closure(Closure::new(script, 0))
+  }
+
+  /// Runs another compiled script on this VM, keeping the globals defined by
+  /// earlier ones. Whatever an earlier script left behind after an error is
+  /// discarded first.
+  pub fn interpret_closure(
+    &mut self,
+    closure: Closure,
+  ) -> Result<(), LoxError> {
+    self.reset();
+    self.load(closure)?;
+    self.run(None)?;
+    Ok(())
+  }
+
+  /// Like `interpret_closure`, returning the value the script returns, such
+  /// as that of its last expression when compiled by `compile_eval`.
+  pub fn eval(&mut self, closure: Closure) -> Result<Value, LoxError> {
+    self.reset();
+    self.load(closure)?;
+    self.run_to_end(&mut None)
+  }
+
+  fn reset(&mut self) {
+    self.frames.clear();
+    self.current = None;
+    self.stack.clear();
+    self.open_upvalues.clear();
+    self.handlers.clear();
+    self.thrown = None;
+  }
+
+  fn load(&mut self, mut closure: Closure) -> Result<(), LoxError> {
+    // Linking walks the code, which only works on code that is whole.
+    let chunk = &closure.function.chunk;
+    chunk.validate().map_err(|msg| LoxError::Load { msg })?;
+    closure.function = Rc::new(self.link(&closure.function));
+    self.current = Some(CallFrame::new(closure.clone(), 0));
+    self.stack.push(Value::closure(closure));
+    Ok(())
   }
 
   fn call(
     &mut self,
     callee: Value,
     arg_count: u8,
-    frame: CallFrame,
-  ) -> Result<CallFrame, String> {
+    frame: &mut CallFrame,
+  ) -> Result<(), String> {
     match callee {
       Value::Closure(closure) => closure.call(self, arg_count, frame),
       Value::Function(f) => f.call(self, arg_count, frame),
-      _ => Err("Can only call functions and classes.".to_owned()),
+      Value::BoundMethod(bound) => bound.call(self, arg_count, frame),
+      Value::Native(native) => native.call(self, arg_count),
+      Value::Class(class) => {
+        if arg_count != 0 {
+          return Err(format!("Expected 0 arguments but got {}.", arg_count));
+        }
+        let len = self.stack.len();
+        self.stack[len - 1] = Value::instance(Instance::new(class));
+        Ok(())
+      }
+      _ => Err(format!(
+        "Can only call functions and classes, got {}.",
+        callee.type_name()
+      )),
+    }
+  }
+
+  /// Takes the top `len` values off the stack.
+  fn split_top(&mut self, len: usize) -> Result<Vec<Value>, String> {
+    let start = self.stack.len().checked_sub(len).ok_or(STACK_UNDERFLOW)?;
+    Ok(self.stack.split_off(start))
+  }
+
+  fn capture_upvalue(&mut self, slot: usize) -> Upvalue {
+    let open = self.open_upvalues.iter().find(|upvalue| {
+      matches!(*upvalue.borrow(), UpvalueState::Open(index) if index == slot)
+    });
+    if let Some(upvalue) = open {
+      return Rc::clone(upvalue);
+    }
+    let upvalue = Rc::new(RefCell::new(UpvalueState::Open(slot)));
+    self.open_upvalues.push(Rc::clone(&upvalue));
+    upvalue
+  }
+
+  fn close_upvalues(&mut self, from: usize) {
+    let stack = &self.stack;
+    self.open_upvalues.retain(|upvalue| {
+      let mut state = upvalue.borrow_mut();
+      match *state {
+        UpvalueState::Open(index) if index >= from => match stack.get(index) {
+          Some(value) => {
+            *state = UpvalueState::Closed(value.clone());
+            false
+          }
+          // Left open, so that reading it reports the lost local.
+          None => true,
+        },
+        _ => true,
+      }
+    });
+  }
+
+  /// Describes a frame-limit overflow, naming the innermost calls so that the
+  /// recursive cycle is visible.
+  pub fn stack_overflow(&self, callee: &Closure, frame: &CallFrame) -> String {
+    let callers = self.frames.iter().rev().map(CallFrame::function_name);
+    let trace = std::iter::once(callee.function.name())
+      .chain(std::iter::once(frame.function_name()))
+      .chain(callers)
+      .take(OVERFLOW_TRACE_LEN)
+      .collect::<Vec<_>>()
+      .join(" <- ");
+    format!("Stack overflow. Recent calls: {} <- ...", trace)
+  }
+
+  fn set_global(&mut self, slot: usize, value: Value) {
+    if let Some(hook) = &mut self.on_global_set {
+      hook(&self.global_names[slot], &value);
     }
+    self.globals[slot] = Global::Defined(value);
   }
 
-  fn function_return(&mut self, result: Value, frame: CallFrame) -> CallFrame {
-    unsafe { self.stack.set_len(frame.start() as usize) };
+  fn undefined_variable(&self, name: &str) -> String {
+    let names = (self.global_names.iter().zip(&self.globals))
+      .filter(|(_, global)| !matches!(global, Global::Undefined))
+      .map(|(name, _)| &**name);
+    match did_you_mean(name, names) {
+      Some(similar) => {
+        format!("Undefined variable '{}'; did you mean '{}'?", name, similar)
+      }
+      None => format!("Undefined variable '{}'.", name),
+    }
+  }
+
+  /// Returns `result` from `frame`, leaving `frame` at its caller or, when a
+  /// `finally` block in the frame has to run first, at that block. Returns
+  /// `false` when the script is done.
+  fn return_from(&mut self, result: Value, frame: &mut CallFrame) -> bool {
+    // A `return` inside a `try` block leaves it without a PopCatch.
+    let depth = self.frames.len();
+    while let Some(handler) = self.handlers.pop_if(|h| h.frames >= depth) {
+      if handler.finally {
+        self.enter_finally(&handler, result, FINALLY_RETURN);
+        *frame = handler.frame;
+        return true;
+      }
+    }
+    self.close_upvalues(frame.start());
+    if self.frames.is_empty() {
+      self.stack.pop();
+      return false;
+    }
+    *frame = self.function_return(result, frame);
+    true
+  }
+
+  fn enter_finally(&mut self, handler: &CatchHandler, value: Value, kind: f64) {
+    self.close_upvalues(handler.stack);
+    self.stack.truncate(handler.stack);
+    self.stack.push(value);
+    self.stack.push(Value::number(kind));
+  }
+
+  fn function_return(&mut self, result: Value, frame: &CallFrame) -> CallFrame {
+    self.stack.truncate(frame.start());
     self.stack.push(result);
     self.frames.pop().unwrap()
   }
@@ -128,8 +635,90 @@ impl VM {
   pub fn run(
     &mut self,
     mut inspector: Option<Inspector>,
-  ) -> Result<Option<Inspector>, String> {
-    let mut frame = self.frames.pop().unwrap();
+  ) -> Result<Option<Inspector>, LoxError> {
+    self.run_to_end(&mut inspector)?;
+    Ok(inspector)
+  }
+
+  /// Runs until the script returns, giving back what it returned.
+  fn run_to_end(
+    &mut self,
+    inspector: &mut Option<Inspector>,
+  ) -> Result<Value, LoxError> {
+    loop {
+      if let Some(inspector) = inspector {
+        inspector.catch_stack(self.stack.clone())
+      }
+      match self.step() {
+        Ok(StepResult::Halted(value)) => return Ok(value),
+        Ok(_) => {}
+        Err(msg) => {
+          let frame = self.current.as_ref();
+          return Err(LoxError::Runtime {
+            line: frame.map_or(0, CallFrame::current_line),
+            msg,
+            trace: self.stack_trace(),
+          });
+        }
+      }
+    }
+  }
+
+  /// Where each frame is, from the innermost one out, like
+  /// `[line 3] in <fun f>`.
+  fn stack_trace(&self) -> Vec<String> {
+    let frames = self.current.iter().chain(self.frames.iter().rev());
+    let mut trace: Vec<_> = frames
+      .take(STACK_TRACE_LEN)
+      .map(|frame| {
+        let function = &frame.closure.function;
+        format!("[line {}] in {:?}", frame.current_line(), function)
+      })
+      .collect();
+    let len = self.frames.len() + 1;
+    if len > STACK_TRACE_LEN {
+      trace.push(format!("[{} more calls]", len - STACK_TRACE_LEN));
+    }
+    trace
+  }
+
+  /// Runs the next instruction, handing an error it raises to the innermost
+  /// `try` statement. An error nothing catches ends the script.
+  pub fn step(&mut self) -> Result<StepResult, String> {
+    let mut frame = self.current.take().ok_or("No script is running.")?;
+    let result = self.execute(&mut frame);
+    // The frame an uncaught error was raised in stays for its stack trace.
+    if !matches!(result, Ok(StepResult::Halted(_))) {
+      self.current = Some(frame);
+    }
+    match result {
+      Err(message) => match self.handlers.pop() {
+        Some(handler) => {
+          self.catch(handler, message);
+          Ok(StepResult::Error)
+        }
+        None => Err(message),
+      },
+      result => result,
+    }
+  }
+
+  /// Unwinds to `handler`'s frame and hands it the error: the thrown value,
+  /// or the message of a runtime error.
+  fn catch(&mut self, handler: CatchHandler, message: String) {
+    let error = self.thrown.take().unwrap_or(Value::string(&message));
+    if handler.finally {
+      self.enter_finally(&handler, error, FINALLY_THROW);
+    } else {
+      self.close_upvalues(handler.stack);
+      self.stack.truncate(handler.stack);
+      self.stack.push(error);
+    }
+    self.frames.truncate(handler.frames);
+    self.current = Some(handler.frame);
+  }
+
+  fn execute(&mut self, frame: &mut CallFrame) -> Result<StepResult, String> {
     macro_rules! push {
       ($v:expr) => {
         self.stack.push($v)
@@ -137,190 +726,465 @@ impl VM {
     }
     macro_rules! pop {
       () => {
-        self.stack.pop().unwrap()
+        self.stack.pop().ok_or(STACK_UNDERFLOW)?
       };
     }
     macro_rules! peek {
       ($distance:expr) => {
         self
           .stack
-          .get(self.stack.len() - 1 - $distance as usize)
-          .unwrap()
+          .len()
+          .checked_sub(1 + $distance as usize)
+          .and_then(|index| self.stack.get(index))
+          .ok_or(STACK_UNDERFLOW)?
       };
     }
 
-    loop {
-      if let Some(ref mut inspector) = inspector {
-        inspector.catch_stack(self.stack.clone())
+    let index = frame.index as usize;
+    let code = frame.read_byte();
+    let op = Op::try_from(code)?;
+    if let Some(tracer) = &mut self.tracer {
+      tracer(&format!("{:?}", op), index, &self.stack);
+    }
+    match op {
+      Op::Constant => {
+        let constant = frame.read_constant();
+        push!(constant);
       }
-
-      let code = frame.read_byte();
-      let op = Op::from(code);
-      match op {
-        Op::Constant => {
-          let constant = frame.read_constant();
-          push!(constant);
-        }
-        Op::Nil => push!(Value::nil()),
-        Op::True => push!(Value::bool(true)),
-        Op::False => push!(Value::bool(false)),
-        Op::Pop => {
-          pop!();
-        }
-        Op::GetLocal => {
-          let value = frame.get_local(&self.stack);
-          push!(value);
+      Op::ConstantLong => {
+        let constant = frame.read_long_constant();
+        push!(constant);
+      }
+      Op::Nil => push!(Value::nil()),
+      Op::True => push!(Value::bool(true)),
+      Op::False => push!(Value::bool(false)),
+      Op::Pop => {
+        pop!();
+      }
+      Op::Dup => {
+        let value = peek!(0).clone();
+        push!(value);
+      }
+      Op::Swap => {
+        let below = self.stack.len().checked_sub(2).ok_or(STACK_UNDERFLOW)?;
+        self.stack.swap(below, below + 1);
+      }
+      Op::GetLocal => {
+        let value = frame.get_local(&self.stack)?;
+        push!(value);
+      }
+      Op::SetLocal => {
+        let value = peek!(0).clone();
+        frame.set_local(&mut self.stack, value)?;
+      }
+      Op::GetGlobal => {
+        let slot = frame.read_global_slot();
+        let value = match &self.globals[slot] {
+          Global::Defined(value) => value.clone(),
+          Global::Declared => {
+            let name = &self.global_names[slot];
+            return Err(format!("Variable '{}' used before assignment.", name));
+          }
+          Global::Undefined => {
+            return Err(self.undefined_variable(&self.global_names[slot]))
+          }
+        };
+        push!(value);
+      }
+      Op::DeclareGlobal => {
+        let slot = frame.read_global_slot();
+        self.globals[slot] = Global::Declared;
+      }
+      Op::DefineGlobal => {
+        let slot = frame.read_global_slot();
+        let value = pop!();
+        self.set_global(slot, value);
+      }
+      Op::SetGlobal => {
+        let slot = frame.read_global_slot();
+        if let Global::Undefined = self.globals[slot] {
+          return Err(self.undefined_variable(&self.global_names[slot]));
         }
-        Op::SetLocal => {
-          let value = peek!(0).clone();
-          frame.set_local(&mut self.stack, value);
+        let value = peek!(0).clone();
+        self.set_global(slot, value);
+      }
+      Op::GetUpvalue => {
+        let index = frame.read_byte();
+        let upvalue = frame.closure.upvalues.get(index as usize);
+        let upvalue = upvalue.ok_or_else(|| no_upvalue(index))?;
+        let value = match &*upvalue.borrow() {
+          UpvalueState::Open(slot) => {
+            self.stack.get(*slot).cloned().ok_or(LOST_LOCAL)?
+          }
+          UpvalueState::Closed(value) => value.clone(),
+        };
+        push!(value);
+      }
+      Op::SetUpvalue => {
+        let index = frame.read_byte();
+        let value = peek!(0).clone();
+        let upvalue = frame.closure.upvalues.get(index as usize);
+        let upvalue = upvalue.ok_or_else(|| no_upvalue(index))?;
+        match &mut *upvalue.borrow_mut() {
+          UpvalueState::Open(slot) => {
+            *self.stack.get_mut(*slot).ok_or(LOST_LOCAL)? = value
+          }
+          UpvalueState::Closed(closed) => *closed = value,
+        };
+      }
+      Op::Equal => {
+        let b = pop!();
+        let a = pop!();
+        push!(Value::bool(Value::equal(&a, &b)));
+      }
+      Op::Greater => {
+        let b = pop!();
+        let a = pop!();
+        let ordering = Value::compare(&a, &b)?;
+        push!(Value::bool(ordering == Some(Ordering::Greater)));
+      }
+      Op::Less => {
+        let b = pop!();
+        let a = pop!();
+        let ordering = Value::compare(&a, &b)?;
+        push!(Value::bool(ordering == Some(Ordering::Less)));
+      }
+      Op::Add => {
+        let b = pop!();
+        let a = pop!();
+        if b.is_string() && a.is_string() {
+          let b = b.as_string().unwrap();
+          let a = a.as_string().unwrap();
+          let concat = &format!("{}{}", a, b);
+          push!(Value::string(concat));
+        } else if b.is_number() && a.is_number() {
+          push!(Value::arithmetic(&a, &b, i64::checked_add, |a, b| a + b)?);
+        } else {
+          return Err(
+            "Operands must be two numbers or two strings.".to_string(),
+          );
         }
-        Op::GetGlobal => {
-          let name = frame.read_constant();
-          let name = name.as_string().unwrap();
-          let value = self
-            .globals
-            .get(&name)
-            .ok_or("Undefined variable.")?
-            .clone();
-          push!(value);
+      }
+      Op::Subtract => {
+        let b = pop!();
+        let a = pop!();
+        push!(Value::arithmetic(&a, &b, i64::checked_sub, |a, b| a - b)?);
+      }
+      Op::Multiply => {
+        let b = pop!();
+        let a = pop!();
+        push!(Value::arithmetic(&a, &b, i64::checked_mul, |a, b| a * b)?);
+      }
+      Op::Divide => {
+        let b = pop!();
+        let a = pop!().expect_number()?;
+        let quotient = a / b.expect_number()?;
+        self.check_divisor(&b)?;
+        push!(Value::number(quotient));
+      }
+      Op::Modulo => {
+        let b = pop!();
+        let a = pop!();
+        let remainder =
+          Value::arithmetic(&a, &b, i64::checked_rem, |a, b| a % b)?;
+        self.check_divisor(&b)?;
+        push!(remainder);
+      }
+      Op::Power => {
+        let b = pop!();
+        let a = pop!();
+        let power = Value::arithmetic(&a, &b, checked_pow, f64::powf)?;
+        push!(power);
+      }
+      Op::BitAnd => {
+        let b = pop!();
+        let a = pop!();
+        let (a, b) = Value::expect_ints(&a, &b)?;
+        push!(Value::int(a & b));
+      }
+      Op::BitOr => {
+        let b = pop!();
+        let a = pop!();
+        let (a, b) = Value::expect_ints(&a, &b)?;
+        push!(Value::int(a | b));
+      }
+      Op::BitXor => {
+        let b = pop!();
+        let a = pop!();
+        let (a, b) = Value::expect_ints(&a, &b)?;
+        push!(Value::int(a ^ b));
+      }
+      Op::ShiftLeft => {
+        let b = pop!();
+        let a = pop!();
+        let (a, b) = Value::expect_ints(&a, &b)?;
+        push!(Value::int(a << shift_amount(b)?));
+      }
+      Op::ShiftRight => {
+        let b = pop!();
+        let a = pop!();
+        let (a, b) = Value::expect_ints(&a, &b)?;
+        push!(Value::int(a >> shift_amount(b)?));
+      }
+      Op::ToString => {
+        let v = pop!();
+        push!(Value::string(&v.to_string()));
+      }
+      Op::Not => {
+        let v = pop!().is_falsey();
+        push!(Value::bool(v));
+      }
+      Op::Negate => {
+        let v = pop!();
+        match v {
+          Value::Int(v) if v != i64::MIN => push!(Value::int(-v)),
+          _ => push!(Value::number(-v.expect_number()?)),
         }
-        Op::DefineGlobal => {
-          let name = frame.read_constant().as_string().unwrap().to_owned();
-          self.globals.insert(name, pop!());
+      }
+      Op::Print => {
+        let text = format!("{}{}", pop!(), self.print_terminator);
+        self.write_output(&text)?;
+      }
+      Op::Assert => {
+        let message = pop!();
+        if pop!().is_falsey() {
+          return Err(match message {
+            Value::String(message) => message.to_string(),
+            Value::Nil => "Assertion failed.".to_owned(),
+            message => format!("{:?}", message),
+          });
         }
-        Op::SetGlobal => {
-          let name = frame.read_constant().as_string().unwrap().to_owned();
-          self
-            .globals
-            .insert(name, peek!(0).clone())
-            .ok_or("Undefined variable.")?;
+      }
+      Op::Jump => {
+        let jump_offset = frame.read_short();
+        frame.step_ahead(jump_offset);
+      }
+      Op::JumpIfArg => {
+        let slot = frame.read_byte() as usize;
+        let jump_offset = frame.read_short();
+        // Arguments left out are missing from the top of the stack.
+        if self.stack.len() > frame.start() + slot {
+          frame.step_ahead(jump_offset);
         }
-        Op::GetUpvalue => {
-          let index = frame.read_byte();
-          dbg!(index, &frame.closure, &frame.closure.upvalues);
-          let value = frame.closure.upvalues.get(index as usize).unwrap().get();
-          push!(value);
+      }
+      Op::JumpIfFalse => {
+        let jump_offset = frame.read_short();
+        if peek!(0).is_falsey() {
+          frame.step_ahead(jump_offset);
         }
-        Op::SetUpvalue => {
+      }
+      Op::Loop => {
+        let offset = frame.read_short();
+        frame.step_back(offset);
+      }
+      Op::Call => {
+        let arg_count = frame.read_byte();
+        let callee = peek!(arg_count).clone();
+        self.call(callee, arg_count, frame)?;
+      }
+      Op::SpreadCall => {
+        let arg_count = frame.read_byte() as usize - 1;
+        let spread = pop!();
+        let elements = spread
+          .as_list()
+          .ok_or("Spread argument must be a list.")?
+          .clone();
+        let arg_count = u8::try_from(arg_count + elements.len())
+          .map_err(|_| "Can't have more than 255 arguments.")?;
+        self.stack.extend(elements);
+        let callee = peek!(arg_count).clone();
+        self.call(callee, arg_count, frame)?;
+      }
+      Op::Closure => {
+        let closure = frame.read_constant();
+        let mut closure = closure.as_closure().unwrap();
+        // The compiled function is shared behind an Rc, so a closure with
+        // no upvalues is the same closure on every execution.
+        for _ in 0..closure.upvalues_len {
+          let is_local = frame.read_byte() == 1;
           let index = frame.read_byte();
-          let len = self.stack.len();
-          let p = self.stack.get_mut(len - 1).unwrap();
-          frame
-            .closure
-            .upvalues
-            .get_mut(index as usize)
-            .unwrap()
-            .set(p);
-        }
-        Op::Equal => {
-          let b = pop!();
-          let a = pop!();
-          push!(Value::bool(Value::equal(&a, &b)));
-        }
-        Op::Greater => {
-          let b = pop!().as_number().ok_or("Operand must be a number.")?;
-          let a = pop!().as_number().ok_or("Operand must be a number.")?;
-          push!(Value::bool(a > b));
-        }
-        Op::Less => {
-          let b = pop!().as_number().ok_or("Operand must be a number.")?;
-          let a = pop!().as_number().ok_or("Operand must be a number.")?;
-          push!(Value::bool(a < b));
-        }
-        Op::Add => {
-          let b = pop!();
-          let a = pop!();
-          if b.is_string() && a.is_string() {
-            let b = b.as_string().unwrap();
-            let a = a.as_string().unwrap();
-            let concat = &format!("{}{}", a, b);
-            push!(Value::string(concat));
-          } else if b.is_number() && a.is_number() {
-            let b = b.as_number().unwrap();
-            let a = a.as_number().unwrap();
-            push!(Value::number(a + b));
+          if is_local {
+            let slot = frame.start() + index as usize;
+            if slot >= self.stack.len() {
+              return Err(no_local(index));
+            }
+            let upvalue = self.capture_upvalue(slot);
+            closure.upvalues.push(upvalue);
           } else {
-            return Err(
-              "Operands must be two numbers or two strings.".to_string(),
-            );
+            let upvalue = frame.closure.upvalues.get(index as usize);
+            let upvalue = upvalue.ok_or_else(|| no_upvalue(index))?;
+            closure.upvalues.push(upvalue.clone());
           }
         }
-        Op::Subtract => {
-          let b = pop!().as_number().ok_or("Operand must be a number.")?;
-          let a = pop!().as_number().ok_or("Operand must be a number.")?;
-          push!(Value::number(a - b));
-        }
-        Op::Multiply => {
-          let b = pop!().as_number().ok_or("Operand must be a number.")?;
-          let a = pop!().as_number().ok_or("Operand must be a number.")?;
-          push!(Value::number(a * b));
-        }
-        Op::Divide => {
-          let b = pop!().as_number().ok_or("Operand must be a number.")?;
-          let a = pop!().as_number().ok_or("Operand must be a number.")?;
-          push!(Value::number(a / b));
-        }
-        Op::Not => {
-          let v = pop!().is_falsey();
-          push!(Value::bool(v));
-        }
-        Op::Negate => {
-          let v = pop!().as_number().ok_or("Operand must be a number.")?;
-          push!(Value::number(-v));
-        }
-        Op::Print => println!("{:?}", pop!()),
-        Op::Jump => {
-          let jump_offset = frame.read_short();
-          frame.step_ahead(jump_offset);
-        }
-        Op::JumpIfFalse => {
-          let jump_offset = frame.read_short();
-          if peek!(0).is_falsey() {
-            frame.step_ahead(jump_offset);
+        push!(Value::closure(closure));
+      }
+      Op::Class => {
+        let name = frame.read_constant().as_string().unwrap();
+        push!(Value::class(Class::new(&name)));
+      }
+      Op::Method => {
+        let name = frame.read_constant().as_string().unwrap();
+        let method = pop!().as_closure().ok_or("Methods must be functions.")?;
+        let class = peek!(0).as_class().ok_or("Methods belong to a class.")?;
+        class.borrow_mut().methods.insert(name, method);
+      }
+      Op::GetProperty => {
+        let instance = peek!(0)
+          .as_instance()
+          .ok_or("Only instances have properties.")?;
+        let name = frame.read_constant().as_string().unwrap();
+        let field = instance.fields.borrow().get(&name).cloned();
+        let value = match field {
+          Some(value) => value,
+          None => {
+            let method = instance
+              .class
+              .borrow()
+              .methods
+              .get(&name)
+              .cloned()
+              .ok_or(format!("Undefined property '{}'.", name))?;
+            let receiver = Value::instance(instance);
+            Value::bound_method(BoundMethod::new(receiver, method))
           }
+        };
+        pop!();
+        push!(value);
+      }
+      Op::SetProperty => {
+        let instance = peek!(1)
+          .as_instance()
+          .ok_or("Only instances have fields.")?;
+        let name = frame.read_constant().as_string().unwrap();
+        let value = pop!();
+        instance.fields.borrow_mut().insert(name, value.clone());
+        pop!();
+        push!(value);
+      }
+      Op::CloseUpvalue => {
+        let top = self.stack.len().checked_sub(1).ok_or(STACK_UNDERFLOW)?;
+        self.close_upvalues(top);
+        pop!();
+      }
+      Op::Inherit => {
+        let superclass =
+          peek!(1).as_class().ok_or("Superclass must be a class.")?;
+        let subclass = pop!().as_class().ok_or("Subclass must be a class.")?;
+        let methods = superclass.borrow().methods.clone();
+        subclass.borrow_mut().methods.extend(methods);
+      }
+      Op::GetSuper => {
+        let name = frame.read_constant().as_string().unwrap();
+        let superclass =
+          pop!().as_class().ok_or("Superclass must be a class.")?;
+        let receiver = pop!();
+        let method = superclass
+          .borrow()
+          .methods
+          .get(&name)
+          .cloned()
+          .ok_or(format!("Undefined property '{}'.", name))?;
+        push!(Value::bound_method(BoundMethod::new(receiver, method)));
+      }
+      Op::BuildList => {
+        let len = frame.read_byte() as usize;
+        let elements = self.split_top(len)?;
+        push!(Value::list(elements));
+      }
+      Op::BuildMap => {
+        let len = frame.read_byte() as usize;
+        let entries = self.split_top(len * 2)?;
+        let map = entries
+          .chunks(2)
+          .map(|entry| Ok((ValueKey::try_from(&entry[0])?, entry[1].clone())))
+          .collect::<Result<_, String>>()?;
+        push!(Value::map(map));
+      }
+      Op::Index => {
+        let index = pop!();
+        let value = match pop!() {
+          Value::List(list) => {
+            let list = list.borrow();
+            list[list_index(&index, list.len())?].clone()
+          }
+          Value::Map(map) => {
+            let key = ValueKey::try_from(&index)?;
+            map.borrow().get(&key).cloned().unwrap_or(Value::nil())
+          }
+          _ => return Err("Only lists and maps can be indexed.".to_owned()),
+        };
+        push!(value);
+      }
+      Op::SetIndex => {
+        let value = pop!();
+        let index = pop!();
+        match pop!() {
+          Value::List(list) => {
+            let mut list = list.try_borrow_mut().map_err(|_| {
+              "Can't modify a list while it is being iterated.".to_owned()
+            })?;
+            let index = list_index(&index, list.len())?;
+            list[index] = value.clone();
+          }
+          Value::Map(map) => {
+            let key = ValueKey::try_from(&index)?;
+            map.borrow_mut().insert(key, value.clone());
+          }
+          _ => return Err("Only lists and maps can be indexed.".to_owned()),
         }
-        Op::Loop => {
-          let offset = frame.read_short();
-          frame.step_back(offset);
-        }
-        Op::Call => {
-          let arg_count = frame.read_byte();
-          let callee = peek!(arg_count).clone();
-          frame = self.call(callee, arg_count, frame)?;
+        push!(value);
+      }
+      Op::SetupCatch | Op::SetupFinally => {
+        let offset = frame.read_short();
+        let mut handler = frame.clone();
+        handler.step_ahead(offset);
+        self.handlers.push(CatchHandler {
+          frame: handler,
+          frames: self.frames.len(),
+          stack: self.stack.len(),
+          finally: matches!(op, Op::SetupFinally),
+        });
+      }
+      Op::PopCatch => {
+        let handler = self.handlers.pop().ok_or("No try block to leave.")?;
+        if handler.finally {
+          let resume = Value::number(frame.index as f64);
+          self.enter_finally(&handler, resume, FINALLY_RESUME);
+          *frame = handler.frame;
         }
-        Op::Closure => {
-          let closure = frame.read_constant();
-          let mut closure = closure.as_closure().unwrap();
-          for _ in 0..closure.upvalues_len {
-            let is_local = if frame.read_byte() == 1 { true } else { false };
-            let index = frame.read_byte();
-            if is_local {
-              let value = self
-                .stack
-                .get_mut((frame.start() + index) as usize)
-                .unwrap();
-              let upvalue = Upvalue::new(value);
-              closure.upvalues.push(upvalue);
-            } else {
-              let upvalue =
-                frame.closure.upvalues.get(index as usize).unwrap().clone();
-              closure.upvalues.push(upvalue);
-            }
+      }
+      Op::EndFinally => {
+        let kind = pop!().as_number().ok_or(BAD_FINALLY)?;
+        let value = pop!();
+        if kind == FINALLY_THROW {
+          let message = value.to_string();
+          self.thrown = Some(value);
+          return Err(message);
+        } else if kind == FINALLY_RETURN {
+          if !self.return_from(value.clone(), frame) {
+            return Ok(StepResult::Halted(value));
           }
-          dbg!(&closure, &closure.upvalues);
-          push!(Value::closure(closure));
-        }
-        Op::Return => {
-          let result = pop!();
-          if self.frames.is_empty() {
-            pop!();
-            break;
+        } else {
+          let resume = value.as_number().ok_or(BAD_FINALLY)? as usize;
+          if !frame.closure.function.chunk.starts_instruction(resume) {
+            return Err(BAD_FINALLY.to_owned());
           }
-          frame = self.function_return(result, frame);
+          frame.index = resume as u16;
         }
-      };
+      }
+      Op::Throw => {
+        let value = pop!();
+        let message = value.to_string();
+        self.thrown = Some(value);
+        return Err(message);
+      }
+      Op::Return => {
+        let result = pop!();
+        if !self.return_from(result.clone(), frame) {
+          return Ok(StepResult::Halted(result));
+        }
+      }
     }
-    Ok(inspector)
+    Ok(StepResult::Continue)
   }
 }
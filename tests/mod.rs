@@ -1,12 +1,53 @@
-use std::fmt;
+use std::{
+  cell::RefCell,
+  env, fmt, fs,
+  io::{Cursor, Write},
+  process::{Command, Stdio},
+  rc::Rc,
+  time::{Duration, Instant},
+};
 
-use clox_rs::{Inspector, Parser, Scanner, VM};
+use clox_rs::{
+  compile, compile_eval, disassemble, interpret_to_string, Chunk, Closure,
+  Function, FunctionKind, Inspector, LoxError, Op, OutputBuffer, Parser,
+  Scanner, StepResult, TokenType, Value, VM,
+};
 use expect_test::{expect, Expect};
 
 fn check(actual: &impl fmt::Debug, expect: Expect) {
   expect.assert_debug_eq(actual);
 }
 
+fn run(source: &str) -> VM {
+  let scanner = Scanner::new(source);
+  let mut parser = Parser::new(scanner, None);
+  parser.advance().unwrap();
+  parser.program().unwrap();
+  let (f, _) = parser.end_compiler();
+  let mut vm = VM::from_closure(f).unwrap();
+  vm.run(None).unwrap();
+  vm
+}
+
+/// Runs `source` through the `clox-rs` binary, returning (stdout, stderr).
+fn run_binary(name: &str, source: &str) -> (String, String) {
+  let path = env::temp_dir().join(format!("clox_rs_{}.lox", name));
+  fs::write(&path, source).unwrap();
+  let output = Command::new(env!("CARGO_BIN_EXE_clox-rs"))
+    .arg(&path)
+    .output()
+    .unwrap();
+  fs::remove_file(&path).unwrap();
+  (
+    String::from_utf8(output.stdout).unwrap(),
+    String::from_utf8(output.stderr).unwrap(),
+  )
+}
+
+fn global(vm: &VM, name: &str) -> Value {
+  vm.global(name).unwrap().clone()
+}
+
 macro_rules! assert_snapshot {
   ($source:literal, $bytecode_snapshot:literal, $stack_snapshot:literal) => {
     let scanner = Scanner::new($source);
@@ -16,7 +57,7 @@ macro_rules! assert_snapshot {
     parser.program().unwrap();
     let (f, _) = parser.end_compiler();
     let inspector = parser.into_inspector();
-    let mut vm = VM::from_closure(f);
+    let mut vm = VM::from_closure(f).unwrap();
     let inspector = vm.run(inspector).unwrap().unwrap();
     check(&inspector.debug_bytecode(), expect![[$bytecode_snapshot]]);
     check(&inspector.debug_stack(), expect![[$stack_snapshot]]);
@@ -25,10 +66,13 @@ macro_rules! assert_snapshot {
     fn get_err() -> Result<(), String> {
       let scanner = Scanner::new($source);
       let mut parser = Parser::new(scanner, None);
-      parser.advance()?;
-      parser.program()?;
+      let _ = parser.advance();
+      parser.program().map_err(|errors| {
+        let errors: Vec<_> = errors.iter().map(|e| e.to_string()).collect();
+        errors.join("\n")
+      })?;
       let (f, _) = parser.end_compiler();
-      let mut vm = VM::from_closure(f);
+      let mut vm = VM::from_closure(f).unwrap();
       vm.run(None)?;
       Ok(())
     }
@@ -81,30 +125,22 @@ fn chapter_18() {
     "!(5 - 4 > 3 * 2 == !nil);",
     "
 == <script> ==
-0000 Constant            0 '5'
-0002 Constant            1 '4'
-0004 Subtract
-0005 Constant            2 '3'
-0007 Constant            3 '2'
-0009 Multiply
-0010 Greater
-0011 Nil
-0012 Not
-0013 Equal
-0014 Not
-0015 Pop
-0016 Nil
-0017 Return
+0000 Constant            0 '1'
+0002 Constant            1 '6'
+0004 Greater
+0005 Nil
+0006 Not
+0007 Equal
+0008 Not
+0009 Pop
+0010 Nil
+0011 Return
 
 ",
     "
 == VM Stack Snapshot ==
 [<script>]
-[<script>, 5]
-[<script>, 5, 4]
 [<script>, 1]
-[<script>, 1, 3]
-[<script>, 1, 3, 2]
 [<script>, 1, 6]
 [<script>, false]
 [<script>, false, nil]
@@ -124,19 +160,15 @@ fn chapter_19() {
     r#""aha" + "b";"#,
     r#"
 == <script> ==
-0000 Constant            0 '"aha"'
-0002 Constant            1 '"b"'
-0004 Add
-0005 Pop
-0006 Nil
-0007 Return
+0000 Constant            0 '"ahab"'
+0002 Pop
+0003 Nil
+0004 Return
 
 "#,
     r#"
 == VM Stack Snapshot ==
 [<script>]
-[<script>, "aha"]
-[<script>, "aha", "b"]
 [<script>, "ahab"]
 [<script>]
 [<script>, nil]
@@ -154,27 +186,19 @@ print 3 * 4;
 "#,
     r#"
 == <script> ==
-0000 Constant            0 '1'
-0002 Constant            1 '2'
-0004 Add
+0000 Constant            0 '3'
+0002 Print
+0003 Constant            1 '12'
 0005 Print
-0006 Constant            2 '3'
-0008 Constant            3 '4'
-0010 Multiply
-0011 Print
-0012 Nil
-0013 Return
+0006 Nil
+0007 Return
 
 "#,
     r#"
 == VM Stack Snapshot ==
 [<script>]
-[<script>, 1]
-[<script>, 1, 2]
 [<script>, 3]
 [<script>]
-[<script>, 3]
-[<script>, 3, 4]
 [<script>, 12]
 [<script>]
 [<script>, nil]
@@ -189,16 +213,14 @@ fn chapter_21_global_uninit() {
     r#"var a;"#,
     r#"
 == <script> ==
-0000 Nil
-0001 DefineGlobal        0 '"a"'
-0003 Nil
-0004 Return
+0000 DeclareGlobal       0 '"a"'
+0002 Nil
+0003 Return
 
 "#,
     r#"
 == VM Stack Snapshot ==
 [<script>]
-[<script>, nil]
 [<script>]
 [<script>, nil]
 
@@ -244,12 +266,12 @@ print a;
 0002 DefineGlobal        0 '"a"'
 0004 Constant            3 '"bbb"'
 0006 DefineGlobal        2 '"b"'
-0008 Constant            5 '"assign add "'
-0010 GetGlobal           6 '"b"'
+0008 Constant            4 '"assign add "'
+0010 GetGlobal           2 '"b"'
 0012 Add
-0013 SetGlobal           4 '"a"'
+0013 SetGlobal           0 '"a"'
 0015 Pop
-0016 GetGlobal           7 '"a"'
+0016 GetGlobal           0 '"a"'
 0018 Print
 0019 Nil
 0020 Return
@@ -327,7 +349,7 @@ fn chapter_22_local_uninit() {
   }
 }
 "#,
-    "Can't read local variable in its own initializer."
+    "[line 5] Error at 'a': Can't read local variable in its own initializer."
   );
 }
 
@@ -518,15 +540,15 @@ while (a < 3) {
 == <script> ==
 0000 Constant            1 '0'
 0002 DefineGlobal        0 '"a"'
-0004 GetGlobal           2 '"a"'
-0006 Constant            3 '3'
+0004 GetGlobal           0 '"a"'
+0006 Constant            2 '3'
 0008 Less
 0009 JumpIfFalse         9 -> 24
 0012 Pop
-0013 GetGlobal           5 '"a"'
-0015 Constant            6 '1'
+0013 GetGlobal           0 '"a"'
+0015 Constant            3 '1'
 0017 Add
-0018 SetGlobal           4 '"a"'
+0018 SetGlobal           0 '"a"'
 0020 Pop
 0021 Loop               21 -> 4
 0024 Pop
@@ -672,26 +694,134 @@ fn chapter_23_for() {
   );
 }
 
+#[test]
+fn break_pops_loop_body_locals() {
+  assert_snapshot!(
+    r#"
+for (var i = 0; i < 3; i = i + 1) {
+  var a = i;
+  var _b = a;
+  if (i == 1) break;
+}
+var after = "done";
+"#,
+    r#"
+== <script> ==
+0000 Constant            0 '0'
+0002 GetLocal            1
+0004 Constant            1 '3'
+0006 Less
+0007 JumpIfFalse         7 -> 52
+0010 Pop
+0011 Jump               11 -> 25
+0014 GetLocal            1
+0016 Constant            2 '1'
+0018 Add
+0019 SetLocal            1
+0021 Pop
+0022 Loop               22 -> 2
+0025 GetLocal            1
+0027 GetLocal            2
+0029 GetLocal            1
+0031 Constant            2 '1'
+0033 Equal
+0034 JumpIfFalse        34 -> 46
+0037 Pop
+0038 Pop
+0039 Pop
+0040 Jump               40 -> 53
+0043 Jump               43 -> 47
+0046 Pop
+0047 Pop
+0048 Pop
+0049 Loop               49 -> 14
+0052 Pop
+0053 Pop
+0054 Constant            4 '"done"'
+0056 DefineGlobal        3 '"after"'
+0058 Nil
+0059 Return
+
+"#,
+    r#"
+== VM Stack Snapshot ==
+[<script>]
+[<script>, 0]
+[<script>, 0, 0]
+[<script>, 0, 0, 3]
+[<script>, 0, true]
+[<script>, 0, true]
+[<script>, 0]
+[<script>, 0]
+[<script>, 0, 0]
+[<script>, 0, 0, 0]
+[<script>, 0, 0, 0, 0]
+[<script>, 0, 0, 0, 0, 1]
+[<script>, 0, 0, 0, false]
+[<script>, 0, 0, 0, false]
+[<script>, 0, 0, 0]
+[<script>, 0, 0]
+[<script>, 0]
+[<script>, 0]
+[<script>, 0, 0]
+[<script>, 0, 0, 1]
+[<script>, 0, 1]
+[<script>, 1, 1]
+[<script>, 1]
+[<script>, 1]
+[<script>, 1, 1]
+[<script>, 1, 1, 3]
+[<script>, 1, true]
+[<script>, 1, true]
+[<script>, 1]
+[<script>, 1]
+[<script>, 1, 1]
+[<script>, 1, 1, 1]
+[<script>, 1, 1, 1, 1]
+[<script>, 1, 1, 1, 1, 1]
+[<script>, 1, 1, 1, true]
+[<script>, 1, 1, 1, true]
+[<script>, 1, 1, 1]
+[<script>, 1, 1]
+[<script>, 1]
+[<script>, 1]
+[<script>]
+[<script>, "done"]
+[<script>]
+[<script>, nil]
+
+"#
+  );
+}
+
+#[test]
+fn break_outside_loop() {
+  assert_snapshot!(
+    "break;",
+    "[line 1] Error at 'break': Can't use 'break' outside of a loop."
+  );
+}
+
 #[test]
 fn chapter_24_call_frames() {
-  // fun first() { var a = 1; second(); var b = 2; } fun second() { var c = 3; var d = 4; } first();
+  // fun first() { var _a = 1; second(); var _b = 2; } fun second() { var _c = 3; var _d = 4; } first();
   assert_snapshot!(
     r#"
 fun first() {
-  var a = 1;
+  var _a = 1;
   second();
-  var b = 2;
+  var _b = 2;
 }
 
 fun second() {
-  var c = 3;
-  var d = 4;
+  var _c = 3;
+  var _d = 4;
 }
 
 first();
 "#,
     r#"
-== <fun first> ==
+== <fun first/0 up0> ==
 0000 Constant            0 '1'
 0002 GetGlobal           1 '"second"'
 0004 Call                0
@@ -699,17 +829,17 @@ first();
 0007 Constant            2 '2'
 0009 Nil
 0010 Return
-== <fun second> ==
+== <fun second/0 up0> ==
 0000 Constant            0 '3'
 0002 Constant            1 '4'
 0004 Nil
 0005 Return
 == <script> ==
-0000 Closure             1 '<fun first>'
+0000 Closure             1 <fun first/0 up0>
 0002 DefineGlobal        0 '"first"'
-0004 Closure             3 '<fun second>'
+0004 Closure             3 <fun second/0 up0>
 0006 DefineGlobal        2 '"second"'
-0008 GetGlobal           4 '"first"'
+0008 GetGlobal           0 '"first"'
 0010 Call                0
 0012 Pop
 0013 Nil
@@ -719,22 +849,22 @@ first();
     r#"
 == VM Stack Snapshot ==
 [<script>]
-[<script>, <fun first>]
+[<script>, <fun first/0 up0>]
 [<script>]
-[<script>, <fun second>]
+[<script>, <fun second/0 up0>]
 [<script>]
-[<script>, <fun first>]
-[<script>, <fun first>]
-[<script>, <fun first>, 1]
-[<script>, <fun first>, 1, <fun second>]
-[<script>, <fun first>, 1, <fun second>]
-[<script>, <fun first>, 1, <fun second>, 3]
-[<script>, <fun first>, 1, <fun second>, 3, 4]
-[<script>, <fun first>, 1, <fun second>, 3, 4, nil]
-[<script>, <fun first>, 1, nil]
-[<script>, <fun first>, 1]
-[<script>, <fun first>, 1, 2]
-[<script>, <fun first>, 1, 2, nil]
+[<script>, <fun first/0 up0>]
+[<script>, <fun first/0 up0>]
+[<script>, <fun first/0 up0>, 1]
+[<script>, <fun first/0 up0>, 1, <fun second/0 up0>]
+[<script>, <fun first/0 up0>, 1, <fun second/0 up0>]
+[<script>, <fun first/0 up0>, 1, <fun second/0 up0>, 3]
+[<script>, <fun first/0 up0>, 1, <fun second/0 up0>, 3, 4]
+[<script>, <fun first/0 up0>, 1, <fun second/0 up0>, 3, 4, nil]
+[<script>, <fun first/0 up0>, 1, nil]
+[<script>, <fun first/0 up0>, 1]
+[<script>, <fun first/0 up0>, 1, 2]
+[<script>, <fun first/0 up0>, 1, 2, nil]
 [<script>, nil]
 [<script>]
 [<script>, nil]
@@ -755,7 +885,7 @@ fun sum(a, b, c) {
 print 4 + sum(5, 6, 7);
 "#,
     r#"
-== <fun sum> ==
+== <fun sum/3 up0> ==
 0000 GetLocal            1
 0002 GetLocal            2
 0004 Add
@@ -765,13 +895,13 @@ print 4 + sum(5, 6, 7);
 0009 Nil
 0010 Return
 == <script> ==
-0000 Closure             1 '<fun sum>'
+0000 Closure             1 <fun sum/3 up0>
 0002 DefineGlobal        0 '"sum"'
 0004 Constant            2 '4'
-0006 GetGlobal           3 '"sum"'
-0008 Constant            4 '5'
-0010 Constant            5 '6'
-0012 Constant            6 '7'
+0006 GetGlobal           0 '"sum"'
+0008 Constant            3 '5'
+0010 Constant            4 '6'
+0012 Constant            5 '7'
 0014 Call                3
 0016 Add
 0017 Print
@@ -782,19 +912,19 @@ print 4 + sum(5, 6, 7);
     r#"
 == VM Stack Snapshot ==
 [<script>]
-[<script>, <fun sum>]
+[<script>, <fun sum/3 up0>]
 [<script>]
 [<script>, 4]
-[<script>, 4, <fun sum>]
-[<script>, 4, <fun sum>, 5]
-[<script>, 4, <fun sum>, 5, 6]
-[<script>, 4, <fun sum>, 5, 6, 7]
-[<script>, 4, <fun sum>, 5, 6, 7]
-[<script>, 4, <fun sum>, 5, 6, 7, 5]
-[<script>, 4, <fun sum>, 5, 6, 7, 5, 6]
-[<script>, 4, <fun sum>, 5, 6, 7, 11]
-[<script>, 4, <fun sum>, 5, 6, 7, 11, 7]
-[<script>, 4, <fun sum>, 5, 6, 7, 18]
+[<script>, 4, <fun sum/3 up0>]
+[<script>, 4, <fun sum/3 up0>, 5]
+[<script>, 4, <fun sum/3 up0>, 5, 6]
+[<script>, 4, <fun sum/3 up0>, 5, 6, 7]
+[<script>, 4, <fun sum/3 up0>, 5, 6, 7]
+[<script>, 4, <fun sum/3 up0>, 5, 6, 7, 5]
+[<script>, 4, <fun sum/3 up0>, 5, 6, 7, 5, 6]
+[<script>, 4, <fun sum/3 up0>, 5, 6, 7, 11]
+[<script>, 4, <fun sum/3 up0>, 5, 6, 7, 11, 7]
+[<script>, 4, <fun sum/3 up0>, 5, 6, 7, 18]
 [<script>, 4, 18]
 [<script>, 22]
 [<script>]
@@ -822,7 +952,7 @@ fun outer() {
 }
 "#,
     r#"
-== <fun inner> ==
+== <fun inner/0 up4> ==
 0000 GetUpvalue          0
 0002 GetUpvalue          1
 0004 Add
@@ -833,26 +963,26 @@ fun outer() {
 0011 Print
 0012 Nil
 0013 Return
-== <fun middle> ==
+== <fun middle/0 up2> ==
 0000 Constant            0 '3'
 0002 Constant            1 '4'
-0004 Closure             2 <fun inner>
+0004 Closure             2 <fun inner/0 up4>
 0006 |                     upvalue 0
 0008 |                     local 1
 0010 |                     upvalue 1
 0012 |                     local 2
 0014 Nil
 0015 Return
-== <fun outer> ==
+== <fun outer/0 up0> ==
 0000 Constant            0 '1'
 0002 Constant            1 '2'
-0004 Closure             2 <fun middle>
+0004 Closure             2 <fun middle/0 up2>
 0006 |                     local 1
 0008 |                     local 2
 0010 Nil
 0011 Return
 == <script> ==
-0000 Closure             1 <fun outer>
+0000 Closure             1 <fun outer/0 up0>
 0002 DefineGlobal        0 '"outer"'
 0004 Nil
 0005 Return
@@ -861,7 +991,7 @@ fun outer() {
     r#"
 == VM Stack Snapshot ==
 [<script>]
-[<script>, <fun outer>]
+[<script>, <fun outer/0 up0>]
 [<script>]
 [<script>, nil]
 
@@ -884,14 +1014,14 @@ fun outer() {
 outer();
 "#,
     r#"
-== <fun inner> ==
+== <fun inner/0 up1> ==
 0000 GetUpvalue          0
 0002 Print
 0003 Nil
 0004 Return
-== <fun outer> ==
+== <fun outer/0 up0> ==
 0000 Constant            0 '"outside"'
-0002 Closure             1 <fun inner>
+0002 Closure             1 <fun inner/0 up1>
 0004 |                     local 1
 0006 GetLocal            2
 0008 Call                0
@@ -899,9 +1029,9 @@ outer();
 0011 Nil
 0012 Return
 == <script> ==
-0000 Closure             1 <fun outer>
+0000 Closure             1 <fun outer/0 up0>
 0002 DefineGlobal        0 '"outer"'
-0004 GetGlobal           2 '"outer"'
+0004 GetGlobal           0 '"outer"'
 0006 Call                0
 0008 Pop
 0009 Nil
@@ -911,21 +1041,209 @@ outer();
     r#"
 == VM Stack Snapshot ==
 [<script>]
-[<script>, <fun outer>]
+[<script>, <fun outer/0 up0>]
+[<script>]
+[<script>, <fun outer/0 up0>]
+[<script>, <fun outer/0 up0>]
+[<script>, <fun outer/0 up0>, "outside"]
+[<script>, <fun outer/0 up0>, "outside", <fun inner/0 up1>]
+[<script>, <fun outer/0 up0>, "outside", <fun inner/0 up1>, <fun inner/0 up1>]
+[<script>, <fun outer/0 up0>, "outside", <fun inner/0 up1>, <fun inner/0 up1>]
+[<script>, <fun outer/0 up0>, "outside", <fun inner/0 up1>, <fun inner/0 up1>, "outside"]
+[<script>, <fun outer/0 up0>, "outside", <fun inner/0 up1>, <fun inner/0 up1>]
+[<script>, <fun outer/0 up0>, "outside", <fun inner/0 up1>, <fun inner/0 up1>, nil]
+[<script>, <fun outer/0 up0>, "outside", <fun inner/0 up1>, nil]
+[<script>, <fun outer/0 up0>, "outside", <fun inner/0 up1>]
+[<script>, <fun outer/0 up0>, "outside", <fun inner/0 up1>, nil]
+[<script>, nil]
+[<script>]
+[<script>, nil]
+
+"#
+  );
+}
+
+#[test]
+fn chapter_27_class_declaration() {
+  assert_snapshot!(
+    r#"
+class Brunch {
+  bacon() {
+    return 1;
+  }
+}
+Brunch;
+"#,
+    r#"
+== <fun bacon/0 up0> ==
+0000 Constant            0 '1'
+0002 Return
+0003 Nil
+0004 Return
+== <script> ==
+0000 Class               0 '"Brunch"'
+0002 DefineGlobal        0 '"Brunch"'
+0004 GetGlobal           0 '"Brunch"'
+0006 Closure             2 <fun bacon/0 up0>
+0008 Method              1 '"bacon"'
+0010 Pop
+0011 GetGlobal           0 '"Brunch"'
+0013 Pop
+0014 Nil
+0015 Return
+
+"#,
+    r#"
+== VM Stack Snapshot ==
+[<script>]
+[<script>, <class Brunch>]
+[<script>]
+[<script>, <class Brunch>]
+[<script>, <class Brunch>, <fun bacon/0 up0>]
+[<script>, <class Brunch>]
+[<script>]
+[<script>, <class Brunch>]
+[<script>]
+[<script>, nil]
+
+"#
+  );
+}
+
+#[test]
+fn number_format_matches_printf_g() {
+  assert_snapshot!(
+    r#"
+100000000000000000000;
+0.0001;
+123456789.0;
+"#,
+    r#"
+== <script> ==
+0000 Constant            0 '1e+20'
+0002 Pop
+0003 Constant            1 '0.0001'
+0005 Pop
+0006 Constant            2 '1.23457e+08'
+0008 Pop
+0009 Nil
+0010 Return
+
+"#,
+    r#"
+== VM Stack Snapshot ==
+[<script>]
+[<script>, 1e+20]
+[<script>]
+[<script>, 0.0001]
+[<script>]
+[<script>, 1.23457e+08]
+[<script>]
+[<script>, nil]
+
+"#
+  );
+}
+
+#[test]
+fn chapter_27_instance_fields() {
+  assert_snapshot!(
+    r#"
+class Brunch {}
+var b = Brunch();
+b.x = 3;
+print b.x;
+"#,
+    r#"
+== <script> ==
+0000 Class               0 '"Brunch"'
+0002 DefineGlobal        0 '"Brunch"'
+0004 GetGlobal           0 '"Brunch"'
+0006 Pop
+0007 GetGlobal           0 '"Brunch"'
+0009 Call                0
+0011 DefineGlobal        1 '"b"'
+0013 GetGlobal           1 '"b"'
+0015 Constant            3 '3'
+0017 SetProperty         2 '"x"'
+0019 Pop
+0020 GetGlobal           1 '"b"'
+0022 GetProperty         2 '"x"'
+0024 Print
+0025 Nil
+0026 Return
+
+"#,
+    r#"
+== VM Stack Snapshot ==
+[<script>]
+[<script>, <class Brunch>]
+[<script>]
+[<script>, <class Brunch>]
+[<script>]
+[<script>, <class Brunch>]
+[<script>, <Brunch instance>]
+[<script>]
+[<script>, <Brunch instance>]
+[<script>, <Brunch instance>, 3]
+[<script>, 3]
+[<script>]
+[<script>, <Brunch instance>]
+[<script>, 3]
 [<script>]
-[<script>, <fun outer>]
-[<script>, <fun outer>]
-[<script>, <fun outer>, "outside"]
-[<script>, <fun outer>, "outside", <fun inner>]
-[<script>, <fun outer>, "outside", <fun inner>, <fun inner>]
-[<script>, <fun outer>, "outside", <fun inner>, <fun inner>]
-[<script>, <fun outer>, "outside", <fun inner>, <fun inner>, "outer"]
-[<script>, <fun outer>, "outside", <fun inner>, <fun inner>]
-[<script>, <fun outer>, "outside", <fun inner>, <fun inner>, nil]
-[<script>, <fun outer>, "outside", <fun inner>, nil]
-[<script>, <fun outer>, "outside", <fun inner>]
-[<script>, <fun outer>, "outside", <fun inner>, nil]
 [<script>, nil]
+
+"#
+  );
+}
+
+#[test]
+fn chapter_27_property_on_non_instance() {
+  assert_snapshot!(
+    r#"
+var a = 1;
+print a.x;
+"#,
+    "[line 3] Error: Only instances have properties.\n[line 3] in <script>"
+  );
+}
+
+#[test]
+fn chapter_28_this() {
+  assert_snapshot!(
+    r#"
+class C {
+  get() {
+    return this.v;
+  }
+}
+"#,
+    r#"
+== <fun get/0 up0> ==
+0000 GetLocal            0
+0002 GetProperty         0 '"v"'
+0004 Return
+0005 Nil
+0006 Return
+== <script> ==
+0000 Class               0 '"C"'
+0002 DefineGlobal        0 '"C"'
+0004 GetGlobal           0 '"C"'
+0006 Closure             2 <fun get/0 up0>
+0008 Method              1 '"get"'
+0010 Pop
+0011 Nil
+0012 Return
+
+"#,
+    r#"
+== VM Stack Snapshot ==
+[<script>]
+[<script>, <class C>]
+[<script>]
+[<script>, <class C>]
+[<script>, <class C>, <fun get/0 up0>]
+[<script>, <class C>]
 [<script>]
 [<script>, nil]
 
@@ -933,4 +1251,3691 @@ outer();
   );
 }
 
+#[test]
+fn chapter_28_this_outside_class() {
+  assert_snapshot!(
+    r#"
+fun notMethod() {
+  print this;
+}
+"#,
+    "[line 3] Error at 'this': Can't use 'this' outside of a class."
+  );
+}
+
+#[test]
+fn chapter_29_inherit_from_class() {
+  assert_snapshot!(
+    r#"
+class A {
+  method() {}
+}
+class B < A {}
+"#,
+    r#"
+== <fun method/0 up0> ==
+0000 Nil
+0001 Return
+== <script> ==
+0000 Class               0 '"A"'
+0002 DefineGlobal        0 '"A"'
+0004 GetGlobal           0 '"A"'
+0006 Closure             2 <fun method/0 up0>
+0008 Method              1 '"method"'
+0010 Pop
+0011 Class               3 '"B"'
+0013 DefineGlobal        3 '"B"'
+0015 GetGlobal           0 '"A"'
+0017 GetGlobal           3 '"B"'
+0019 Inherit
+0020 GetGlobal           3 '"B"'
+0022 Pop
+0023 Pop
+0024 Nil
+0025 Return
+
+"#,
+    r#"
+== VM Stack Snapshot ==
+[<script>]
+[<script>, <class A>]
+[<script>]
+[<script>, <class A>]
+[<script>, <class A>, <fun method/0 up0>]
+[<script>, <class A>]
+[<script>]
+[<script>, <class B>]
+[<script>]
+[<script>, <class A>]
+[<script>, <class A>, <class B>]
+[<script>, <class A>]
+[<script>, <class A>, <class B>]
+[<script>, <class A>]
+[<script>]
+[<script>, nil]
+
+"#
+  );
+}
 
+#[test]
+fn chapter_29_inherit_from_non_class() {
+  assert_snapshot!(
+    r#"
+var NotAClass = 1;
+class B < NotAClass {}
+"#,
+    "[line 3] Error: Superclass must be a class.\n[line 3] in <script>"
+  );
+}
+
+#[test]
+fn chapter_29_inherit_from_itself() {
+  assert_snapshot!(
+    r#"class Oops < Oops {}"#,
+    "[line 1] Error at 'Oops': A class can't inherit from itself."
+  );
+}
+
+#[test]
+fn chapter_28_bound_method() {
+  assert_snapshot!(
+    r#"
+class C {
+  tell() {
+    return this.n;
+  }
+}
+var c = C();
+c.n = 5;
+var f = c.tell;
+print f();
+"#,
+    r#"
+== <fun tell/0 up0> ==
+0000 GetLocal            0
+0002 GetProperty         0 '"n"'
+0004 Return
+0005 Nil
+0006 Return
+== <script> ==
+0000 Class               0 '"C"'
+0002 DefineGlobal        0 '"C"'
+0004 GetGlobal           0 '"C"'
+0006 Closure             2 <fun tell/0 up0>
+0008 Method              1 '"tell"'
+0010 Pop
+0011 GetGlobal           0 '"C"'
+0013 Call                0
+0015 DefineGlobal        3 '"c"'
+0017 GetGlobal           3 '"c"'
+0019 Constant            5 '5'
+0021 SetProperty         4 '"n"'
+0023 Pop
+0024 GetGlobal           3 '"c"'
+0026 GetProperty         1 '"tell"'
+0028 DefineGlobal        6 '"f"'
+0030 GetGlobal           6 '"f"'
+0032 Call                0
+0034 Print
+0035 Nil
+0036 Return
+
+"#,
+    r#"
+== VM Stack Snapshot ==
+[<script>]
+[<script>, <class C>]
+[<script>]
+[<script>, <class C>]
+[<script>, <class C>, <fun tell/0 up0>]
+[<script>, <class C>]
+[<script>]
+[<script>, <class C>]
+[<script>, <C instance>]
+[<script>]
+[<script>, <C instance>]
+[<script>, <C instance>, 5]
+[<script>, 5]
+[<script>]
+[<script>, <C instance>]
+[<script>, <fun tell/0 up0>]
+[<script>]
+[<script>, <fun tell/0 up0>]
+[<script>, <C instance>]
+[<script>, <C instance>, <C instance>]
+[<script>, <C instance>, 5]
+[<script>, 5]
+[<script>]
+[<script>, nil]
+
+"#
+  );
+}
+
+#[test]
+fn chapter_28_method_call() {
+  assert_snapshot!(
+    r#"
+class C {
+  get() {
+    return this.v;
+  }
+}
+var c = C();
+c.v = 9;
+print c.get();
+"#,
+    r#"
+== <fun get/0 up0> ==
+0000 GetLocal            0
+0002 GetProperty         0 '"v"'
+0004 Return
+0005 Nil
+0006 Return
+== <script> ==
+0000 Class               0 '"C"'
+0002 DefineGlobal        0 '"C"'
+0004 GetGlobal           0 '"C"'
+0006 Closure             2 <fun get/0 up0>
+0008 Method              1 '"get"'
+0010 Pop
+0011 GetGlobal           0 '"C"'
+0013 Call                0
+0015 DefineGlobal        3 '"c"'
+0017 GetGlobal           3 '"c"'
+0019 Constant            5 '9'
+0021 SetProperty         4 '"v"'
+0023 Pop
+0024 GetGlobal           3 '"c"'
+0026 GetProperty         1 '"get"'
+0028 Call                0
+0030 Print
+0031 Nil
+0032 Return
+
+"#,
+    r#"
+== VM Stack Snapshot ==
+[<script>]
+[<script>, <class C>]
+[<script>]
+[<script>, <class C>]
+[<script>, <class C>, <fun get/0 up0>]
+[<script>, <class C>]
+[<script>]
+[<script>, <class C>]
+[<script>, <C instance>]
+[<script>]
+[<script>, <C instance>]
+[<script>, <C instance>, 9]
+[<script>, 9]
+[<script>]
+[<script>, <C instance>]
+[<script>, <fun get/0 up0>]
+[<script>, <C instance>]
+[<script>, <C instance>, <C instance>]
+[<script>, <C instance>, 9]
+[<script>, 9]
+[<script>]
+[<script>, nil]
+
+"#
+  );
+}
+
+#[test]
+fn pretty_print_nested_instances() {
+  let vm = run(
+    r#"
+class Node {}
+var root = Node();
+root.name = "root";
+root.child = Node();
+root.child.value = 1;
+root.child.parent = root;
+root.leaf = Node();
+prettyPrint(root);
+"#,
+  );
+  assert_eq!(
+    global(&vm, "root").pretty(2),
+    r#"<Node instance> {
+  child: <Node instance> {
+    parent: <cycle>,
+    value: 1
+  },
+  leaf: <Node instance> {},
+  name: "root"
+}"#
+  );
+}
+
+#[test]
+fn chapter_29_super() {
+  assert_snapshot!(
+    r#"
+class Doughnut {
+  finish() {
+    print "Doughnut finish";
+  }
+}
+
+class Cruller < Doughnut {
+  finish() {
+    super.finish();
+    print "Cruller finish";
+  }
+}
+
+Cruller().finish();
+"#,
+    r#"
+== <fun finish/0 up0> ==
+0000 Constant            0 '"Doughnut finish"'
+0002 Print
+0003 Nil
+0004 Return
+== <fun finish/0 up1> ==
+0000 GetLocal            0
+0002 GetUpvalue          0
+0004 GetSuper            0 '"finish"'
+0006 Call                0
+0008 Pop
+0009 Constant            1 '"Cruller finish"'
+0011 Print
+0012 Nil
+0013 Return
+== <script> ==
+0000 Class               0 '"Doughnut"'
+0002 DefineGlobal        0 '"Doughnut"'
+0004 GetGlobal           0 '"Doughnut"'
+0006 Closure             2 <fun finish/0 up0>
+0008 Method              1 '"finish"'
+0010 Pop
+0011 Class               3 '"Cruller"'
+0013 DefineGlobal        3 '"Cruller"'
+0015 GetGlobal           0 '"Doughnut"'
+0017 GetGlobal           3 '"Cruller"'
+0019 Inherit
+0020 GetGlobal           3 '"Cruller"'
+0022 Closure             4 <fun finish/0 up1>
+0024 |                     local 1
+0026 Method              1 '"finish"'
+0028 Pop
+0029 CloseUpvalue
+0030 GetGlobal           3 '"Cruller"'
+0032 Call                0
+0034 GetProperty         1 '"finish"'
+0036 Call                0
+0038 Pop
+0039 Nil
+0040 Return
+
+"#,
+    r#"
+== VM Stack Snapshot ==
+[<script>]
+[<script>, <class Doughnut>]
+[<script>]
+[<script>, <class Doughnut>]
+[<script>, <class Doughnut>, <fun finish/0 up0>]
+[<script>, <class Doughnut>]
+[<script>]
+[<script>, <class Cruller>]
+[<script>]
+[<script>, <class Doughnut>]
+[<script>, <class Doughnut>, <class Cruller>]
+[<script>, <class Doughnut>]
+[<script>, <class Doughnut>, <class Cruller>]
+[<script>, <class Doughnut>, <class Cruller>, <fun finish/0 up1>]
+[<script>, <class Doughnut>, <class Cruller>]
+[<script>, <class Doughnut>]
+[<script>]
+[<script>, <class Cruller>]
+[<script>, <Cruller instance>]
+[<script>, <fun finish/0 up1>]
+[<script>, <Cruller instance>]
+[<script>, <Cruller instance>, <Cruller instance>]
+[<script>, <Cruller instance>, <Cruller instance>, <class Doughnut>]
+[<script>, <Cruller instance>, <fun finish/0 up0>]
+[<script>, <Cruller instance>, <Cruller instance>]
+[<script>, <Cruller instance>, <Cruller instance>, "Doughnut finish"]
+[<script>, <Cruller instance>, <Cruller instance>]
+[<script>, <Cruller instance>, <Cruller instance>, nil]
+[<script>, <Cruller instance>, nil]
+[<script>, <Cruller instance>]
+[<script>, <Cruller instance>, "Cruller finish"]
+[<script>, <Cruller instance>]
+[<script>, <Cruller instance>, nil]
+[<script>, nil]
+[<script>]
+[<script>, nil]
+
+"#
+  );
+}
+
+#[test]
+fn chapter_29_inherit_methods() {
+  let vm = run(
+    r#"
+class A {
+  method() {
+    return "A method";
+  }
+}
+class B < A {}
+var result = B().method();
+"#,
+  );
+  check(
+    &global(&vm, "result"),
+    expect![[r#"
+        "A method"
+    "#]],
+  );
+}
+
+#[test]
+fn chapter_29_super_without_superclass() {
+  assert_snapshot!(
+    r#"
+class A {
+  method() {
+    super.method();
+  }
+}
+"#,
+    "[line 4] Error at 'super': Can't use 'super' in a class with no superclass."
+  );
+}
+
+#[test]
+fn global_used_before_assignment() {
+  assert_snapshot!(
+    r#"
+var a;
+print a;
+"#,
+    "[line 3] Error: Variable 'a' used before assignment.\n[line 3] in <script>"
+  );
+}
+
+#[test]
+fn global_assigned_nil() {
+  let vm = run(
+    r#"
+var a = nil;
+var b;
+b = a;
+print a;
+print b;
+"#,
+  );
+  check(
+    &global(&vm, "b"),
+    expect![[r#"
+        nil
+    "#]],
+  );
+}
+
+#[test]
+fn assert_statement_passes() {
+  assert_snapshot!(
+    r#"
+assert 1 < 2;
+assert true : "never shown";
+"#,
+    r#"
+== <script> ==
+0000 Constant            0 '1'
+0002 Constant            1 '2'
+0004 Less
+0005 Nil
+0006 Assert
+0007 True
+0008 Constant            2 '"never shown"'
+0010 Assert
+0011 Nil
+0012 Return
+
+"#,
+    r#"
+== VM Stack Snapshot ==
+[<script>]
+[<script>, 1]
+[<script>, 1, 2]
+[<script>, true]
+[<script>, true, nil]
+[<script>]
+[<script>, true]
+[<script>, true, "never shown"]
+[<script>]
+[<script>, nil]
+
+"#
+  );
+}
+
+#[test]
+fn assert_statement_fails() {
+  assert_snapshot!(
+    r#"assert 1 > 2;"#,
+    "[line 1] Error: Assertion failed.\n[line 1] in <script>"
+  );
+}
+
+#[test]
+fn assert_statement_fails_on_its_line() {
+  assert_snapshot!(
+    r#"
+assert 1 < 2;
+fun check() {
+  assert false;
+}
+check();
+"#,
+    "[line 4] Error: Assertion failed.\n[line 4] in <fun check>\n[line 6] in <script>"
+  );
+}
+
+#[test]
+fn assert_statement_fails_with_message() {
+  assert_snapshot!(
+    r#"assert nil : "expected a value";"#,
+    "[line 1] Error: expected a value\n[line 1] in <script>"
+  );
+}
+
+#[test]
+fn call_number() {
+  assert_snapshot!(
+    r#"1();"#,
+    "[line 1] Error: Can only call functions and classes, got number.\n[line 1] in <script>"
+  );
+}
+
+#[test]
+fn call_nil() {
+  assert_snapshot!(
+    r#"
+var f;
+f = nil;
+f();
+"#,
+    "[line 4] Error: Can only call functions and classes, got nil.\n[line 4] in <script>"
+  );
+}
+
+#[test]
+fn call_string() {
+  assert_snapshot!(
+    r#""not a function"();"#,
+    "[line 1] Error: Can only call functions and classes, got string.\n[line 1] in <script>"
+  );
+}
+
+#[test]
+fn call_instance() {
+  assert_snapshot!(
+    r#"
+class Foo {}
+Foo()();
+"#,
+    "[line 3] Error: Can only call functions and classes, got instance.\n[line 3] in <script>"
+  );
+}
+
+#[test]
+fn closures_do_not_write_to_stderr() {
+  let (stdout, stderr) = run_binary(
+    "closures_do_not_write_to_stderr",
+    r#"
+fun outer() {
+  var a = 1;
+  var b = 2;
+  fun middle() {
+    var c = 3;
+    fun inner() {
+      return a + b + c;
+    }
+    return inner();
+  }
+  return middle();
+}
+var i = 0;
+while (i < 3) {
+  print outer();
+  i = i + 1;
+}
+"#,
+  );
+  assert_eq!(stderr, "");
+  assert_eq!(stdout, "6\n6\n6\n");
+}
+
+#[test]
+fn inspector_shows_source_lines() {
+  let source = r#"
+var greeting = "hi";
+print greeting;
+fun twice(n) {
+  return n * 2;
+}
+print twice(2);
+"#;
+  let scanner = Scanner::new(source);
+  let mut parser = Parser::new(scanner, Some(Inspector::with_source(source)));
+  parser.advance().unwrap();
+  parser.program().unwrap();
+  parser.end_compiler();
+  let inspector = parser.into_inspector().unwrap();
+  check(
+    &inspector.debug_bytecode(),
+    expect![[r#"
+      == <fun twice/1 up0> ==
+      [line 5] return n * 2;
+      0000 GetLocal            1
+      0002 Constant            0 '2'
+      0004 Multiply
+      0005 Return
+      [line 6] }
+      0006 Nil
+      0007 Return
+      == <script> ==
+      [line 2] var greeting = "hi";
+      0000 Constant            1 '"hi"'
+      0002 DefineGlobal        0 '"greeting"'
+      [line 3] print greeting;
+      0004 GetGlobal           0 '"greeting"'
+      0006 Print
+      [line 6] }
+      0007 Closure             3 <fun twice/1 up0>
+      0009 DefineGlobal        2 '"twice"'
+      [line 7] print twice(2);
+      0011 GetGlobal           2 '"twice"'
+      0013 Constant            4 '2'
+      0015 Call                1
+      0017 Print
+      0018 Nil
+      0019 Return
+
+  "#]],
+  );
+}
+
+#[test]
+fn runtime_error_reports_line() {
+  assert_snapshot!(
+    r#"
+var a = 1;
+fun add(b) {
+  return a + b;
+}
+add(nil);
+"#,
+    "[line 4] Error: Operands must be two numbers or two strings.\n[line 4] in <fun add>\n[line 6] in <script>"
+  );
+}
+
+#[test]
+fn runtime_error_traces_nested_calls() {
+  assert_snapshot!(
+    r#"
+class Box {
+  open() {
+    return inner(1);
+  }
+}
+fun inner(n) {
+  return n + nil;
+}
+fun outer() {
+  return Box().open();
+}
+outer();
+"#,
+    "[line 8] Error: Operands must be two numbers or two strings.\n[line 8] in <fun inner>\n[line 4] in <fun open>\n[line 11] in <fun outer>\n[line 13] in <script>"
+  );
+}
+
+#[test]
+fn stack_overflow_names_recursive_calls() {
+  assert_snapshot!(
+    r#"
+fun isEven(n) { return isOdd(n + 1); }
+fun isOdd(n) { return isEven(n + 1); }
+isEven(0);
+"#,
+    "[line 2] Error: Stack overflow. Recent calls: isOdd <- isEven <- isOdd <- isEven <- isOdd <- ...\n[line 2] in <fun isEven>\n[line 3] in <fun isOdd>\n[line 2] in <fun isEven>\n[line 3] in <fun isOdd>\n[line 2] in <fun isEven>\n[line 3] in <fun isOdd>\n[line 2] in <fun isEven>\n[line 3] in <fun isOdd>\n[line 2] in <fun isEven>\n[line 3] in <fun isOdd>\n[246 more calls]"
+  );
+}
+
+#[test]
+fn max_frames_limits_recursion() {
+  let source = r#"
+fun count(n) {
+  if (n == 0) return 0;
+  return 1 + count(n - 1);
+}
+print count(200);
+"#;
+  assert_eq!(interpret_to_string(source), Ok("200\n".to_owned()));
+
+  let mut vm =
+    VM::from_closure(compile("fun f() { return f(); }\nf();").unwrap())
+      .unwrap();
+  vm.max_frames = 10;
+  let error = vm.run(None).err().unwrap().to_string();
+  assert_eq!(
+    error.lines().next(),
+    Some("[line 1] Error: Stack overflow. Recent calls: f <- f <- f <- f <- f <- ...")
+  );
+  // A line in the trace for each frame.
+  assert_eq!(error.lines().count(), 11);
+  assert_eq!(vm.frames.len() + 1, 10);
+}
+
+#[test]
+fn jump_offsets_are_big_endian() {
+  let mut chunk = Chunk::new();
+  let jump = chunk.emit_jump(Op::Jump).unwrap();
+  for _ in 0..300 {
+    chunk.emit_op(Op::Nil);
+  }
+  chunk.patch_jump(jump).unwrap();
+
+  // The encoding must not depend on the host: the high byte always comes
+  // first, so a little-endian read of the same bytes gives a different value.
+  let bytes = [chunk.codes[1], chunk.codes[2]];
+  assert_eq!(bytes, 300u16.to_be_bytes());
+  assert_eq!(u16::from_be_bytes(bytes), 300);
+  assert_ne!(u16::from_le_bytes(bytes), 300);
+  assert_eq!(chunk.read_short(1), 300);
+
+  chunk.emit_loop(0).unwrap();
+  let len = chunk.codes.len();
+  assert_eq!(chunk.read_short(len - 2), len as u16);
+}
+
+#[test]
+fn break_and_continue() {
+  let (stdout, _) = run_binary(
+    "break_and_continue",
+    r#"
+for (var i = 0; i < 5; i = i + 1) {
+  if (i == 3) break;
+  print i;
+}
+var i = 0;
+while (i < 5) {
+  i = i + 1;
+  if (i == 2 or i == 4) continue;
+  print i;
+}
+"#,
+  );
+  expect![[r#"
+      0
+      1
+      2
+      1
+      3
+      5
+  "#]]
+  .assert_eq(&stdout);
+}
+
+#[test]
+fn continue_outside_loop() {
+  assert_snapshot!(
+    "fun f() { continue; }",
+    "[line 1] Error at 'continue': Can't use 'continue' outside of a loop."
+  );
+}
+
+#[test]
+fn modulo() {
+  let vm = run(
+    r#"
+var a = 7 % 3;
+var b = -7 % 3;
+var c = 1 + 7 % 3 * 2;
+// Follows f64 remainder outside strict math: no error, just NaN.
+var d = 5 % 0;
+"#,
+  );
+  check(
+    &global(&vm, "a"),
+    expect![[r#"
+      1
+  "#]],
+  );
+  check(
+    &global(&vm, "b"),
+    expect![[r#"
+      -1
+  "#]],
+  );
+  check(
+    &global(&vm, "c"),
+    expect![[r#"
+      3
+  "#]],
+  );
+  check(
+    &global(&vm, "d"),
+    expect![[r#"
+      nan
+  "#]],
+  );
+}
+
+#[test]
+fn modulo_non_number() {
+  assert_snapshot!(
+    r#"print "a" % 2;"#,
+    "[line 1] Error: Operand must be a number.\n[line 1] in <script>"
+  );
+}
+
+#[test]
+fn division_by_zero_in_strict_math() {
+  let divide = |source: &str, strict: bool| {
+    let closure = compile(&format!("var a = {};", source)).unwrap();
+    let mut vm = VM::from_closure(closure).unwrap().with_strict_math(strict);
+    match vm.run(None) {
+      Ok(_) => Ok(global(&vm, "a").to_string()),
+      Err(error) => Err(error.to_string()),
+    }
+  };
+  assert_eq!(divide("5 % 0", false), Ok("nan".to_owned()));
+  assert_eq!(divide("5 / 0", false), Ok("inf".to_owned()));
+  let error = "[line 1] Error: Division by zero.\n[line 1] in <script>";
+  assert_eq!(divide("5 % 0", true), Err(error.to_owned()));
+  assert_eq!(divide("5 / 0", true), Err(error.to_owned()));
+  assert_eq!(divide("5.5 % 0.0", true), Err(error.to_owned()));
+  assert_eq!(divide("7 % 3", true), Ok("1".to_owned()));
+  assert_eq!(divide("7 / 2", true), Ok("3.5".to_owned()));
+}
+
+#[test]
+fn misspelled_keyword_suggestion() {
+  assert_snapshot!(
+    r#"
+fun f(x) {
+  retrun x;
+}
+"#,
+    "[line 3] Error at 'retrun': Unknown name 'retrun'; did you mean 'return'?"
+  );
+}
+
+#[test]
+fn short_names_get_no_suggestion() {
+  assert_snapshot!(
+    r#"
+var f = 1;
+print y;
+"#,
+    "[line 3] Error: Undefined variable 'y'.\n[line 3] in <script>"
+  );
+}
+
+#[test]
+fn misspelled_global_suggestion() {
+  assert_snapshot!(
+    r#"
+var count = 1;
+print cuont;
+"#,
+    "[line 3] Error: Undefined variable 'cuont'; did you mean 'count'?\n[line 3] in <script>"
+  );
+}
+
+#[test]
+fn conditional_expression() {
+  let vm = run(
+    r#"
+var a = (1 < 2) ? "y" : "n";
+var b = false ? 1 : nil ? 2 : 3;
+var c = true ? false ? 1 : 2 : 3;
+var d;
+d = 1 > 2 ? "big" : 0;
+"#,
+  );
+  check(
+    &global(&vm, "a"),
+    expect![[r#"
+      "y"
+  "#]],
+  );
+  check(
+    &global(&vm, "b"),
+    expect![[r#"
+      3
+  "#]],
+  );
+  check(
+    &global(&vm, "c"),
+    expect![[r#"
+      2
+  "#]],
+  );
+  check(
+    &global(&vm, "d"),
+    expect![[r#"
+      0
+  "#]],
+  );
+}
+
+#[test]
+fn conditional_expression_bytecode() {
+  assert_snapshot!(
+    r#"
+var a = true ? 1 : 2;
+"#,
+    r#"
+== <script> ==
+0000 True
+0001 JumpIfFalse         1 -> 10
+0004 Pop
+0005 Constant            1 '1'
+0007 Jump                7 -> 13
+0010 Pop
+0011 Constant            2 '2'
+0013 DefineGlobal        0 '"a"'
+0015 Nil
+0016 Return
+
+"#,
+    r#"
+== VM Stack Snapshot ==
+[<script>]
+[<script>, true]
+[<script>, true]
+[<script>]
+[<script>, 1]
+[<script>, 1]
+[<script>]
+[<script>, nil]
+
+"#
+  );
+}
+
+#[test]
+fn conditional_expression_missing_colon() {
+  assert_snapshot!(
+    "var a = true ? 1;",
+    "[line 1] Error at ';': Expect ':' after then branch."
+  );
+}
+
+#[test]
+fn global_set_hook() {
+  let scanner = Scanner::new(
+    r#"
+var x;
+x = 1;
+var y = x + 1;
+"#,
+  );
+  let mut parser = Parser::new(scanner, None);
+  parser.advance().unwrap();
+  parser.program().unwrap();
+  let (f, _) = parser.end_compiler();
+  let mut vm = VM::from_closure(f).unwrap();
+  let writes = Rc::new(RefCell::new(Vec::new()));
+  let sink = Rc::clone(&writes);
+  vm.on_global_set(move |name, value| {
+    sink.borrow_mut().push((name.to_owned(), value.clone()))
+  });
+  vm.run(None).unwrap();
+  check(
+    &writes.borrow(),
+    expect![[r#"
+        [
+            (
+                "x",
+                1,
+            ),
+            (
+                "y",
+                2,
+            ),
+        ]
+    "#]],
+  );
+}
+
+#[test]
+fn print_uses_display_form() {
+  let (stdout, _) = run_binary(
+    "print_uses_display_form",
+    r#"
+class Point {
+  sum() {}
+}
+fun f() {}
+var p = Point();
+print "hi";
+print 1.5;
+print nil;
+print true;
+print f;
+print Point;
+print p;
+print p.sum;
+print prettyPrint;
+"#,
+  );
+  expect![[r#"
+      hi
+      1.5
+      nil
+      true
+      <fn f>
+      Point
+      Point instance
+      <fn sum>
+      <native fn>
+  "#]]
+  .assert_eq(&stdout);
+}
+
+#[test]
+fn closures_without_upvalues_are_shared() {
+  let vm = run(
+    r#"
+var first = nil;
+var shared = true;
+for (var i = 0; i < 3; i = i + 1) {
+  fun f() { return 1; }
+  if (first == nil) first = f;
+  shared = shared and f == first;
+}
+
+var captured = true;
+var last = nil;
+for (var i = 0; i < 3; i = i + 1) {
+  var n = i;
+  fun g() { return n; }
+  captured = captured and last != g;
+  last = g;
+}
+"#,
+  );
+  check(
+    &global(&vm, "shared"),
+    expect![[r#"
+      true
+  "#]],
+  );
+  check(
+    &global(&vm, "captured"),
+    expect![[r#"
+      true
+  "#]],
+  );
+}
+
+#[test]
+fn scanner_is_linear() {
+  let source = "var a = 1 + 2; // some padding\n".repeat(3300);
+  assert!(source.len() > 100_000);
+  let start = Instant::now();
+  let mut scanner = Scanner::new(&source);
+  let mut count = 0;
+  while scanner.scan_token().unwrap().is_some() {
+    count += 1;
+  }
+  assert_eq!(count, 7 * 3300);
+  assert!(start.elapsed() < Duration::from_secs(2));
+}
+
+#[test]
+fn globals_are_looked_up_by_name_once_per_load() {
+  let source = r#"
+var total = 0;
+fun add(n) { total = total + n; }
+for (var i = 0; i < 100000; i = i + 1) {
+  add(i);
+}
+"#;
+  let mut vm = VM::from_closure(compile(source).unwrap()).unwrap();
+  // `total` and `add` in the script, and `total` again in `add`.
+  assert_eq!(vm.name_lookups, 3);
+  let start = Instant::now();
+  vm.run(None).unwrap();
+  assert!(start.elapsed() < Duration::from_secs(5));
+  assert_eq!(vm.name_lookups, 3);
+  assert!(matches!(global(&vm, "total"), Value::Int(4999950000)));
+}
+
+#[test]
+fn scanner_non_ascii_string() {
+  let vm = run(r#"var s = "héllo wörld";"#);
+  check(
+    &global(&vm, "s"),
+    expect![[r#"
+      "héllo wörld"
+  "#]],
+  );
+}
+
+#[test]
+fn adjacent_string_literals() {
+  assert_snapshot!(
+    r#"
+var s = "a" "b"
+  "c";
+"#,
+    r#"
+== <script> ==
+0000 Constant            1 '"abc"'
+0002 DefineGlobal        0 '"s"'
+0004 Nil
+0005 Return
+
+"#,
+    r#"
+== VM Stack Snapshot ==
+[<script>]
+[<script>, "abc"]
+[<script>]
+[<script>, nil]
+
+"#
+  );
+}
+
+#[test]
+fn closed_upvalue_outlives_its_frame() {
+  let vm = run(
+    r#"
+fun makeCounter() {
+  var count = 0;
+  fun counter() {
+    count = count + 1;
+    return count;
+  }
+  return counter;
+}
+var counter = makeCounter();
+var other = makeCounter();
+counter();
+counter();
+var a = counter();
+var b = other();
+"#,
+  );
+  check(
+    &global(&vm, "a"),
+    expect![[r#"
+      3
+  "#]],
+  );
+  check(
+    &global(&vm, "b"),
+    expect![[r#"
+      1
+  "#]],
+  );
+}
+
+#[test]
+fn string_literal_concat_is_folded() {
+  assert_snapshot!(
+    r#"
+var a = "a" + "b" + "c";
+var b = a + "b";
+"#,
+    r#"
+== <script> ==
+0000 Constant            1 '"abc"'
+0002 DefineGlobal        0 '"a"'
+0004 GetGlobal           0 '"a"'
+0006 Constant            2 '"b"'
+0008 Add
+0009 DefineGlobal        2 '"b"'
+0011 Nil
+0012 Return
+
+"#,
+    r#"
+== VM Stack Snapshot ==
+[<script>]
+[<script>, "abc"]
+[<script>]
+[<script>, "abc"]
+[<script>, "abc", "b"]
+[<script>, "abcb"]
+[<script>]
+[<script>, nil]
+
+"#
+  );
+}
+
+#[test]
+fn interpret_closure_keeps_globals() {
+  let mut vm = VM::new();
+  for line in [
+    "var x = 1;",
+    "fun next() { x = x + 1; return x; }",
+    "print missing;",
+    "var y = next();",
+  ] {
+    if let Ok(f) = compile(line) {
+      let _ = vm.interpret_closure(f);
+    }
+  }
+  check(
+    &global(&vm, "x"),
+    expect![[r#"
+      2
+  "#]],
+  );
+  check(
+    &global(&vm, "y"),
+    expect![[r#"
+      2
+  "#]],
+  );
+}
+
+#[test]
+fn repl_keeps_globals_between_lines() {
+  let mut child = Command::new(env!("CARGO_BIN_EXE_clox-rs"))
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .spawn()
+    .unwrap();
+  child
+    .stdin
+    .take()
+    .unwrap()
+    .write_all(b"var x = 1;\nfun f() { return x + 1; }\nprint f();\n")
+    .unwrap();
+  let output = child.wait_with_output().unwrap();
+  let stdout = String::from_utf8(output.stdout).unwrap();
+  expect![[r#"
+      > > > 2
+      > 
+  "#]]
+  .assert_eq(&stdout);
+}
+
+#[test]
+fn expect_number_message() {
+  assert_eq!(
+    Value::string("a").expect_number(),
+    Err("Operand must be a number.".to_owned())
+  );
+  assert_eq!(Value::number(1.0).expect_number(), Ok(1.0));
+  assert_snapshot!(
+    r#"print -"a";"#,
+    "[line 1] Error: Operand must be a number.\n[line 1] in <script>"
+  );
+}
+
+#[test]
+fn interpret_to_string_collects_output() {
+  let output = interpret_to_string(
+    r#"
+print "a";
+prettyPrint(1);
+print 2;
+"#,
+  );
+  check(
+    &output,
+    expect![[r#"
+      Ok(
+          "a\n1\n2\n",
+      )
+  "#]],
+  );
+}
+
+#[test]
+fn print_terminator() {
+  let source = r#"
+print 1;
+print "two";
+"#;
+  for terminator in ["", ", "] {
+    let output = OutputBuffer::new();
+    let mut vm = VM::from_closure(compile(source).unwrap())
+      .unwrap()
+      .with_output(output.clone())
+      .with_print_terminator(terminator.to_owned());
+    vm.run(None).unwrap();
+    assert_eq!(output.contents(), format!("1{0}two{0}", terminator));
+  }
+}
+
+#[test]
+fn input_reads_lines() {
+  let source = r#"
+var name = input();
+print name;
+print input();
+print input();
+"#;
+  let output = OutputBuffer::new();
+  let mut vm = VM::from_closure(compile(source).unwrap())
+    .unwrap()
+    .with_output(output.clone())
+    .with_input(Cursor::new("Lox\r\nlast"));
+  vm.run(None).unwrap();
+  assert_eq!(output.contents(), "Lox\nlast\nnil\n");
+}
+
+#[test]
+fn conversion_natives() {
+  let output = interpret_to_string(
+    r#"
+print number("42") + 1;
+print number("  3.5 ");
+print number("abc");
+print number("");
+print number(7);
+print string(1.5) + "!";
+print string(nil) + string(true) + string([1, "a"]);
+print len(string(100));
+"#,
+  );
+  assert_eq!(
+    output,
+    Ok("43\n3.5\nnil\nnil\n7\n1.5!\nniltrue[1, \"a\"]\n3\n".to_owned())
+  );
+}
+
+#[test]
+fn default_parameters() {
+  let output = interpret_to_string(
+    r#"
+fun greet(name, greeting = "hi") {
+  return greeting + " " + name;
+}
+print greet("Lox");
+print greet("Lox", "yo");
+
+fun range(from, to = from + 3, step = 1) {
+  return [from, to, step];
+}
+print range(1);
+print range(1, 2);
+print range(1, 2, 3);
+
+class Counter {
+  add(n = 1) { return n + 10; }
+}
+print Counter().add();
+print Counter().add(5);
+print (fun (a = "lambda") { return a; })();
+"#,
+  );
+  assert_eq!(
+    output,
+    Ok(
+      "hi Lox\nyo Lox\n[1, 4, 1]\n[1, 2, 1]\n[1, 2, 3]\n11\n15\nlambda\n"
+        .to_owned()
+    )
+  );
+
+  let error = |source: &str| interpret_to_string(source).unwrap_err().remove(0);
+  assert_eq!(
+    error("fun f(a, b = 1) {} f();").to_string(),
+    "[line 1] Error: Expected 1 to 2 arguments but got 0.\n[line 1] in <script>"
+  );
+  assert_eq!(
+    error("fun f(a, b = 1) {} f(1, 2, 3);").to_string(),
+    "[line 1] Error: Expected 1 to 2 arguments but got 3.\n[line 1] in <script>"
+  );
+  assert_eq!(
+    error("fun f(a = 1, b) {}").to_string(),
+    "[line 1] Error at ')': Expect default value after a parameter that has one."
+  );
+  assert_eq!(
+    error("fun f(a = a) {}").to_string(),
+    "[line 1] Error at 'a': Can't read local variable in its own initializer."
+  );
+}
+
+#[test]
+fn rest_parameters() {
+  let output = interpret_to_string(
+    r#"
+fun f(a, ...rest) { return [a, rest]; }
+print f(1, 2, 3);
+print f(1);
+
+fun sum(...numbers) {
+  var total = 0;
+  for (var i = 0; i < len(numbers); i = i + 1) total = total + numbers[i];
+  return total;
+}
+print sum();
+print sum(1, 2, 3, 4);
+print sum(7, ...[5, 6]);
+
+fun g(a, b = "b", ...rest) { return [a, b, rest]; }
+print g("a");
+print g("a", "x", "y");
+"#,
+  );
+  assert_eq!(
+    output,
+    Ok(
+      "[1, [2, 3]]\n[1, []]\n0\n10\n18\n[\"a\", \"b\", []]\n[\"a\", \"x\", [\"y\"]]\n"
+        .to_owned()
+    )
+  );
+  let error = interpret_to_string("fun f(a, ...rest) {} f();").unwrap_err();
+  assert_eq!(
+    error[0].to_string(),
+    "[line 1] Error: Expected at least 1 arguments but got 0.\n[line 1] in <script>"
+  );
+  let error = compile("fun f(...rest, a) {}").unwrap_err();
+  assert_eq!(
+    error[0].to_string(),
+    "[line 1] Error at ',': Expect ')' after parameters."
+  );
+}
+
+#[test]
+fn do_while_loop() {
+  let output = interpret_to_string(
+    r#"
+do print "once"; while (false);
+
+var i = 0;
+do {
+  i = i + 1;
+  if (i == 2) continue;
+  if (i == 5) break;
+  print i;
+} while (i < 10);
+
+var done = 0;
+do { var local = done; done = local + 1; } while (done < 3);
+print done;
+"#,
+  );
+  assert_eq!(output, Ok("once\n1\n3\n4\n3\n".to_owned()));
+  let error = compile("do print 1; (true);").unwrap_err();
+  assert_eq!(
+    error[0].to_string(),
+    "[line 1] Error at '(': Expect 'while' after do body."
+  );
+}
+
+#[test]
+fn loop_else_clause() {
+  let output = interpret_to_string(
+    r#"
+fun find(list, target) {
+  for (var i = 0; i < len(list); i = i + 1) {
+    if (list[i] == target) {
+      print "found at " + string(i);
+      break;
+    }
+  } else {
+    print "not found";
+  }
+}
+find([1, 2, 3], 2);
+find([1, 2, 3], 4);
+
+var n = 0;
+while (n < 3) n = n + 1; else print "counted to " + string(n);
+while (true) break; else print "never";
+do n = n - 1; while (n > 0); else print "down to " + string(n);
+
+for (var i = 0; i < 2; i = i + 1) {
+  while (false) {} else {
+    if (i == 0) continue;
+    break;
+  }
+  print "unreached";
+} else print "not after an outer break";
+print "end";
+"#,
+  );
+  assert_eq!(
+    output,
+    Ok("found at 1\nnot found\ncounted to 3\ndown to 0\nend\n".to_owned())
+  );
+}
+
+#[test]
+fn loop_as_if_body_leaves_else_to_the_if() {
+  let output = interpret_to_string(
+    r#"
+if (false) while (false) {} else print "else";
+if (true) for (var i = 0; i < 1; i = i + 1) {} else print "bad";
+if (false) do {} while (false); else print "do else";
+if (true) {
+  while (false) {} else print "loop else";
+} else print "bad";
+if (true) if (false) while (false) {} else print "inner else";
+"#,
+  );
+  assert_eq!(
+    output,
+    Ok("else\ndo else\nloop else\ninner else\n".to_owned())
+  );
+}
+
+#[test]
+fn reference_values_compare_by_identity() {
+  let output = interpret_to_string(
+    r#"
+fun foo() {}
+fun make() {
+  var count = 0;
+  fun inner() {
+    count = count + 1;
+  }
+  return inner;
+}
+var f = foo;
+print f == foo;
+print make() == make();
+
+class A { m() {} }
+var a = A();
+var b = a;
+print A == A;
+print a == b;
+print a == A();
+print a.m == b.m;
+print a.m == A().m;
+print push == push;
+print push == pop;
+"#,
+  );
+  assert_eq!(
+    output,
+    Ok("true\nfalse\ntrue\ntrue\nfalse\ntrue\nfalse\ntrue\nfalse\n".to_owned())
+  );
+}
+
+#[test]
+fn functions_and_natives_compare_by_identity() {
+  let function = Value::function(Function::new_function("f"));
+  let same_name = Value::function(Function::new_function("f"));
+  assert!(Value::equal(&function, &function.clone()));
+  assert!(!Value::equal(&function, &same_name));
+
+  fn zero(_vm: &mut VM, _args: &[Value]) -> Result<Value, String> {
+    Ok(Value::int(0))
+  }
+  let mut vm = run("var old = len;");
+  vm.define_native("len", 1, zero);
+  vm.interpret_closure(
+    compile("var same = old == old;\nvar replaced = old == len;").unwrap(),
+  )
+  .unwrap();
+  assert!(matches!(global(&vm, "same"), Value::Bool(true)));
+  assert!(matches!(global(&vm, "replaced"), Value::Bool(false)));
+}
+
+#[test]
+fn map_keys_beyond_strings() {
+  let output = interpret_to_string(
+    r#"
+var m = {};
+m[1] = "a";
+m[true] = "b";
+m[nil] = "c";
+m["1"] = "d";
+m[2.5] = "e";
+print m[1];
+print m[1.0];
+print m[true];
+print m[false];
+print m;
+"#,
+  );
+  assert_eq!(
+    output,
+    Ok("a\na\nb\nnil\n{nil: \"c\", true: \"b\", 1: \"a\", 2.5: \"e\", \"1\": \"d\"}\n".to_owned())
+  );
+}
+
+#[test]
+fn map_literal_keys_beyond_strings() {
+  let output = interpret_to_string(
+    r#"
+var key = "k";
+var m = {1: "a", true: "b", nil: "c", 2.5: "d", key: "e", 1 + 1: "f"};
+print m[1];
+print m[1.0];
+print m[true];
+print m["k"];
+print m;
+"#,
+  );
+  assert_eq!(
+    output,
+    Ok(
+      "a\na\nb\ne\n{nil: \"c\", true: \"b\", 1: \"a\", 2: \"f\", 2.5: \"d\", \"k\": \"e\"}\n"
+        .to_owned()
+    )
+  );
+}
+
+#[test]
+fn type_names() {
+  let output = interpret_to_string(
+    r#"
+class A { m() {} }
+print type(nil);
+print type(3);
+print type(0.5);
+print type(true);
+print type("x");
+print type(A);
+print type(A());
+print type(A().m);
+print type(type);
+print type(fun () {});
+print type([]);
+print type({});
+"#,
+  );
+  assert_eq!(
+    output,
+    Ok(
+      "nil\nnumber\nnumber\nbool\nstring\nclass\ninstance\nfunction\nfunction\nfunction\nlist\nmap\n"
+        .to_owned()
+    )
+  );
+}
+
+#[test]
+fn exit_codes() {
+  let status = |source: &str| {
+    let path = env::temp_dir().join("clox_rs_exit_codes.lox");
+    fs::write(&path, source).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_clox-rs"))
+      .arg(&path)
+      .output()
+      .unwrap();
+    output.status.code()
+  };
+  assert_eq!(status("print 1;"), Some(0));
+  assert_eq!(status("print ;"), Some(65));
+  assert_eq!(status("print -nil;"), Some(70));
+}
+
+#[test]
+fn try_catch_runtime_error() {
+  let vm = run(
+    r#"
+var result = nil;
+try {
+  var x = 1;
+  result = x / "two";
+  result = "unreachable";
+} catch (e) {
+  result = e;
+}
+"#,
+  );
+  check(
+    &global(&vm, "result"),
+    expect![[r#"
+      "Operand must be a number."
+  "#]],
+  );
+}
+
+#[test]
+fn try_catch_unwinds_frames() {
+  let output = interpret_to_string(
+    r#"
+fun fail(n) {
+  if (n == 0) throw "bottom";
+  return fail(n - 1);
+}
+fun guarded() {
+  try {
+    return fail(3);
+  } catch (e) {
+    return "caught " + e;
+  }
+}
+print guarded();
+
+// Leaving a try block by return or break drops its handler.
+fun early() {
+  try { return 1; } catch (e) { return 2; }
+}
+early();
+for (var i = 0; i < 3; i = i + 1) {
+  try { if (i == 1) break; } catch (e) {}
+}
+try { -nil; } catch (e) { print "outer"; }
+"#,
+  );
+  check(
+    &output,
+    expect![[r#"
+      Ok(
+          "caught bottom\nouter\n",
+      )
+  "#]],
+  );
+}
+
+#[test]
+fn uncaught_throw() {
+  assert_snapshot!(
+    r#"
+try {} catch (e) {}
+throw "boom";
+"#,
+    "[line 3] Error: boom\n[line 3] in <script>"
+  );
+}
+
+#[test]
+fn try_catch_bytecode() {
+  assert_snapshot!(
+    r#"
+try { throw 1; } catch (e) { print e; }
+"#,
+    r#"
+== <script> ==
+0000 SetupCatch          0 -> 10
+0003 Constant            0 '1'
+0005 Throw
+0006 PopCatch
+0007 Jump                7 -> 14
+0010 GetLocal            1
+0012 Print
+0013 Pop
+0014 Nil
+0015 Return
+
+"#,
+    r#"
+== VM Stack Snapshot ==
+[<script>]
+[<script>]
+[<script>, 1]
+[<script>, 1]
+[<script>, 1, 1]
+[<script>, 1]
+[<script>]
+[<script>, nil]
+
+"#
+  );
+}
+
+#[test]
+fn list_literal_and_index() {
+  let output = interpret_to_string(
+    r#"
+var a = [10, 20, 30];
+a[1] = 99;
+print a[1];
+var nested = [a, [], "s"];
+nested[0][2] = nested[1];
+print nested;
+"#,
+  );
+  check(&output, expect![[r#"
+      Ok(
+          "99\n[[10, 99, []], [], \"s\"]\n",
+      )
+  "#]]);
+}
+
+#[test]
+fn list_index_out_of_range() {
+  assert_snapshot!(
+    r#"
+var a = [1];
+print a[1];
+"#,
+    "[line 3] Error: List index out of range.\n[line 3] in <script>"
+  );
+}
+
+#[test]
+fn list_index_not_a_number() {
+  assert_snapshot!(
+    r#"
+var a = [1];
+a[0.5] = 2;
+"#,
+    "[line 3] Error: List index must be a number.\n[line 3] in <script>"
+  );
+}
+
+#[test]
+fn index_non_list() {
+  assert_snapshot!(
+    r#""abc"[0];"#,
+    "[line 1] Error: Only lists and maps can be indexed.\n[line 1] in <script>"
+  );
+}
+
+#[test]
+fn native_iterates_list() {
+  fn sum(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let list = args[0].as_list().ok_or("Expected a list.")?;
+    let mut total = 0.0;
+    for element in list.iter() {
+      total += element.expect_number()?;
+    }
+    Ok(Value::number(total))
+  }
+
+  let mut vm =
+    VM::from_closure(compile("var total = sum([1, 2, 3.5]);").unwrap())
+      .unwrap();
+  vm.define_native("sum", 1, sum);
+  vm.run(None).unwrap();
+  check(&global(&vm, "total"), expect![[r#"
+      6.5
+  "#]]);
+}
+
+#[test]
+fn pretty_print_nested_lists() {
+  let vm = run(
+    r#"
+var inner = [1, "two"];
+var list = [inner, [], [[nil]]];
+inner[1] = list;
+"#,
+  );
+  assert_eq!(
+    global(&vm, "list").pretty(2),
+    r#"[
+  [
+    1,
+    <cycle>
+  ],
+  [],
+  [
+    [
+      nil
+    ]
+  ]
+]"#
+  );
+}
+
+#[test]
+fn list_bytecode() {
+  assert_snapshot!(
+    r#"
+var a = [1, 2];
+a[0] = a[1];
+"#,
+    r#"
+== <script> ==
+0000 Constant            1 '1'
+0002 Constant            2 '2'
+0004 BuildList           2
+0006 DefineGlobal        0 '"a"'
+0008 GetGlobal           0 '"a"'
+0010 Constant            3 '0'
+0012 GetGlobal           0 '"a"'
+0014 Constant            1 '1'
+0016 Index
+0017 SetIndex
+0018 Pop
+0019 Nil
+0020 Return
+
+"#,
+    r#"
+== VM Stack Snapshot ==
+[<script>]
+[<script>, 1]
+[<script>, 1, 2]
+[<script>, [2, 2]]
+[<script>]
+[<script>, [2, 2]]
+[<script>, [2, 2], 0]
+[<script>, [2, 2], 0, [2, 2]]
+[<script>, [2, 2], 0, [2, 2], 1]
+[<script>, [2, 2], 0, 2]
+[<script>, 2]
+[<script>]
+[<script>, nil]
+
+"#
+  );
+}
+
+#[test]
+fn map_literal_and_index() {
+  let output = interpret_to_string(
+    r#"
+var m = {"a": 1, "b": [2]};
+m["c"] = m["a"] + 2;
+m["b"][0] = nil;
+print m["c"];
+print m["missing"];
+print m;
+print {};
+"#,
+  );
+  check(
+    &output,
+    expect![[r#"
+      Ok(
+          "3\nnil\n{\"a\": 1, \"b\": [nil], \"c\": 3}\n{}\n",
+      )
+  "#]],
+  );
+}
+
+#[test]
+fn map_literal_key_unhashable() {
+  assert_snapshot!(
+    r#"var m = {[]: 2};"#,
+    "[line 1] Error: Unhashable key.\n[line 1] in <script>"
+  );
+}
+
+#[test]
+fn map_index_unhashable() {
+  assert_snapshot!(
+    r#"
+var m = {};
+m[[]] = 2;
+"#,
+    "[line 3] Error: Unhashable key.\n[line 3] in <script>"
+  );
+}
+
+#[test]
+fn pretty_print_list_of_maps() {
+  let vm = run(
+    r#"
+var map = {"z": [], "a": "x"};
+var list = [map, {}];
+map["self"] = map;
+"#,
+  );
+  assert_eq!(
+    global(&vm, "list").pretty(2),
+    r#"[
+  {
+    "a": "x",
+    "self": <cycle>,
+    "z": []
+  },
+  {}
+]"#
+  );
+}
+
+#[test]
+fn throw_closes_upvalues_of_unwound_frames() {
+  let output = interpret_to_string(
+    r#"
+var saved = nil;
+fun thrower() {
+  var local = "captured";
+  fun get() { return local; }
+  saved = get;
+  throw "thrown";
+}
+try {
+  thrower();
+} catch (e) {
+  print e;
+  var _clobber = "stack slot reused";
+  print saved();
+}
+"#,
+  );
+  check(
+    &output,
+    expect![[r#"
+      Ok(
+          "thrown\ncaptured\n",
+      )
+  "#]],
+  );
+}
+
+#[test]
+fn finally_runs_once_on_every_exit() {
+  let output = interpret_to_string(
+    r#"
+var runs = 0;
+try {
+  print "normal";
+} finally {
+  runs = runs + 1;
+}
+print runs;
+
+try {
+  throw "thrown";
+} catch (e) {
+  print e;
+} finally {
+  runs = runs + 1;
+}
+print runs;
+
+fun f() {
+  try {
+    return "returned";
+  } finally {
+    runs = runs + 1;
+  }
+}
+print f();
+print runs;
+"#,
+  );
+  check(
+    &output,
+    expect![[r#"
+      Ok(
+          "normal\n1\nthrown\n2\nreturned\n3\n",
+      )
+  "#]],
+  );
+}
+
+#[test]
+fn finally_runs_before_error_propagates() {
+  let output = interpret_to_string(
+    r#"
+fun f() {
+  try {
+    nope();
+  } finally {
+    print "finally";
+  }
+}
+try {
+  f();
+} catch (e) {
+  print e;
+}
+try {
+  throw "first";
+} catch (e) {
+  throw "second";
+} finally {
+  print "after catch";
+}
+"#,
+  );
+  check(
+    &output,
+    expect![[r#"
+      Err(
+          [
+              Runtime {
+                  line: 20,
+                  msg: "second",
+                  trace: [
+                      "[line 20] in <script>",
+                  ],
+              },
+          ],
+      )
+  "#]],
+  );
+}
+
+#[test]
+fn finally_on_nested_return_and_break() {
+  let output = interpret_to_string(
+    r#"
+fun f() {
+  try {
+    try {
+      return "value";
+    } finally {
+      print "inner";
+    }
+  } finally {
+    print "outer";
+  }
+}
+print f();
+
+fun g() {
+  try {
+    return "try";
+  } finally {
+    return "finally";
+  }
+}
+print g();
+
+for (var i = 0; i < 3; i = i + 1) {
+  var a = i;
+  try {
+    var b = a;
+    if (b == 0) continue;
+    if (b == 1) break;
+  } finally {
+    print a;
+  }
+}
+"#,
+  );
+  check(
+    &output,
+    expect![[r#"
+      Ok(
+          "inner\nouter\nvalue\nfinally\n0\n1\n",
+      )
+  "#]],
+  );
+}
+
+#[test]
+fn try_without_catch_or_finally() {
+  assert_snapshot!(
+    "try {}",
+    "[line 1] Error at end: Expect 'catch' or 'finally' after try block."
+  );
+}
+
+#[test]
+fn try_finally_bytecode() {
+  assert_snapshot!(
+    r#"
+try { print 1; } finally { print 2; }
+"#,
+    r#"
+== <script> ==
+0000 SetupFinally        0 -> 10
+0003 Constant            0 '1'
+0005 Print
+0006 PopCatch
+0007 Jump                7 -> 14
+0010 Constant            1 '2'
+0012 Print
+0013 EndFinally
+0014 Nil
+0015 Return
+
+"#,
+    r#"
+== VM Stack Snapshot ==
+[<script>]
+[<script>]
+[<script>, 1]
+[<script>]
+[<script>, 7, 0]
+[<script>, 7, 0, 2]
+[<script>, 7, 0]
+[<script>]
+[<script>]
+[<script>, nil]
+
+"#
+  );
+}
+
+#[test]
+fn list_natives() {
+  let output = interpret_to_string(
+    r#"
+var a = [1];
+push(a, 2);
+print len(a);
+print pop(a);
+print a;
+print len("héllo");
+"#,
+  );
+  check(
+    &output,
+    expect![[r#"
+      Ok(
+          "2\n2\n[1]\n5\n",
+      )
+  "#]],
+  );
+}
+
+#[test]
+fn push_non_list() {
+  assert_snapshot!(
+    r#"push("a", 1);"#,
+    "[line 1] Error: Expected a list.\n[line 1] in <script>"
+  );
+}
+
+#[test]
+fn pop_empty_list() {
+  assert_snapshot!(
+    "pop([]);",
+    "[line 1] Error: Can't pop from an empty list.\n[line 1] in <script>"
+  );
+}
+
+#[test]
+fn spread_call() {
+  let output = interpret_to_string(
+    r#"
+fun add(a, b, c) {
+  return a + b + c;
+}
+var args = [1, 2, 3];
+print add(...args);
+print add(10, ...[20, 30]);
+"#,
+  );
+  check(
+    &output,
+    expect![[r#"
+      Ok(
+          "6\n60\n",
+      )
+  "#]],
+  );
+}
+
+#[test]
+fn spread_non_list() {
+  assert_snapshot!(
+    r#"
+fun f(a) {}
+f(...1);
+"#,
+    "[line 3] Error: Spread argument must be a list.\n[line 3] in <script>"
+  );
+}
+
+#[test]
+fn spread_argument_not_last() {
+  assert_snapshot!(
+    "fun f(a, b) {} f(...[1], 2);",
+    "[line 1] Error at ',': Spread argument must be last."
+  );
+}
+
+#[test]
+fn integers_stay_integral() {
+  let vm = run(
+    r#"
+var half = 7 / 2;
+var rem = 7 % 2;
+var count = 0;
+for (var i = 0; i < 3; i = i + 1) count = count + 1;
+var mixed = count * 1.5;
+var same = 1 == 1.0;
+"#,
+  );
+  assert!(matches!(global(&vm, "half"), Value::Number(n) if n == 3.5));
+  assert!(matches!(global(&vm, "rem"), Value::Int(1)));
+  assert!(matches!(global(&vm, "count"), Value::Int(3)));
+  assert!(matches!(global(&vm, "mixed"), Value::Number(n) if n == 4.5));
+  assert!(matches!(global(&vm, "same"), Value::Bool(true)));
+}
+
+#[test]
+fn integer_output() {
+  let output = interpret_to_string(
+    r#"
+print 7 / 2;
+print 7 % 2;
+print 123456789 * 10;
+print 9223372036854775807 + 1;
+"#,
+  );
+  check(
+    &output,
+    expect![[r#"
+      Ok(
+          "3.5\n1\n1234567890\n9.22337e+18\n",
+      )
+  "#]],
+  );
+}
+
+#[test]
+fn inspector_shows_constants_table() {
+  let source = r#"
+var a = "aaa";
+var b = "bbb";
+a = "assign add " + b;
+
+print a;
+"#;
+  let scanner = Scanner::new(source);
+  let mut parser = Parser::new(scanner, Some(Inspector::new()));
+  parser.advance().unwrap();
+  parser.program().unwrap();
+  parser.end_compiler();
+  let inspector = parser.into_inspector().unwrap();
+  check(
+    &inspector.debug_bytecode_with_constants(),
+    expect![[r#"
+      == <script> ==
+      0000 Constant            1 '"aaa"'
+      0002 DefineGlobal        0 '"a"'
+      0004 Constant            3 '"bbb"'
+      0006 DefineGlobal        2 '"b"'
+      0008 Constant            4 '"assign add "'
+      0010 GetGlobal           2 '"b"'
+      0012 Add
+      0013 SetGlobal           0 '"a"'
+      0015 Pop
+      0016 GetGlobal           0 '"a"'
+      0018 Print
+      0019 Nil
+      0020 Return
+      == <script> constants ==
+         0 '"a"'
+         1 '"aaa"'
+         2 '"b"'
+         3 '"bbb"'
+         4 '"assign add "'
+
+  "#]],
+  );
+}
+
+#[test]
+fn string_comparison() {
+  let output = interpret_to_string(
+    r#"
+print "apple" < "banana";
+print "apple" > "banana";
+print "b" >= "b";
+print "ab" <= "a";
+print 2 > 1.5;
+"#,
+  );
+  check(
+    &output,
+    expect![[r#"
+      Ok(
+          "true\nfalse\ntrue\nfalse\ntrue\n",
+      )
+  "#]],
+  );
+}
+
+#[test]
+fn compare_mixed_types() {
+  assert_snapshot!(
+    r#"1 < "a";"#,
+    "[line 1] Error: Operands must be comparable.\n[line 1] in <script>"
+  );
+}
+
+#[test]
+fn raw_and_escaped_strings() {
+  let vm = run(
+    r#"
+var raw = r"C:\path\n";
+var escaped = "tab\t\"quoted\"\\";
+"#,
+  );
+  assert_eq!(global(&vm, "raw").as_string().unwrap(), r"C:\path\n");
+  assert_eq!(
+    global(&vm, "escaped").as_string().unwrap(),
+    "tab\t\"quoted\"\\"
+  );
+}
+
+#[test]
+fn invalid_escape_sequence() {
+  assert_snapshot!(
+    r#"print "\q";"#,
+    r#"[line 1] Error at '"\q"': Invalid escape sequence."#
+  );
+}
+
+#[test]
+fn bitwise_operators() {
+  let output = interpret_to_string(
+    r#"
+print 6 & 3;
+print 1 << 4;
+print 6 | 1 ^ 3;
+print -16 >> 2;
+print 1 << 2 + 1;
+print 1 + 2 & 6;
+"#,
+  );
+  check(
+    &output,
+    expect![[r#"
+      Ok(
+          "2\n16\n6\n-4\n8\n2\n",
+      )
+  "#]],
+  );
+}
+
+#[test]
+fn bitwise_operators_need_integers() {
+  assert_snapshot!(
+    "print 1.5 & 1;",
+    "[line 1] Error: Operands must be integers.\n[line 1] in <script>"
+  );
+}
+
+#[test]
+fn triple_quoted_string() {
+  let source = "var s = \"\"\"first \"line\"\nit's the second\n\"\"\";\nnext";
+  let mut scanner = Scanner::new(source);
+  let mut tokens = Vec::new();
+  while let Some(token) = scanner.scan_token().unwrap() {
+    tokens.push(token);
+  }
+  assert_eq!(tokens.last().unwrap().source, "next");
+  assert_eq!(tokens.last().unwrap().line, 4);
+
+  let vm = run(source.trim_end_matches("next"));
+  assert_eq!(
+    global(&vm, "s").as_string().unwrap(),
+    "first \"line\"\nit's the second\n"
+  );
+}
+
+#[test]
+fn unterminated_triple_quoted_string() {
+  assert_snapshot!(
+    r#"print """never closed";"#,
+    "[line 1] Error: Unterminated triple-quoted string."
+  );
+}
+
+#[test]
+fn power_operator() {
+  let output = interpret_to_string(
+    r#"
+print 2 ** 10;
+print 2 ** 3 ** 2;
+print -2 ** 2;
+print 2 * 3 ** 2;
+print 2 ** -1;
+"#,
+  );
+  check(
+    &output,
+    expect![[r#"
+      Ok(
+          "1024\n512\n-4\n18\n0.5\n",
+      )
+  "#]],
+  );
+}
+
+#[test]
+fn compound_assignment() {
+  let output = interpret_to_string(
+    r#"
+var a = 10;
+a += 5;
+print a;
+{
+  var b = 3;
+  b *= a;
+  print b;
+}
+fun counter() {
+  var count = 10;
+  fun step() {
+    count -= 4;
+    count /= 2;
+    return count;
+  }
+  return step;
+}
+print counter()();
+"#,
+  );
+  check(
+    &output,
+    expect![[r#"
+      Ok(
+          "15\n45\n3\n",
+      )
+  "#]],
+  );
+}
+
+#[test]
+fn compound_assignment_invalid_target() {
+  assert_snapshot!(
+    "1 += 2;",
+    "[line 1] Error at '+=': Invalid assignment target."
+  );
+}
+
+#[test]
+fn increment_and_decrement() {
+  let output = interpret_to_string(
+    r#"
+var i = 0;
+print i++;
+print i;
+print ++i;
+print i--;
+print --i;
+var total = 0;
+for (var j = 0; j < 3; j++) total += j;
+print total;
+"#,
+  );
+  check(
+    &output,
+    expect![[r#"
+      Ok(
+          "0\n1\n2\n2\n0\n3\n",
+      )
+  "#]],
+  );
+}
+
+#[test]
+fn increment_non_variable() {
+  assert_snapshot!(
+    "++5;",
+    "[line 1] Error at '5': Operand of '++' must be a variable."
+  );
+}
+
+#[test]
+fn postfix_decrement_non_variable() {
+  assert_snapshot!(
+    "5--;",
+    "[line 1] Error at '--': Operand of '--' must be a variable."
+  );
+}
+
+#[test]
+fn nested_block_comment() {
+  fn tokens(source: &str) -> Vec<(String, usize)> {
+    let mut scanner = Scanner::new(source);
+    let mut tokens = Vec::new();
+    while let Some(token) = scanner.scan_token().unwrap() {
+      tokens.push((token.source, token.line));
+    }
+    tokens
+  }
+
+  assert_eq!(
+    tokens("var a = /* one /* two\n */ still\n comment */ 1;\nprint a;"),
+    tokens("var a =\n\n 1;\nprint a;"),
+  );
+}
+
+#[test]
+fn unterminated_block_comment() {
+  assert_snapshot!(
+    "print 1; /* open /* nested */",
+    "[line 1] Error: Unterminated block comment."
+  );
+}
+
+#[test]
+fn switch_statement() {
+  let output = interpret_to_string(
+    r#"
+for (var i = 0; i < 4; i++) {
+  switch (i) {
+    case 0:
+      print "zero";
+    case 1:
+      print "one";
+    case 1 + 1:
+      var two = "two";
+      print two;
+    default:
+      print "other";
+  }
+}
+switch ("none") {
+  case "some":
+    print "unreachable";
+}
+"#,
+  );
+  check(
+    &output,
+    expect![[r#"
+      Ok(
+          "zero\none\ntwo\nother\n",
+      )
+  "#]],
+  );
+}
+
+#[test]
+fn switch_default_must_come_last() {
+  assert_snapshot!(
+    "switch (1) { default: print 1; case 1: print 2; }",
+    "[line 1] Error at 'case': The default case must come last."
+  );
+}
+
+#[test]
+fn switch_bytecode() {
+  assert_snapshot!(
+    r#"
+switch (2) {
+  case 1: print "a";
+  default: print "b";
+}
+"#,
+    r#"
+== <script> ==
+0000 Constant            0 '2'
+0002 GetLocal            1
+0004 Constant            1 '1'
+0006 Equal
+0007 JumpIfFalse         7 -> 17
+0010 Pop
+0011 Constant            2 '"a"'
+0013 Print
+0014 Jump               14 -> 21
+0017 Pop
+0018 Constant            3 '"b"'
+0020 Print
+0021 Pop
+0022 Nil
+0023 Return
+
+"#,
+    r#"
+== VM Stack Snapshot ==
+[<script>]
+[<script>, 2]
+[<script>, 2, 2]
+[<script>, 2, 2, 1]
+[<script>, 2, false]
+[<script>, 2, false]
+[<script>, 2]
+[<script>, 2, "b"]
+[<script>, 2]
+[<script>]
+[<script>, nil]
+
+"#
+  );
+}
+
+#[test]
+fn lambda_expressions() {
+  let output = interpret_to_string(
+    r#"
+var add = fun(a, b) { return a + b; };
+print add(2, 3);
+fun apply(f, x) { return f(x); }
+print apply(fun(n) { return n * 2; }, 21);
+print fun() {};
+"#,
+  );
+  check(
+    &output,
+    expect![[r#"
+      Ok(
+          "5\n42\n<fn <anonymous>>\n",
+      )
+  "#]],
+  );
+}
+
+#[test]
+fn lambda_captures_this() {
+  let output = interpret_to_string(
+    r#"
+class Box {
+  getter() { return fun() { return this.value; }; }
+}
+var box = Box();
+box.value = 7;
+var get = box.getter();
+box = nil;
+print get();
+"#,
+  );
+  check(
+    &output,
+    expect![[r#"
+      Ok(
+          "7\n",
+      )
+  "#]],
+  );
+}
+
+#[test]
+fn string_interpolation() {
+  let output = interpret_to_string(
+    r#"
+var n = "Lox";
+print "hi ${n}";
+print "${1 + 2} and ${[1, 2]} ${nil}";
+print "nested ${ {"k": "v"}["k"] } ${"inner ${n}!"}";
+print "escaped \${n}";
+print """triple "${n}" done""";
+print r"raw ${n}";
+"#,
+  );
+  check(
+    &output,
+    expect![[r#"
+      Ok(
+          "hi Lox\n3 and [1, 2] nil\nnested v inner Lox!\nescaped ${n}\ntriple \"Lox\" done\nraw ${n}\n",
+      )
+  "#]],
+  );
+}
+
+#[test]
+fn unterminated_interpolation() {
+  assert_snapshot!(
+    r#"print "a ${1 2}";"#,
+    "[line 1] Error at '2': Expect '}' after interpolated expression."
+  );
+}
+
+#[test]
+fn string_interpolation_bytecode() {
+  assert_snapshot!(
+    r#"
+var n = 1;
+print "a${n}b";
+"#,
+    r#"
+== <script> ==
+0000 Constant            1 '1'
+0002 DefineGlobal        0 '"n"'
+0004 Constant            2 '"a"'
+0006 GetGlobal           0 '"n"'
+0008 ToString
+0009 Add
+0010 Constant            3 '"b"'
+0012 Add
+0013 Print
+0014 Nil
+0015 Return
+
+"#,
+    r#"
+== VM Stack Snapshot ==
+[<script>]
+[<script>, 1]
+[<script>]
+[<script>, "a"]
+[<script>, "a", 1]
+[<script>, "a", "1"]
+[<script>, "a1"]
+[<script>, "a1", "b"]
+[<script>, "a1b"]
+[<script>]
+[<script>, nil]
+
+"#
+  );
+}
+
+#[test]
+fn arithmetic_literals_are_folded() {
+  assert_snapshot!(
+    r#"
+print 2 * 3;
+print 1 / 0;
+var a = 2;
+print a * 3 + 1;
+"#,
+    r#"
+== <script> ==
+0000 Constant            0 '6'
+0002 Print
+0003 Constant            1 '1'
+0005 Constant            2 '0'
+0007 Divide
+0008 Print
+0009 Constant            4 '2'
+0011 DefineGlobal        3 '"a"'
+0013 GetGlobal           3 '"a"'
+0015 Constant            5 '3'
+0017 Multiply
+0018 Constant            1 '1'
+0020 Add
+0021 Print
+0022 Nil
+0023 Return
+
+"#,
+    r#"
+== VM Stack Snapshot ==
+[<script>]
+[<script>, 6]
+[<script>]
+[<script>, 1]
+[<script>, 1, 0]
+[<script>, inf]
+[<script>]
+[<script>, 2]
+[<script>]
+[<script>, 2]
+[<script>, 2, 3]
+[<script>, 6]
+[<script>, 6, 1]
+[<script>, 7]
+[<script>]
+[<script>, nil]
+
+"#
+  );
+}
+
+#[test]
+fn folding_keeps_runtime_semantics() {
+  let output = interpret_to_string(
+    r#"
+print 7 / 2;
+print 7 % 2;
+print 2 ** 3 ** 2;
+print 9223372036854775807 + 1;
+print 2 ** -1;
+print 2 * 7;
+"#,
+  );
+  check(
+    &output,
+    expect![[r#"
+      Ok(
+          "3.5\n1\n512\n9.22337e+18\n0.5\n14\n",
+      )
+  "#]],
+  );
+}
+
+#[test]
+fn repeated_constants_are_shared() {
+  let closure = compile(
+    r#"
+var a = "a";
+print a + "a" + a + "a";
+var n = 1;
+print n + 1 + 1.0 + n + 1.0;
+"#,
+  )
+  .unwrap();
+  let constants = &closure.function.chunk.constants;
+  assert_eq!(constants.len(), 4);
+  assert_eq!(format!("{:?}", constants), r#"["a", "n", 1, 1]"#);
+}
+
+#[test]
+fn string_literals_are_interned() {
+  let closure = compile(
+    r#"
+var a = "abc";
+fun f() { return "abc"; }
+"#,
+  )
+  .unwrap();
+  let find_abc = |constants: &[Value]| {
+    constants.iter().find_map(|constant| match constant {
+      Value::String(s) if &**s == "abc" => Some(s.clone()),
+      _ => None,
+    })
+  };
+  let script = &closure.function.chunk.constants;
+  let f = script
+    .iter()
+    .find_map(|constant| match constant {
+      Value::Closure(f) => Some(f.function.clone()),
+      _ => None,
+    })
+    .unwrap();
+  let in_script = find_abc(script).unwrap();
+  let in_f = find_abc(&f.chunk.constants).unwrap();
+  assert!(Rc::ptr_eq(&in_script, &in_f));
+}
+
+#[test]
+fn errors_report_their_stage() {
+  check(
+    &compile("print \"abc;").err(),
+    expect![[r#"
+      Some(
+          [
+              Scan {
+                  line: 1,
+                  msg: "Unterminated string.",
+              },
+          ],
+      )
+  "#]],
+  );
+  check(
+    &compile("print;").err(),
+    expect![[r#"
+      Some(
+          [
+              Compile {
+                  line: 1,
+                  at: Some(
+                      ";",
+                  ),
+                  msg: "Expect expression.",
+              },
+          ],
+      )
+  "#]],
+  );
+  let error = interpret_to_string("\nprint -nil;").unwrap_err().remove(0);
+  check(
+    &error,
+    expect![[r#"
+      Runtime {
+          line: 2,
+          msg: "Operand must be a number.",
+          trace: [
+              "[line 2] in <script>",
+          ],
+      }
+  "#]],
+  );
+  assert_eq!(
+    error.to_string(),
+    "[line 2] Error: Operand must be a number.\n[line 2] in <script>"
+  );
+}
+
+#[test]
+fn parse_error_at_end() {
+  assert_snapshot!("print 1", "[line 1] Error at end: Expect ';' after value.");
+}
+
+#[test]
+fn errors_in_separate_statements_are_all_reported() {
+  assert_snapshot!(
+    r#"
+var a = ;
+print a;
+fun f() {
+  return 1 +;
+}
+print "ok";
+"#,
+    "[line 2] Error at ';': Expect expression.\n[line 5] Error at ';': Expect expression."
+  );
+}
+
+#[test]
+fn serialized_chunk_runs_the_same() {
+  let source = r#"
+class Counter {
+  next() { this.count = this.count + 1; return this.count; }
+}
+fun make() {
+  var c = Counter();
+  c.count = 1.5;
+  fun step() { return c.next(); }
+  return step;
+}
+var step = make();
+print step();
+print step() + 10;
+print "done" + "!";
+print nil == false;
+"#;
+  let expected = interpret_to_string(source).unwrap();
+  let chunk = &compile(source).unwrap().function.chunk;
+  let bytes = chunk.serialize().unwrap();
+  let output = OutputBuffer::new();
+  let mut vm = VM::from_chunk(Chunk::deserialize(&bytes).unwrap())
+    .unwrap()
+    .with_output(output.clone());
+  vm.run(None).unwrap();
+  assert_eq!(output.contents(), expected);
+  assert_eq!(
+    Chunk::deserialize(&bytes[..bytes.len() - 1]).err(),
+    Some("Unexpected end of the chunk.".to_owned())
+  );
+  assert_eq!(
+    Chunk::deserialize(b"nope").err(),
+    Some("Not a compiled Lox chunk.".to_owned())
+  );
+
+  let mut chunk = Chunk::new();
+  chunk.constants = vec![Value::list(vec![Value::Int(1)])];
+  assert_eq!(
+    chunk.serialize().err(),
+    Some("Can't serialize the constant [1].".to_owned())
+  );
+}
+
+#[test]
+fn tampered_chunk_is_rejected() {
+  let load = |codes: Vec<u8>, constants: Vec<Value>| {
+    let mut chunk = Chunk::new();
+    chunk.lines = vec![1; codes.len()];
+    chunk.codes = codes;
+    chunk.constants = constants;
+    Chunk::deserialize(&chunk.serialize().unwrap()).err()
+  };
+  let (nil, ret) = (Op::Nil as u8, Op::Return as u8);
+  assert_eq!(load(vec![nil, ret], vec![]), None);
+  assert_eq!(
+    load(vec![200, ret], vec![]),
+    Some("Unknown op 200 at 0.".to_owned())
+  );
+  assert_eq!(
+    load(vec![Op::Constant as u8, 1, ret], vec![Value::Nil]),
+    Some("No constant 1 for Constant at 0.".to_owned())
+  );
+  assert_eq!(
+    load(vec![Op::GetGlobal as u8, 0, ret], vec![Value::Nil]),
+    Some("GetGlobal at 0 needs a name.".to_owned())
+  );
+  assert_eq!(
+    load(
+      vec![Op::Jump as u8, 0, 1, Op::Constant as u8, 0, ret],
+      vec![Value::Nil]
+    ),
+    Some("The jump at 0 lands off an instruction.".to_owned())
+  );
+  assert_eq!(
+    load(vec![Op::Loop as u8, 0, 9, ret], vec![]),
+    Some("The jump at 0 lands off an instruction.".to_owned())
+  );
+  assert_eq!(
+    load(vec![nil, ret, Op::Constant as u8], vec![Value::Nil]),
+    Some("Truncated Constant at 2.".to_owned())
+  );
+  assert_eq!(
+    load(vec![nil], vec![]),
+    Some("The code doesn't end with a return.".to_owned())
+  );
+  assert_eq!(
+    load(vec![Op::GetLocal as u8, 3, ret], vec![]),
+    Some("No local 3 for GetLocal at 0.".to_owned())
+  );
+  assert_eq!(
+    load(vec![Op::PopCatch as u8, ret], vec![]),
+    Some("PopCatch at 0 is outside a try.".to_owned())
+  );
+
+  let mut rest_only = Function::new_function("f");
+  rest_only.variadic = true;
+  rest_only.chunk.lines = vec![1; 2];
+  rest_only.chunk.codes = vec![nil, ret];
+  let closure = Value::closure(Closure::new(rest_only, 0));
+  assert_eq!(
+    load(vec![Op::Closure as u8, 0, ret], vec![closure]),
+    Some("Bad parameter counts for <fun f>.".to_owned())
+  );
+}
+
+#[test]
+fn bad_code_is_a_runtime_error() {
+  let run = |codes: Vec<u8>, constants: Vec<Value>| {
+    let mut chunk = Chunk::new();
+    chunk.lines = vec![1; codes.len()];
+    chunk.codes = codes;
+    chunk.constants = constants;
+    let mut vm = VM::from_chunk(chunk).unwrap();
+    match vm.run(None).err() {
+      Some(LoxError::Runtime { msg, .. }) => msg,
+      error => panic!("{:?}", error),
+    }
+  };
+  let (nil, pop, ret) = (Op::Nil as u8, Op::Pop as u8, Op::Return as u8);
+  assert_eq!(
+    run(vec![nil, pop, nil, pop, Op::GetLocal as u8, 4, ret], vec![]),
+    "No local in slot 4."
+  );
+  let (jump, setup_catch) = (Op::Jump as u8, Op::SetupCatch as u8);
+  assert_eq!(
+    run(
+      vec![jump, 0, 3, setup_catch, 0, 0, Op::PopCatch as u8, nil, ret],
+      vec![]
+    ),
+    "No try block to leave."
+  );
+  assert_eq!(
+    run(
+      vec![Op::Class as u8, 0, nil, Op::Method as u8, 0, ret],
+      vec![Value::string("A")]
+    ),
+    "Methods must be functions."
+  );
+  let (setup_finally, end) = (Op::SetupFinally as u8, Op::EndFinally as u8);
+  assert_eq!(
+    run(vec![setup_finally, 0, 0, nil, nil, end, ret], vec![]),
+    "No finally block to end."
+  );
+  // A finally block resuming past the end of the code.
+  let constant = Op::Constant as u8;
+  assert_eq!(
+    run(
+      vec![setup_finally, 0, 0, constant, 0, constant, 1, end, ret],
+      vec![Value::number(100.0), Value::number(0.0)]
+    ),
+    "No finally block to end."
+  );
+}
+
+#[test]
+fn malformed_chunks_fail_without_panicking() {
+  let load = |codes: Vec<u8>, constants: Vec<Value>| {
+    let mut chunk = Chunk::new();
+    chunk.lines = vec![1; codes.len()];
+    chunk.codes = codes;
+    chunk.constants = constants;
+    Chunk::deserialize(&chunk.serialize().unwrap())
+  };
+  let run = |codes: Vec<u8>, constants: Vec<Value>| {
+    let mut vm = VM::from_chunk(load(codes, constants).unwrap()).unwrap();
+    match vm.run(None).err() {
+      Some(LoxError::Runtime { msg, .. }) => msg,
+      error => panic!("{:?}", error),
+    }
+  };
+  let (nil, pop, ret) = (Op::Nil as u8, Op::Pop as u8, Op::Return as u8);
+  assert_eq!(
+    run(
+      vec![Op::Class as u8, 0, nil, Op::Inherit as u8, pop, ret],
+      vec![Value::string("A")]
+    ),
+    "Subclass must be a class."
+  );
+  assert_eq!(
+    run(
+      vec![nil, nil, Op::GetSuper as u8, 0, ret],
+      vec![Value::string("method")]
+    ),
+    "Superclass must be a class."
+  );
+  assert_eq!(
+    load(vec![nil, Op::SpreadCall as u8, 0, ret], vec![]).err(),
+    Some("SpreadCall at 1 spreads no list.".to_owned())
+  );
+
+  let capturing = |code: Vec<u8>| {
+    let mut function = Function::new_function("f");
+    function.chunk.lines = vec![1; code.len()];
+    function.chunk.codes = code;
+    Value::closure(Closure::new(function, 1))
+  };
+  let get_upvalue = Op::GetUpvalue as u8;
+  let closure = capturing(vec![get_upvalue, 0, ret]);
+  assert_eq!(
+    load(vec![Op::Closure as u8, 0, 1, 200, ret], vec![closure]).err(),
+    Some("Bad capture for Closure at 0.".to_owned())
+  );
+  // The captured local is popped without closing its upvalue.
+  let closure = capturing(vec![get_upvalue, 0, ret]);
+  let (define, get) = (Op::DefineGlobal as u8, Op::GetGlobal as u8);
+  assert_eq!(
+    run(
+      vec![
+        nil,
+        nil,
+        Op::Closure as u8,
+        0,
+        1,
+        2,
+        define,
+        1,
+        pop,
+        pop,
+        get,
+        1,
+        Op::Call as u8,
+        0,
+        ret
+      ],
+      vec![closure, Value::string("f")]
+    ),
+    "Captured local is no longer on the stack."
+  );
+}
+
+#[test]
+fn disassemble_matches_the_inspector() {
+  let source = r#"
+fun outer() {
+  var a = 1;
+  fun middle() {
+    fun inner() { print a; }
+    return inner;
+  }
+  return middle;
+}
+class A {
+  method() { return fun () { return this; }; }
+}
+"#;
+  let scanner = Scanner::new(source);
+  let mut parser = Parser::new(scanner, Some(Inspector::new()));
+  parser.advance().unwrap();
+  parser.program().unwrap();
+  let (closure, _) = parser.end_compiler();
+  let inspector = parser.into_inspector().unwrap();
+  assert_eq!(
+    disassemble(&closure),
+    format!("{:?}", inspector.debug_bytecode())
+  );
+}
+
+#[test]
+fn tracer_sees_each_instruction() {
+  let trace = Rc::new(RefCell::new(Vec::new()));
+  let mut vm = VM::from_closure(compile("var a = 1;\nprint a + 2;").unwrap())
+    .unwrap()
+    .with_output(OutputBuffer::new());
+  let writes = trace.clone();
+  vm.set_tracer(Box::new(move |op, index, stack| {
+    writes
+      .borrow_mut()
+      .push(format!("{:04} {} {:?}", index, op, stack));
+  }));
+  vm.run(None).unwrap();
+  check(
+    &trace.borrow(),
+    expect![[r#"
+      [
+          "0000 Constant [<script>]",
+          "0002 DefineGlobal [<script>, 1]",
+          "0004 GetGlobal [<script>]",
+          "0006 Constant [<script>, 1]",
+          "0008 Add [<script>, 1, 2]",
+          "0009 Print [<script>, 3]",
+          "0010 Nil [<script>]",
+          "0011 Return [<script>, nil]",
+      ]
+  "#]],
+  );
+}
+
+#[test]
+fn stepping_matches_run() {
+  let source = "(-1 + 2) * 3 - -4;";
+  let scanner = Scanner::new(source);
+  let mut parser = Parser::new(scanner, Some(Inspector::new()));
+  parser.advance().unwrap();
+  parser.program().unwrap();
+  let (f, _) = parser.end_compiler();
+  let mut run = VM::from_closure(f.clone()).unwrap();
+  let inspector = run.run(parser.into_inspector()).unwrap().unwrap();
+
+  let mut vm = VM::from_closure(f).unwrap();
+  let mut stacks = Vec::new();
+  let result = loop {
+    stacks.push(format!("{:?}", vm.stack));
+    match vm.step().unwrap() {
+      StepResult::Continue => {}
+      result => break result,
+    }
+  };
+  assert_eq!(format!("{:?}", result), "Halted(nil)");
+  assert_eq!(format!("{:?}", vm.stack), format!("{:?}", run.stack));
+  assert_eq!(
+    format!("== VM Stack Snapshot ==\n{}\n", stacks.join("\n")),
+    format!("{:?}", inspector.debug_stack())
+  );
+  assert_eq!(vm.step().err(), Some("No script is running.".to_owned()));
+}
+
+#[test]
+fn long_constants() {
+  let source: String =
+    (0..300).map(|n| format!("sum = sum + {};\n", n)).collect();
+  let source = format!("var sum = 0;\n{}print sum;", source);
+  assert_eq!(interpret_to_string(&source), Ok("44850\n".to_owned()));
+
+  let chunk = &compile(&source).unwrap().function.chunk;
+  let listing = chunk.debug_bytecodes("== <script> ==");
+  assert!(listing.contains("ConstantLong      300 '299'"));
+}
+
+#[test]
+fn inner_local_shadows_outer() {
+  let output = interpret_to_string(
+    r#"
+var a = 0;
+{
+  var a = 1;
+  {
+    var a = 2;
+    print a;
+  }
+  print a;
+}
+print a;
+"#,
+  );
+  assert_eq!(output, Ok("2\n1\n0\n".to_owned()));
+}
+
+#[test]
+fn redeclared_local_in_same_scope() {
+  assert_snapshot!(
+    "{ var a = 1; var a = 2; }",
+    "[line 1] Error at 'a': Already a variable with this name in this scope."
+  );
+}
+
+#[test]
+fn unreachable_code_after_return() {
+  assert_snapshot!(
+    r#"
+fun f(x) {
+  return x;
+  var y = 1;
+}
+fun g() {
+  while (true) {
+    return;
+    print "never";
+  }
+}
+"#,
+    "[line 4] Error at 'var': Unreachable code.\n[line 9] Error at 'print': Unreachable code."
+  );
+}
+
+#[test]
+fn return_in_branches_is_not_unreachable() {
+  let output = interpret_to_string(
+    r#"
+fun sign(x) {
+  if (x < 0) return "-"; else { return "+"; }
+  print "never";
+}
+fun name(n) {
+  switch (n) {
+    case 1: return "one";
+    default: print "many";
+  }
+  return "some";
+}
+fun first(list) {
+  for (var i = 0; i < len(list); i = i + 1) {
+    if (list[i]) { return i; }
+    print i;
+  }
+  return nil;
+}
+print sign(-1) + sign(1);
+print name(1) + name(2);
+print first([false, true]);
+"#,
+  );
+  assert_eq!(output, Ok("-+\nmany\nonesome\n0\n1\n".to_owned()));
+}
+
+#[test]
+fn unused_local_variable() {
+  assert_snapshot!(
+    r#"
+{ var unused = 1; }
+fun f(a, b) {
+  var c = a;
+}
+"#,
+    "[line 2] Error at '}': Unused local variable 'unused'.\n[line 5] Error at '}': Unused local variable 'c'."
+  );
+}
+
+#[test]
+fn used_and_exempt_locals() {
+  let output = interpret_to_string(
+    r#"
+{ var a = 1; print a; }
+fun f(unused, _ignored) {
+  var _skipped = 1;
+  fun helper() {}
+  switch (1) {
+    case 1: try { throw "x"; } catch (e) {} finally { print "finally"; }
+  }
+}
+f(1, 2);
+"#,
+  );
+  assert_eq!(output, Ok("1\nfinally\n".to_owned()));
+}
+
+#[test]
+fn redeclared_local_in_inner_scope() {
+  let output = interpret_to_string("{ var a; { var a; print a; } print a; }");
+  assert_eq!(output, Ok("nil\nnil\n".to_owned()));
+}
+
+#[test]
+fn hex_binary_and_separated_numbers() {
+  let output = interpret_to_string(
+    "print 0xFF; print 0b1010; print 1_000; print 1_2.2_5;",
+  );
+  assert_eq!(output, Ok("255\n10\n1000\n12.25\n".to_owned()));
+  let invalid = ["print 0x;", "print 0b102;", "print 1_;", "print 0x_f;"];
+  for source in invalid {
+    let error = compile(source).unwrap_err().remove(0);
+    assert_eq!(error.to_string(), "[line 1] Error: Invalid number literal.");
+  }
+}
+
+#[test]
+fn exponent_numbers() {
+  let output =
+    interpret_to_string("print 6.022e23; print 2.5e-3; print 3E+8; print 1e3;");
+  assert_eq!(output, Ok("6.022e+23\n0.0025\n3e+08\n1000\n".to_owned()));
+  for source in ["print 1e;", "print 2.5e+;", "print 1e_3;"] {
+    let error = compile(source).unwrap_err().remove(0);
+    assert_eq!(error.to_string(), "[line 1] Error: Invalid number literal.");
+  }
+}
+
+#[test]
+fn numbers_print_like_clox() {
+  let output = interpret_to_string(
+    r#"
+print 0.1 + 0.2;
+print 3.0 / 2.0 * 2.0;
+print 1.0;
+print -2.50;
+print 100000.0;
+print 1000000.0;
+print 123456789.0;
+print 1e21;
+print 0.0001;
+print 0.00001;
+print 1.0 / 3.0;
+"#,
+  );
+  assert_eq!(
+    output,
+    Ok(
+      "0.3\n3\n1\n-2.5\n100000\n1e+06\n1.23457e+08\n1e+21\n0.0001\n1e-05\n0.333333\n"
+        .to_owned()
+    )
+  );
+}
+
+#[test]
+fn unicode_identifiers() {
+  let output = interpret_to_string(
+    r#"
+var café = 3;
+var π = 3.5;
+var forë = "ü ${café + π} é";
+print café;
+print forë;
+"#,
+  );
+  assert_eq!(output, Ok("3\nü 6.5 é\n".to_owned()));
+}
+
+#[test]
+fn underscore_identifiers() {
+  let output = interpret_to_string(
+    "var _x = 1; var a_b_c = 2; var __ = 3; print _x + a_b_c + __;",
+  );
+  assert_eq!(output, Ok("6\n".to_owned()));
+}
+
+#[test]
+fn unexpected_character() {
+  assert_snapshot!(
+    "print 1;\nprint ¿2;",
+    "[line 2] Error: Unexpected character '¿'."
+  );
+}
+
+#[test]
+fn unterminated_string() {
+  assert_snapshot!(
+    "print 1;\n\nprint \"abc;",
+    "[line 3] Error: Unterminated string."
+  );
+}
+
+#[test]
+fn compile_without_running() {
+  let closure: Closure = compile("fun f(a, b) { return a + b; }").unwrap();
+  let f: &Function = &closure.function;
+  assert!(matches!(f.kind, FunctionKind::Script));
+  let listing = disassemble(&closure);
+  assert!(listing.starts_with("== <fun f/2 up0> ==\n"));
+  assert!(listing.contains("== <script> ==\n"));
+}
+
+#[test]
+fn eval_returns_the_last_expression() {
+  let mut vm = VM::new();
+  let mut eval = |source| vm.eval(compile_eval(source).unwrap());
+  // Integer literals evaluate to integers.
+  assert!(matches!(eval("1 + 2"), Ok(Value::Int(3))));
+  assert!(
+    matches!(eval("var a = 1.5; a * 2;"), Ok(Value::Number(n)) if n == 3.0)
+  );
+  // Only a statement outside any block is the script's value.
+  assert!(matches!(eval("a = 3; { a + 1; }"), Ok(Value::Nil)));
+  // A lone name ending the input is its value, not a misspelled keyword.
+  assert!(matches!(eval("var retrun = 4; retrun"), Ok(Value::Int(4))));
+  assert_eq!(
+    compile_eval("1 + 2 print 3;").err().unwrap()[0].to_string(),
+    "[line 1] Error at 'print': Expect ';' after expression."
+  );
+}
+
+#[test]
+fn and_or_short_circuit() {
+  let output = interpret_to_string(
+    r#"
+var calls = 0;
+fun sideEffect(value) {
+  calls = calls + 1;
+  return value;
+}
+print false and sideEffect(true);
+print true or sideEffect(false);
+print nil and sideEffect(1) or sideEffect(2);
+print calls;
+print true or false and false;
+print false and true or true;
+print sideEffect(1) and sideEffect(nil) or sideEffect(false) or "last";
+print calls;
+{
+  var a = 1;
+  var b = false or a and nil or a + 1;
+  print b;
+  print a;
+}
+"#,
+  );
+  check(
+    &output,
+    expect![[r#"
+      Ok(
+          "false\ntrue\n2\n1\ntrue\ntrue\nlast\n4\n2\n1\n",
+      )
+  "#]],
+  );
+}
+
+#[test]
+fn call_results() {
+  let output = interpret_to_string(
+    r#"
+fun early(n) {
+  var _a = "local";
+  if (n > 0) return "early";
+  var b = "late";
+  return b;
+}
+fun none() {
+  var _a = 1;
+  { var _b = 2; }
+}
+fun empty() {}
+var before = "before";
+print early(1);
+print early(0);
+print none();
+print empty();
+print [early(1), none(), early(0)];
+print before;
+"#,
+  );
+  check(
+    &output,
+    expect![[r#"
+      Ok(
+          "early\nlate\nnil\nnil\n[\"early\", nil, \"late\"]\nbefore\n",
+      )
+  "#]],
+  );
+}
+
+#[test]
+fn returning_drops_locals() {
+  let vm = run(
+    r#"
+var kept = [1];
+fun f() {
+  var list = kept;
+  var _s = "local";
+  var c = fun () { return list; };
+  var _result = c();
+  return 1;
+}
+f();
+f();
+"#,
+  );
+  let kept = match global(&vm, "kept") {
+    Value::List(list) => list,
+    _ => unreachable!(),
+  };
+  // Held by the global and by `kept` alone, once the calls' locals and the
+  // closures capturing them are dropped.
+  assert_eq!(Rc::strong_count(&kept), 2);
+}
+
+#[test]
+fn scanner_iterates_tokens() {
+  let tokens: Vec<TokenType> = Scanner::new("var x = 1; // done\nprint x;")
+    .map(|token| token.unwrap().token_type)
+    .collect();
+  assert_eq!(
+    tokens,
+    [
+      TokenType::Var,
+      TokenType::Identifier,
+      TokenType::Equal,
+      TokenType::Number,
+      TokenType::Semicolon,
+      TokenType::Print,
+      TokenType::Identifier,
+      TokenType::Semicolon,
+    ]
+  );
+
+  let results: Vec<_> = Scanner::new("a # b").collect();
+  assert_eq!(results.len(), 3);
+  assert_eq!(
+    results[1].as_ref().unwrap_err().to_string(),
+    "[line 1] Error: Unexpected character '#'."
+  );
+  assert_eq!(results[2].as_ref().unwrap().source, "b");
+}
+
+#[test]
+fn op_bytes_round_trip() {
+  for (byte, &op) in Op::ALL.iter().enumerate() {
+    assert_eq!(u8::from(op) as usize, byte);
+    assert_eq!(Op::try_from(byte as u8), Ok(op));
+  }
+  let unknown = Op::ALL.len() as u8;
+  assert_eq!(
+    Op::try_from(unknown),
+    Err(format!("Unknown op {}.", unknown))
+  );
+  assert!(Op::try_from(u8::MAX).is_err());
+}
+
+#[test]
+fn dup_and_swap() {
+  let mut chunk = Chunk::new();
+  chunk.set_line(1);
+  chunk.emit_constant(Value::number(1.0)).unwrap();
+  chunk.emit_constant(Value::number(2.0)).unwrap();
+  chunk.emit_op(Op::Swap);
+  chunk.emit_op(Op::Dup);
+  chunk.emit_op(Op::Print);
+  chunk.emit_op(Op::Subtract);
+  chunk.emit_op(Op::Print);
+  chunk.emit_op(Op::Nil);
+  chunk.emit_op(Op::Return);
+  let mut script = Function::new_script();
+  script.chunk = chunk;
+  let closure = Closure::new(script, 0);
+  assert!(disassemble(&closure).contains("0004 Swap\n0005 Dup\n"));
+  assert!(closure.function.chunk.validate().is_ok());
+
+  let output = OutputBuffer::new();
+  let mut vm = VM::from_closure(closure)
+    .unwrap()
+    .with_output(output.clone());
+  vm.run(None).unwrap();
+  assert_eq!(output.contents(), "1\n1\n");
+}
+
+#[test]
+fn vm_rejects_broken_chunks_before_linking() {
+  let load = |codes: Vec<u8>| {
+    let mut chunk = Chunk::new();
+    chunk.lines = vec![1; codes.len()];
+    chunk.codes = codes;
+    VM::from_chunk(chunk).err().map(|error| error.to_string())
+  };
+  assert_eq!(
+    load(vec![200, Op::Return as u8]),
+    Some("Error: Unknown op 200 at 0.".to_owned())
+  );
+  assert_eq!(
+    load(vec![Op::GetGlobal as u8, 0, Op::Return as u8]),
+    Some("Error: No constant 0 for GetGlobal at 0.".to_owned())
+  );
+}
+
+#[test]
+fn stack_underflow_is_an_error() {
+  let mut chunk = Chunk::new();
+  chunk.set_line(1);
+  // Pops the script closure in slot zero, then nothing.
+  chunk.emit_op(Op::Pop);
+  chunk.emit_op(Op::Add);
+  chunk.emit_op(Op::Return);
+  let mut vm = VM::from_chunk(chunk)
+    .unwrap()
+    .with_output(OutputBuffer::new());
+  assert_eq!(
+    vm.run(None).err().unwrap().to_string(),
+    "[line 1] Error: VM stack underflow.\n[line 1] in <script>"
+  );
+}